@@ -0,0 +1,55 @@
+//! Processes sequences of arbitrary requests, any field missing, on a few clients and transaction
+//! ids so that the requests refer to each other.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use rails::domain::{TransactionRequest, TransactionService};
+use rails::repository::{InMemAccountRepository, InMemTransactionRepository};
+use serde_json::{json, Map, Value};
+
+#[derive(Arbitrary, Debug)]
+struct Request {
+    operation: Option<u8>,
+    client_id: Option<u8>,
+    transaction_id: Option<u8>,
+    /// Mantissa and exponent, the exponents within the range the file reader produces.
+    amount: Option<(i64, i8)>,
+    case_id: Option<u8>,
+    timestamp: Option<u64>,
+}
+
+impl Request {
+    /// Requests are deserialized like the rows of a file, the only way to leave fields out.
+    fn into_request(self) -> Option<TransactionRequest> {
+        let mut fields = Map::new();
+        if let Some(operation) = self.operation {
+            let operations = ["deposit", "withdrawal", "dispute", "resolve", "chargeback"];
+            fields.insert("type".to_string(), json!(operations[operation as usize % operations.len()]));
+        }
+        if let Some(client_id) = self.client_id {
+            fields.insert("client".to_string(), json!(client_id % 4));
+        }
+        if let Some(transaction_id) = self.transaction_id {
+            fields.insert("tx".to_string(), json!(transaction_id % 16));
+        }
+        if let Some((mantissa, exponent)) = self.amount {
+            fields.insert("amount".to_string(), json!(format!("{}e{}", mantissa, exponent)));
+        }
+        if let Some(case_id) = self.case_id {
+            fields.insert("case".to_string(), json!(case_id % 4));
+        }
+        if let Some(timestamp) = self.timestamp {
+            fields.insert("timestamp".to_string(), json!(timestamp));
+        }
+        serde_json::from_value(Value::Object(fields)).ok()
+    }
+}
+
+fuzz_target!(|requests: Vec<Request>| {
+    let mut service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+    for request in requests.into_iter().filter_map(Request::into_request) {
+        let _ = service.process_transaction(request);
+    }
+    let _ = service.accounts();
+});
@@ -0,0 +1,26 @@
+//! Reads arbitrary bytes as a transactions file and processes every request read, as the CLI does.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rails::domain::{Amount, ServiceConfig, TransactionService};
+use rails::infrastructure::TransactionFileReader;
+use rails::repository::{InMemAccountRepository, InMemTransactionRepository};
+use rails::rules::RulesConfig;
+
+fuzz_target!(|data: &[u8]| {
+    // Limits that are easy to reach, so that the rules are exercised too.
+    let config = ServiceConfig {
+        max_dispute_cycles: Some(2),
+        rules: RulesConfig {
+            max_withdrawal_amount: Some(Amount::from(100)),
+            max_daily_volume: Some(Amount::from(1000)),
+            lock_on_violation: true,
+            ..RulesConfig::default()
+        },
+        ..ServiceConfig::default()
+    };
+    let mut service = TransactionService::with_config(InMemAccountRepository::default(), InMemTransactionRepository::default(), config);
+    let mut reader = TransactionFileReader::from_reader(data);
+    let _ = service.process_transactions(reader.values());
+    let _ = service.accounts();
+});
@@ -1,22 +1,38 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
 use std::io;
+use std::mem::discriminant;
 use std::ops::{Add, Sub};
 use std::path::Path;
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use crate::infrastructure::{ReportProducer, TransactionFileReader};
+use tracing::{debug, field, info, info_span, warn};
+use crate::infrastructure::{AuditProducer, ReportProducer, TransactionFileReader};
 
 use bigdecimal::{BigDecimal, Signed, Zero};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use crate::ServiceError::GenericErrorMsg;
+use crate::rules::{ClientActivity, RulesConfig, RulesEngine};
+use crate::hooks::{HookVerdict, TransactionHook};
+use crate::events::{DomainEvent, EventSubscriber, OutboxEntry};
+use crate::metrics::{Metrics, BAD_ROWS, REJECTIONS, REQUESTS, REQUEST_DURATION, ROWS_READ};
 
 /// Type definitions for correctness and clean code.
 pub type ClientId = u64;
 pub type TransactionId = u64;
+/// Amounts are handled with 4 decimal positions.
 pub type Amount = BigDecimal;
+/// Line of the input file, starting at 1 for the header.
+pub type RowNumber = u64;
+pub type CaseId = u64;
+/// Seconds since the unix epoch.
+pub type Timestamp = u64;
 
 const ROUND_DIGITS: i64 = 4;
 
 /// The set of operations the process expects to find in the transactions file.
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
 #[serde(rename_all = "lowercase")]
 pub enum Operation {
     Deposit,
@@ -26,6 +42,26 @@ pub enum Operation {
     Chargeback,
 }
 
+impl Operation {
+    /// Whether requests of the operation must carry an amount. Dispute-family requests may carry
+    /// one, to dispute or settle part of the amount.
+    pub fn requires_amount(&self) -> bool {
+        matches!(self, Operation::Deposit | Operation::Withdrawal)
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Deposit => write!(f, "deposit"),
+            Operation::Withdrawal => write!(f, "withdrawal"),
+            Operation::Dispute => write!(f, "dispute"),
+            Operation::Resolve => write!(f, "resolve"),
+            Operation::Chargeback => write!(f, "chargeback"),
+        }
+    }
+}
+
 /// Request describing an attempt to execute a transaction.
 #[derive(Debug, Deserialize, Clone)]
 pub struct TransactionRequest {
@@ -37,9 +73,23 @@ pub struct TransactionRequest {
     transaction_id: Option<TransactionId>,
     #[serde(rename = "amount")]
     amount: Option<Amount>,
+    /// Dispute case a dispute opens or a resolve or chargeback settles.
+    #[serde(rename = "case")]
+    case_id: Option<CaseId>,
+    /// When the transaction happened, if known.
+    #[serde(rename = "timestamp")]
+    timestamp: Option<Timestamp>,
+    /// Line of the input the request was read from, if any.
+    #[serde(skip)]
+    row: Option<RowNumber>,
+    /// Identifies the request among the ones with the same content, e.g. a second dispute of a
+    /// transaction. Given by the sender, or derived from the rows of the file up to the one the
+    /// request was read from.
+    #[serde(rename = "request")]
+    request_id: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TransactionStatus {
     /// Received transactions are stored in pending state until it is applied to the account.
     Pending,
@@ -52,9 +102,51 @@ pub enum TransactionStatus {
     /// invalid, the system became temporarily inconsistent and re-execution of Error(ed) transactions
     /// later might solve this status without human intervention.
     Error,
+    /// The transaction was refused by a business rule and was not applied.
+    Rejected(RejectionReason),
 }
 
-#[derive(Debug, Clone)]
+/// Why a transaction was refused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RejectionReason {
+    /// The named risk rule does not allow the transaction.
+    RuleViolation(String),
+    /// A transaction hook vetoed the transaction.
+    HookVeto(String),
+}
+
+impl Display for TransactionStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionStatus::Pending => write!(f, "pending"),
+            TransactionStatus::Applied => write!(f, "applied"),
+            TransactionStatus::Error => write!(f, "error"),
+            TransactionStatus::Rejected(_) => write!(f, "rejected"),
+        }
+    }
+}
+
+impl RejectionReason {
+    /// The rule or the hook that caused the rejection.
+    pub fn cause(&self) -> &str {
+        match self {
+            RejectionReason::RuleViolation(rule) => rule,
+            RejectionReason::HookVeto(veto) => veto.split(':').next().unwrap_or(veto),
+        }
+    }
+}
+
+impl Display for RejectionReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectionReason::RuleViolation(rule) => write!(f, "violates the rule {}", rule),
+            RejectionReason::HookVeto(veto) => write!(f, "vetoed by {}", veto),
+        }
+    }
+}
+
+/// Where a transaction stands in the dispute lifecycle.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TransactionDispute {
     No,
     Disputed,
@@ -62,19 +154,222 @@ pub enum TransactionDispute {
     Chargeback,
 }
 
-#[derive(Debug, Clone)]
+impl Display for TransactionDispute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionDispute::No => write!(f, "no"),
+            TransactionDispute::Disputed => write!(f, "disputed"),
+            TransactionDispute::Resolved => write!(f, "resolved"),
+            TransactionDispute::Chargeback => write!(f, "chargeback"),
+        }
+    }
+}
+
+impl FromStr for TransactionDispute {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "no" => Ok(TransactionDispute::No),
+            "disputed" => Ok(TransactionDispute::Disputed),
+            "resolved" => Ok(TransactionDispute::Resolved),
+            "chargeback" => Ok(TransactionDispute::Chargeback),
+            _ => Err(format!("Unknown dispute state {}, expected no, disputed, resolved or chargeback", value)),
+        }
+    }
+}
+
+/// The actions recorded along the life of a dispute.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum DisputeAction {
+    Opened,
+    Resolved,
+    ChargedBack,
+}
+
+impl Display for DisputeAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisputeAction::Opened => write!(f, "opened"),
+            DisputeAction::Resolved => write!(f, "resolved"),
+            DisputeAction::ChargedBack => write!(f, "chargeback"),
+        }
+    }
+}
+
+/// An entry in the dispute history of a transaction. Every dispute cycle starts with an Opened
+/// entry and ends with either a Resolved or a ChargedBack one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisputeHistoryEntry {
+    transaction_id: TransactionId,
+    client_id: ClientId,
+    case_id: Option<CaseId>,
+    cycle: u32,
+    action: DisputeAction,
+    amount: Amount,
+    row: Option<RowNumber>,
+}
+
+impl DisputeHistoryEntry {
+    pub fn new(transaction_id: TransactionId, client_id: ClientId, case_id: Option<CaseId>, cycle: u32, action: DisputeAction, amount: Amount, row: Option<RowNumber>) -> Self {
+        DisputeHistoryEntry {
+            transaction_id,
+            client_id,
+            case_id,
+            cycle,
+            action,
+            amount,
+            row,
+        }
+    }
+
+    pub fn transaction_id(&self) -> TransactionId { self.transaction_id }
+    pub fn client_id(&self) -> ClientId { self.client_id }
+    pub fn case_id(&self) -> Option<CaseId> { self.case_id }
+    /// The dispute cycle this entry belongs to, starting at 1.
+    pub fn cycle(&self) -> u32 { self.cycle }
+    pub fn action(&self) -> &DisputeAction { &self.action }
+    /// The amount held, released or charged back by the action.
+    pub fn amount(&self) -> Amount { self.amount.clone() }
+    /// The input row that triggered the action, if known.
+    pub fn row(&self) -> Option<RowNumber> { self.row }
+}
+
+/// Funds of a transaction held by one open dispute. A transaction can have several open disputes
+/// as long as their sum does not exceed the transaction amount.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisputeHold {
+    held: Amount,
+    cycle: u32,
+    case_id: Option<CaseId>,
+    /// Input row of the dispute that opened the hold.
+    #[serde(default)]
+    row: Option<RowNumber>,
+    #[serde(default)]
+    opened_at: Timestamp,
+}
+
+impl DisputeHold {
+    pub fn new(held: Amount, cycle: u32, case_id: Option<CaseId>, row: Option<RowNumber>, opened_at: Timestamp) -> Self {
+        DisputeHold {
+            held,
+            cycle,
+            case_id,
+            row,
+            opened_at,
+        }
+    }
+
+    pub fn held(&self) -> Amount { self.held.clone() }
+    /// The dispute cycle that opened the hold.
+    pub fn cycle(&self) -> u32 { self.cycle }
+    pub fn case_id(&self) -> Option<CaseId> { self.case_id }
+    pub fn row(&self) -> Option<RowNumber> { self.row }
+    /// Time of the dispute that opened the hold, its processing time if it carried none.
+    pub fn opened_at(&self) -> Timestamp { self.opened_at }
+}
+
+/// Where a dispute case stands, cases are closed once their hold is fully settled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DisputeCaseState {
+    Open,
+    Resolved,
+    ChargedBack,
+}
+
+/// A dispute case identifies one dispute over a transaction, so that concurrent disputes over the
+/// same transaction can be settled independently and retried dispute rows are detected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisputeCase {
+    case_id: CaseId,
+    transaction_id: TransactionId,
+    client_id: ClientId,
+    state: DisputeCaseState,
+}
+
+impl DisputeCase {
+    /// A case opened over the transaction.
+    pub fn new(case_id: CaseId, transaction_id: TransactionId, client_id: ClientId) -> Self {
+        DisputeCase {
+            case_id,
+            transaction_id,
+            client_id,
+            state: DisputeCaseState::Open,
+        }
+    }
+
+    pub fn case_id(&self) -> CaseId { self.case_id }
+    pub fn transaction_id(&self) -> TransactionId { self.transaction_id }
+    pub fn client_id(&self) -> ClientId { self.client_id }
+    pub fn state(&self) -> &DisputeCaseState { &self.state }
+    pub fn set_state(&mut self, state: DisputeCaseState) {
+        self.state = state;
+    }
+}
+
+/// A deposit or withdrawal as stored in the transaction repository, or a dispute-family request
+/// while it is being processed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     operation: Operation,
     client_id: ClientId,
     transaction_id: TransactionId,
     amount: Option<Amount>,
+    case_id: Option<CaseId>,
+    timestamp: Option<Timestamp>,
     status: TransactionStatus,
     dispute: TransactionDispute,
+    disputes: Vec<DisputeHold>,
+    charged_back: Amount,
+    row: Option<RowNumber>,
 }
 
 impl Transaction {
+    /// Rebuilds a pending transaction from its stored fields, intended for repository
+    /// implementations. The dispute state is restored with the setters.
+    pub fn from_parts(operation: Operation, client_id: ClientId, transaction_id: TransactionId, amount: Option<Amount>, timestamp: Option<Timestamp>) -> Self {
+        Transaction {
+            operation,
+            client_id,
+            transaction_id,
+            amount,
+            case_id: None,
+            timestamp,
+            status: TransactionStatus::Pending,
+            dispute: TransactionDispute::No,
+            disputes: Vec::new(),
+            charged_back: BigDecimal::zero(),
+            row: None,
+        }
+    }
+
+    pub fn operation(&self) -> &Operation { &self.operation }
+    pub fn client_id(&self) -> ClientId { self.client_id }
     pub fn transaction_id(&self) -> TransactionId { self.transaction_id }
     pub fn amount(&self) -> Option<Amount> { self.amount.clone() }
+    pub fn case_id(&self) -> Option<CaseId> { self.case_id }
+    pub fn timestamp(&self) -> Option<Timestamp> { self.timestamp }
+    pub fn status(&self) -> &TransactionStatus { &self.status }
+    pub fn dispute(&self) -> &TransactionDispute { &self.dispute }
+    /// The open disputes over the transaction, oldest first.
+    pub fn disputes(&self) -> &[DisputeHold] { &self.disputes }
+    pub fn charged_back(&self) -> Amount { self.charged_back.clone() }
+    /// The input row the transaction was read from, if any.
+    pub fn row(&self) -> Option<RowNumber> { self.row }
+
+    /// The portion of the transaction amount that is neither held by a dispute nor charged back.
+    pub fn disputable_amount(&self) -> Option<Amount> {
+        self.amount.as_ref().map(|amount| {
+            self.disputes.iter()
+                .fold(amount.sub(&self.charged_back), |disputable, hold| disputable.sub(&hold.held))
+                .round(ROUND_DIGITS)
+        })
+    }
+
+    pub fn set_holds(&mut self, disputes: Vec<DisputeHold>, charged_back: Amount) {
+        self.disputes = disputes;
+        self.charged_back = charged_back;
+    }
     pub fn set_status(&mut self, status: TransactionStatus) {
         self.status = status;
     }
@@ -83,53 +378,148 @@ impl Transaction {
     }
 }
 
-impl From<TransactionRequest> for Transaction {
-    // The From trait has no place to catch errors, this impl is intended to panic if the data is
-    // invalid.
-    fn from(request: TransactionRequest) -> Self {
-        Transaction {
-            operation: request.transaction_type.unwrap(),
-            client_id: request.client_id.unwrap(),
-            transaction_id: request.transaction_id.unwrap(),
-            amount: match request.amount {
-                None => None,
-                Some(amount) => { Some(amount.round(ROUND_DIGITS)) }
-            },
-            status: TransactionStatus::Pending,
-            dispute: TransactionDispute::No
-        }
+impl TryFrom<TransactionRequest> for Transaction {
+    type Error = RequestError;
+
+    /// Checks the request against the fields its operation requires.
+    fn try_from(request: TransactionRequest) -> Result<Self, Self::Error> {
+        let operation = request.transaction_type.ok_or(RequestError::MissingOperation)?;
+        let client_id = request.client_id.ok_or(RequestError::MissingClient)?;
+        let transaction_id = request.transaction_id.ok_or(RequestError::MissingTransactionId)?;
+        let amount = match request.amount {
+            None if operation.requires_amount() => return Err(RequestError::MissingAmount(operation)),
+            Some(amount) if amount.round(ROUND_DIGITS) != amount => return Err(RequestError::InvalidAmount(amount)),
+            amount => amount.map(|amount| amount.round(ROUND_DIGITS)),
+        };
+        let mut transaction = Transaction::from_parts(operation, client_id, transaction_id, amount, request.timestamp);
+        transaction.case_id = request.case_id;
+        transaction.row = request.row;
+        Ok(transaction)
     }
 }
 
-impl TransactionRequest {
-    pub fn valid_transaction(&self) -> Result<Transaction, ServiceError> {
+/// Builds transaction requests programmatically, an alternative to reading them from a file.
+#[derive(Debug, Clone)]
+pub struct TransactionRequestBuilder {
+    request: TransactionRequest,
+}
 
-        match &self.amount {
-            None => Ok(()),
-            Some(value) => {
-                if value.round(ROUND_DIGITS).ne(&value.clone()) {
-                    Err(ServiceError::GenericErrorMsg("Invalid transaction amount.".to_string()))
-                } else {
-                    Ok(())
-                }
-            }
-        }.and_then(|_| {
-            match self.transaction_type.is_some() && self.transaction_id.is_some() {
-                true => Ok(Transaction::from(self.to_owned())),
-                false => Err(ServiceError::GenericErrorMsg("Invalid transaction request.".to_string()))
+impl TransactionRequestBuilder {
+    /// The amount of a deposit or withdrawal, or the partial amount of a dispute-family request.
+    pub fn amount(mut self, amount: Amount) -> Self {
+        self.request.amount = Some(amount);
+        self
+    }
+
+    pub fn case_id(mut self, case_id: CaseId) -> Self {
+        self.request.case_id = Some(case_id);
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.request.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn row(mut self, row: RowNumber) -> Self {
+        self.request.row = Some(row);
+        self
+    }
+
+    pub fn request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request.request_id = Some(request_id.into());
+        self
+    }
+
+    pub fn build(self) -> TransactionRequest {
+        self.request
+    }
+}
+
+impl TransactionRequest {
+    /// Starts building a request. Dispute-family requests reference the disputed transaction id.
+    pub fn builder(operation: Operation, client_id: ClientId, transaction_id: TransactionId) -> TransactionRequestBuilder {
+        TransactionRequestBuilder {
+            request: TransactionRequest {
+                transaction_type: Some(operation),
+                client_id: Some(client_id),
+                transaction_id: Some(transaction_id),
+                amount: None,
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             }
-        })//.or_else(|err| return Err(err))
+        }
+    }
 
+    pub fn transaction_type(&self) -> Option<&Operation> { self.transaction_type.as_ref() }
+    pub fn client_id(&self) -> Option<ClientId> { self.client_id }
+    pub fn transaction_id(&self) -> Option<TransactionId> { self.transaction_id }
+    pub fn amount(&self) -> Option<Amount> { self.amount.clone() }
+    pub fn case_id(&self) -> Option<CaseId> { self.case_id }
+    pub fn timestamp(&self) -> Option<Timestamp> { self.timestamp }
+    pub fn row(&self) -> Option<RowNumber> { self.row }
+    pub fn request_id(&self) -> Option<&str> { self.request_id.as_deref() }
+
+    /// Tags the request with the input row it was read from.
+    pub fn with_row(mut self, row: Option<RowNumber>) -> Self {
+        self.row = row;
+        self
+    }
+
+    /// Tags the request with its identifier, unless its sender gave one.
+    pub fn with_request_id(mut self, request_id: Option<String>) -> Self {
+        if self.request_id.is_none() {
+            self.request_id = request_id;
+        }
+        self
+    }
+
+    /// Key identifying the request, used to detect replayed requests: its content and its
+    /// identifier. The amount is normalized so that equivalent amounts produce the same key.
+    /// Requests without an identifier have no key, nothing tells a replay from a new request with
+    /// the same content, e.g. a second dispute of a resolved transaction. They are only checked
+    /// against the state, a transaction id is posted once and a dispute opened once at a time.
+    /// Callers that replay requests identify them, e.g. with their sender and sequence number.
+    pub fn idempotency_key(&self) -> Option<String> {
+        let operation = match &self.transaction_type {
+            None => "",
+            Some(Operation::Deposit) => "deposit",
+            Some(Operation::Withdrawal) => "withdrawal",
+            Some(Operation::Dispute) => "dispute",
+            Some(Operation::Resolve) => "resolve",
+            Some(Operation::Chargeback) => "chargeback",
+        };
+        let field = |value: Option<String>| value.unwrap_or_default();
+        let content = format!("{},{},{},{},{}",
+                operation,
+                field(self.client_id.map(|client_id| client_id.to_string())),
+                field(self.transaction_id.map(|transaction_id| transaction_id.to_string())),
+                field(self.amount.as_ref().map(|amount| amount.round(ROUND_DIGITS).to_string())),
+                field(self.case_id.map(|case_id| case_id.to_string())));
+        self.request_id.as_ref().map(|request_id| format!("{}@{}", content, request_id))
+    }
+
+    pub fn valid_transaction(&self) -> Result<Transaction, ServiceError> {
+        Ok(Transaction::try_from(self.to_owned())?)
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// The funds of a client.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Account {
     client_id: ClientId,
     available: Amount,
     held: Amount,
     locked: bool,
     last_tx_applied: Option<TransactionId>,
+    /// Incremented on every update, so that repositories can detect concurrent updates.
+    #[serde(default)]
+    version: u64,
+    /// The recent transactions of the client the risk rules look at.
+    #[serde(default)]
+    activity: ClientActivity,
 }
 
 impl Account {
@@ -140,8 +530,24 @@ impl Account {
             held: BigDecimal::zero(),
             locked: false,
             last_tx_applied: None,
+            version: 0,
+            activity: ClientActivity::default(),
         }
     }
+
+    /// Rebuilds an account from its stored state, intended for repository implementations.
+    pub fn from_parts(client_id: ClientId, available: Amount, held: Amount, locked: bool, last_tx_applied: Option<TransactionId>, version: u64) -> Self {
+        Account {
+            client_id,
+            available,
+            held,
+            locked,
+            last_tx_applied,
+            version,
+            activity: ClientActivity::default(),
+        }
+    }
+
     pub fn client_id(&self) -> ClientId { self.client_id }
 
     /// The total funds that are available for trading, staking, withdrawal, etc.
@@ -160,9 +566,51 @@ impl Account {
     pub fn is_locked(&self) -> bool {
         self.locked
     }
+
+    /// The last transaction applied to the account.
+    pub fn last_tx_applied(&self) -> Option<TransactionId> { self.last_tx_applied }
+
+    /// The number of updates applied to the account.
+    pub fn version(&self) -> u64 { self.version }
 }
 
-/// Repository errors.
+/// The state of an account right after an operation changed it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountVersion {
+    transaction_id: TransactionId,
+    operation: Operation,
+    timestamp: Option<Timestamp>,
+    account: Account,
+}
+
+impl AccountVersion {
+    pub fn new(transaction: &Transaction, account: &Account) -> Self {
+        AccountVersion {
+            transaction_id: transaction.transaction_id,
+            operation: transaction.operation,
+            timestamp: transaction.timestamp,
+            account: account.clone(),
+        }
+    }
+
+    pub fn transaction_id(&self) -> TransactionId { self.transaction_id }
+    pub fn operation(&self) -> &Operation { &self.operation }
+    pub fn timestamp(&self) -> Option<Timestamp> { self.timestamp }
+    pub fn account(&self) -> &Account { &self.account }
+}
+
+/// A point in the history of an account.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AccountPoint {
+    /// Right after the deposit or withdrawal with the id was applied, later dispute steps on the
+    /// same transaction are not included.
+    AfterTransaction(TransactionId),
+    /// After every operation up to the time. Operations without a timestamp are placed at their
+    /// position in the input.
+    At(Timestamp),
+}
+
+/// Service errors.
 #[derive(Error, Debug)]
 pub enum ServiceError {
     #[error("Error, {0}")]
@@ -174,8 +622,34 @@ pub enum ServiceError {
     #[error("Data Error")]
     DataError(#[from] RepositoryError),
 
+    #[error("Request already processed: {0}")]
+    DuplicateRequest(String),
+
+    #[error("Invalid transaction request, {0}")]
+    InvalidRequest(#[from] RequestError),
+
 }
 
+/// Why a request does not make a transaction, a variant per field.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum RequestError {
+    #[error("missing type")]
+    MissingOperation,
+
+    #[error("missing client")]
+    MissingClient,
+
+    #[error("missing tx")]
+    MissingTransactionId,
+
+    #[error("missing amount, required by {0}")]
+    MissingAmount(Operation),
+
+    #[error("amount {0} has more than 4 decimal positions")]
+    InvalidAmount(Amount),
+}
+
+/// Repository errors.
 #[derive(Error, Debug)]
 pub enum RepositoryError {
     #[error("Entity already exists with id: {0}")]
@@ -187,12 +661,302 @@ pub enum RepositoryError {
     #[error("Inconsistency detected, reference: {0}")]
     InconsistencyDetected(String),
 
+    #[error("Storage failure: {0}")]
+    StorageError(String),
 }
 
+/// Default number of times an operation is retried when the account changed under it.
+pub const DEFAULT_CONFLICT_RETRIES: u32 = 3;
+
+/// Tunables of the transaction service.
+#[derive(Debug, Clone)]
+pub struct ServiceConfig {
+    /// Maximum number of times a single transaction can be disputed, unlimited if None.
+    pub max_dispute_cycles: Option<u32>,
+    /// Risk rules evaluated before every operation.
+    pub rules: RulesConfig,
+    /// Times an operation is applied again on a fresh read of the account when another writer
+    /// updated it in between.
+    pub conflict_retries: u32,
+    /// Whether every update of an account is recorded in its history, for point-in-time balance
+    /// queries. The history grows with every applied request.
+    pub account_history: bool,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            max_dispute_cycles: None,
+            rules: RulesConfig::default(),
+            conflict_retries: DEFAULT_CONFLICT_RETRIES,
+            account_history: true,
+        }
+    }
+}
+
+/// The transaction processing engine. Validates requests, applies them to the accounts and keeps
+/// track of transactions and disputes through the injected repositories.
 pub struct TransactionService<AccRep, TxRep>
     where AccRep: AccountRepository, {
     account_repository: AccRep,
     transaction_repository: TxRep,
+    config: ServiceConfig,
+    rules: RulesEngine,
+    hooks: Vec<Box<dyn TransactionHook>>,
+    subscribers: Vec<Subscription>,
+    /// Sequence of the last event the service marked published.
+    published: u64,
+    metrics: Option<Metrics>,
+    /// Events raised by the request being processed, moved to the outbox once it is processed.
+    events: Vec<DomainEvent>,
+}
+
+/// A subscriber to the domain events, and how far it got.
+struct Subscription {
+    subscriber: Box<dyn EventSubscriber>,
+    /// Sequence of the last event the subscriber handled in this run.
+    acknowledged: u64,
+}
+
+/// The result of processing a request.
+#[derive(Debug, Clone)]
+pub struct TransactionOutcome {
+    pub status: TransactionStatus,
+    /// Notes the hooks raised about the transaction.
+    pub annotations: Vec<String>,
+    /// Events raised while processing the request.
+    pub events: Vec<DomainEvent>,
+}
+
+/// Counters describing the outcome of processing a stream of requests.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessingSummary {
+    pub processed: u64,
+    /// Processed requests applied to the account.
+    pub applied: u64,
+    /// Processed requests not applicable to the account balance.
+    pub errored: u64,
+    /// Processed requests refused by a business rule.
+    pub rejected: u64,
+    /// Rejected requests per rule or hook.
+    pub rejected_by_cause: BTreeMap<String, u64>,
+    /// Processed requests annotated by a hook.
+    pub flagged: u64,
+    pub skipped_duplicates: u64,
+    pub failed: u64,
+    /// Input rows that could not be read as a request.
+    pub bad_rows: u64,
+    /// Requests processed, skipped or failed per operation.
+    pub by_operation: BTreeMap<Operation, u64>,
+    pub deposited: Amount,
+    pub withdrawn: Amount,
+    /// Funds put on hold by the disputes opened.
+    pub held: Amount,
+    pub charged_back: Amount,
+    /// Accounts locked once the requests are processed.
+    pub locked_accounts: u64,
+    pub elapsed_seconds: f64,
+    /// Requests read per second.
+    pub throughput: f64,
+}
+
+impl Default for ProcessingSummary {
+    fn default() -> Self {
+        ProcessingSummary {
+            processed: 0,
+            applied: 0,
+            errored: 0,
+            rejected: 0,
+            rejected_by_cause: BTreeMap::new(),
+            flagged: 0,
+            skipped_duplicates: 0,
+            failed: 0,
+            bad_rows: 0,
+            by_operation: BTreeMap::new(),
+            deposited: BigDecimal::zero(),
+            withdrawn: BigDecimal::zero(),
+            held: BigDecimal::zero(),
+            charged_back: BigDecimal::zero(),
+            locked_accounts: 0,
+            elapsed_seconds: 0.0,
+            throughput: 0.0,
+        }
+    }
+}
+
+impl ProcessingSummary {
+    /// Accounts for the outcome of a processed request.
+    fn add_outcome(&mut self, outcome: &TransactionOutcome) {
+        self.processed += 1;
+        match &outcome.status {
+            TransactionStatus::Applied => self.applied += 1,
+            TransactionStatus::Error => self.errored += 1,
+            TransactionStatus::Rejected(reason) => {
+                self.rejected += 1;
+                *self.rejected_by_cause.entry(reason.cause().to_string()).or_default() += 1;
+            }
+            TransactionStatus::Pending => (),
+        }
+        if !outcome.annotations.is_empty() {
+            self.flagged += 1;
+        }
+        for event in outcome.events.iter() {
+            let (volume, amount) = match event {
+                DomainEvent::Deposited { amount, .. } => (&mut self.deposited, amount),
+                DomainEvent::Withdrawn { amount, .. } => (&mut self.withdrawn, amount),
+                DomainEvent::DisputeOpened { amount, .. } => (&mut self.held, amount),
+                DomainEvent::ChargedBack { amount, .. } => (&mut self.charged_back, amount),
+                _ => continue,
+            };
+            *volume = volume.clone().add(amount).round(ROUND_DIGITS);
+        }
+    }
+}
+
+impl Display for ProcessingSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Processed {} requests in {:.3}s ({:.0} requests/s): {} applied, {} errored, {} rejected, {} flagged.",
+                 self.processed, self.elapsed_seconds, self.throughput, self.applied, self.errored, self.rejected, self.flagged)?;
+        writeln!(f, "Skipped {} duplicates, {} failed, {} bad rows.", self.skipped_duplicates, self.failed, self.bad_rows)?;
+        let operations: Vec<String> = self.by_operation.iter()
+            .map(|(operation, count)| format!("{} {}", count, operation))
+            .collect();
+        writeln!(f, "Requests per operation: {}.", operations.join(", "))?;
+        if !self.rejected_by_cause.is_empty() {
+            let causes: Vec<String> = self.rejected_by_cause.iter()
+                .map(|(cause, count)| format!("{} {}", count, cause))
+                .collect();
+            writeln!(f, "Rejections per cause: {}.", causes.join(", "))?;
+        }
+        write!(f, "Deposited {}, withdrawn {}, held {}, charged back {}, {} accounts locked.",
+               self.deposited, self.withdrawn, self.held, self.charged_back, self.locked_accounts)
+    }
+}
+
+/// Selects transactions by their status, regardless of the rejection reason.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StatusFilter {
+    Pending,
+    Applied,
+    Error,
+    Rejected,
+}
+
+impl StatusFilter {
+    pub fn matches(&self, status: &TransactionStatus) -> bool {
+        matches!((self, status),
+            (StatusFilter::Pending, TransactionStatus::Pending)
+            | (StatusFilter::Applied, TransactionStatus::Applied)
+            | (StatusFilter::Error, TransactionStatus::Error)
+            | (StatusFilter::Rejected, TransactionStatus::Rejected(_)))
+    }
+}
+
+impl FromStr for StatusFilter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pending" => Ok(StatusFilter::Pending),
+            "applied" => Ok(StatusFilter::Applied),
+            "error" => Ok(StatusFilter::Error),
+            "rejected" => Ok(StatusFilter::Rejected),
+            _ => Err(format!("Unknown status {}, expected pending, applied, error or rejected", value)),
+        }
+    }
+}
+
+/// Filters selecting stored transactions, a transaction is selected when it matches every filter
+/// set. Selected transactions are ordered by id and paginated with `offset` and `limit`.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionQuery {
+    pub client_id: Option<ClientId>,
+    pub status: Option<StatusFilter>,
+    pub dispute: Option<TransactionDispute>,
+    /// Lowest transaction id selected, inclusive.
+    pub from_transaction_id: Option<TransactionId>,
+    /// Highest transaction id selected, inclusive.
+    pub to_transaction_id: Option<TransactionId>,
+    /// Number of selected transactions skipped.
+    pub offset: usize,
+    /// Maximum number of transactions returned, all of them if None.
+    pub limit: Option<usize>,
+}
+
+impl TransactionQuery {
+    /// Whether the transaction passes the filters, pagination aside.
+    pub fn matches(&self, transaction: &Transaction) -> bool {
+        self.client_id.is_none_or(|client_id| client_id == transaction.client_id)
+            && self.status.is_none_or(|status| status.matches(&transaction.status))
+            && self.dispute.as_ref().is_none_or(|dispute| discriminant(dispute) == discriminant(&transaction.dispute))
+            && self.from_transaction_id.is_none_or(|from| transaction.transaction_id >= from)
+            && self.to_transaction_id.is_none_or(|to| transaction.transaction_id <= to)
+    }
+}
+
+/// A page of the transactions selected by a query.
+#[derive(Debug, Clone)]
+pub struct TransactionPage {
+    pub transactions: Vec<Transaction>,
+    /// Number of transactions selected by the filters, across every page.
+    pub total: usize,
+}
+
+/// A dispute hold still open.
+#[derive(Debug, Clone)]
+pub struct OpenDispute {
+    pub transaction_id: TransactionId,
+    pub client_id: ClientId,
+    pub case_id: Option<CaseId>,
+    pub amount: Amount,
+    /// Input row of the dispute that opened the hold.
+    pub row: Option<RowNumber>,
+    /// Seconds the hold has been open for.
+    pub age: Timestamp,
+}
+
+/// Funds held by the open disputes of a client next to the funds held by the account, both are
+/// expected to match.
+#[derive(Debug, Clone)]
+pub struct ClientHeld {
+    pub client_id: ClientId,
+    pub disputed: Amount,
+    pub held: Amount,
+}
+
+impl ClientHeld {
+    pub fn is_consistent(&self) -> bool {
+        self.disputed == self.held
+    }
+}
+
+/// The disputes still open and what they hold per client, ordered by transaction and client id.
+#[derive(Debug, Clone, Default)]
+pub struct OpenDisputesReport {
+    pub disputes: Vec<OpenDispute>,
+    pub clients: Vec<ClientHeld>,
+}
+
+fn record_request_metrics(metrics: &Metrics, operation: &str, result: &Result<TransactionOutcome, ServiceError>, seconds: f64) {
+    let outcome = match result {
+        Ok(outcome) => {
+            if let TransactionStatus::Rejected(reason) = &outcome.status {
+                metrics.inc(&REJECTIONS, &[("cause", reason.cause())], 1);
+            }
+            match outcome.status {
+                TransactionStatus::Pending => "pending",
+                TransactionStatus::Applied => "applied",
+                TransactionStatus::Error => "error",
+                TransactionStatus::Rejected(_) => "rejected",
+            }
+        }
+        Err(ServiceError::DuplicateRequest(_))
+        | Err(ServiceError::DataError(RepositoryError::EntityAlreadyExists(_))) => "duplicate",
+        Err(_) => "failed",
+    };
+    metrics.inc(&ROWS_READ, &[], 1);
+    metrics.inc(&REQUESTS, &[("operation", operation), ("outcome", outcome)], 1);
+    metrics.observe(&REQUEST_DURATION, &[], seconds);
 }
 
 /// Kind of a business util function. Sanitizes the transaction amount by checking preconditions.
@@ -209,58 +973,283 @@ fn sanitize_transaction_amount(transaction: &Transaction) -> Result<Amount, Serv
     }
 }
 
+/// Refuses a dispute, resolve or chargeback referencing the transaction of another client: the
+/// funds it would move were never on the account of the requesting client.
+fn other_client_error(transaction: &Transaction) -> ServiceError {
+    GenericErrorMsg(format!("The requested transaction id belongs to another client. {}", transaction.transaction_id))
+}
+
+/// The amount a resolve or chargeback settles from a hold: the whole hold unless the request
+/// specifies a smaller amount.
+fn settlement_amount(hold: &DisputeHold, transaction: &Transaction) -> Result<Amount, ServiceError> {
+    match transaction.amount() {
+        None => Ok(hold.held.clone()),
+        Some(amount) => {
+            if !amount.is_positive() || amount.gt(&hold.held) {
+                Err(GenericErrorMsg(format!("The settled amount exceeds the disputed amount. {}", transaction.transaction_id)))
+            } else {
+                Ok(amount)
+            }
+        }
+    }
+}
+
+/// The transaction time, the processing time for transactions that do not carry one.
+fn transaction_time(transaction: &Transaction) -> Timestamp {
+    transaction.timestamp().unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+    })
+}
+
+/// Releases the amount from the hold at index, dropping the hold once fully settled. Returns the
+/// hold as it was before the release and whether it was dropped.
+fn release_hold(holds: &mut Vec<DisputeHold>, index: usize, amount: &Amount) -> (DisputeHold, bool) {
+    let released = holds[index].clone();
+    let hold = &mut holds[index];
+    hold.held = hold.held.clone().sub(amount).round(ROUND_DIGITS);
+    let closed = hold.held.is_zero();
+    if closed {
+        holds.remove(index);
+    }
+    (released, closed)
+}
+
 impl<AccRep, TxRep> TransactionService<AccRep, TxRep>
     where AccRep: AccountRepository,
           TxRep: TransactionRepository, {
     pub fn new(account_repository: AccRep, transaction_repository: TxRep) -> Self {
+        Self::with_config(account_repository, transaction_repository, ServiceConfig::default())
+    }
+
+    pub fn with_config(account_repository: AccRep, transaction_repository: TxRep, config: ServiceConfig) -> Self {
         TransactionService {
             account_repository,
             transaction_repository,
+            rules: RulesEngine::new(config.rules.clone()),
+            hooks: Vec::new(),
+            subscribers: Vec::new(),
+            published: 0,
+            metrics: None,
+            events: Vec::new(),
+            config,
         }
     }
 
-    pub fn process_transactions_from_file<F>(&mut self, filename: F) -> Result<(), ServiceError>
+    /// Registers a hook run before and after every operation.
+    pub fn register_hook(&mut self, hook: Box<dyn TransactionHook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Registers a subscriber to the domain events. Events are relayed from the outbox after every
+    /// processed request, events left in the outbox by a previous run are relayed too. Events
+    /// raised while no subscriber is registered are not recorded in the outbox.
+    pub fn register_subscriber(&mut self, subscriber: Box<dyn EventSubscriber>) {
+        self.subscribers.push(Subscription { subscriber, acknowledged: 0 });
+    }
+
+    /// Records the processing metrics in the registry.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Relays the events of the outbox to the subscribers, returns the number of events relayed.
+    /// Only the events every subscriber handled leave the outbox. A subscriber that fails stops at
+    /// the failed event, which is relayed to it again by the next publication, and the first
+    /// failure is returned. Subscribers get the events they handled again only after a restart,
+    /// with the same sequence numbers so that they can skip them.
+    pub fn publish_events(&mut self) -> Result<usize, ServiceError> {
+        // Without subscribers the events wait in the outbox.
+        let Some(relayed) = self.subscribers.iter().map(|subscription| subscription.acknowledged).min() else {
+            return Ok(0);
+        };
+        // Only the events a subscriber did not get yet are read.
+        let entries = self.transaction_repository.unpublished_events(relayed)?;
+        let mut failure = None;
+        for subscription in self.subscribers.iter_mut() {
+            let acknowledged = subscription.acknowledged;
+            for entry in entries.iter().filter(|entry| entry.sequence() > acknowledged) {
+                if let Err(err) = subscription.subscriber.on_event(entry) {
+                    warn!(sequence = entry.sequence(), error = %err, "Event not handled by a subscriber, it stays in the outbox");
+                    failure.get_or_insert(err);
+                    break;
+                }
+                subscription.acknowledged = entry.sequence();
+            }
+        }
+        let furthest = self.subscribers.iter().map(|subscription| subscription.acknowledged).max().unwrap_or(0);
+        let relayed = entries.iter().take_while(|entry| entry.sequence() <= furthest).count();
+        // Events handed over to a background subscriber leave the outbox only once handled.
+        let handled = self
+            .subscribers
+            .iter()
+            .map(|subscription| {
+                let handled = subscription.subscriber.handled();
+                handled.map_or(subscription.acknowledged, |handled| handled.min(subscription.acknowledged))
+            })
+            .min()
+            .unwrap_or(0);
+        if handled > self.published {
+            self.transaction_repository.mark_events_published(handled)?;
+            self.published = handled;
+        }
+        match failure {
+            Some(err) => Err(ServiceError::IOError(err)),
+            None => Ok(relayed),
+        }
+    }
+
+    /// Waits until the subscribers handled the events relayed to them, then relays the outbox again
+    /// so the events they handled leave it. Returns the number of events relayed.
+    pub fn flush_events(&mut self) -> Result<usize, ServiceError> {
+        for subscription in self.subscribers.iter_mut() {
+            subscription.subscriber.flush();
+        }
+        self.publish_events()
+    }
+
+    /// Releases the repositories, e.g. to persist their state.
+    pub fn into_repositories(self) -> (AccRep, TxRep) {
+        (self.account_repository, self.transaction_repository)
+    }
+
+    pub fn process_transactions_from_file<F>(&mut self, filename: F) -> Result<ProcessingSummary, ServiceError>
         where F: AsRef<Path> {
         let mut reader = TransactionFileReader::from(filename)?;
-        self.process_transactions(reader.values())
+        let mut summary = self.process_transactions(reader.values())?;
+        summary.bad_rows = reader.bad_rows();
+        if let Some(metrics) = &self.metrics {
+            metrics.inc(&BAD_ROWS, &[], summary.bad_rows);
+            metrics.inc(&ROWS_READ, &[], summary.bad_rows);
+        }
+        Ok(summary)
     }
 
     pub fn report_account_statuses(&mut self) -> Result<(), ServiceError> {
         let mut report = ReportProducer::new();
         let f = |account : &Account| {
-            report.add(&account);
+            report.add(account);
         };
         self.account_repository
             .account_visitor(f)
-            .or_else( |err| Err(GenericErrorMsg(format!("Error accessing account repository. {:?}", err))))
+            .map_err(|err| GenericErrorMsg(format!("Error accessing account repository. {:?}", err)))
+    }
+    /// Writes the dispute history of every transaction to the audit file.
+    pub fn report_dispute_history<F>(&mut self, filename: F) -> Result<(), ServiceError>
+        where F: AsRef<Path> {
+        let mut entries = Vec::new();
+        self.transaction_repository
+            .dispute_history_visitor(&mut |entry: &DisputeHistoryEntry| entries.push(entry.clone()))
+            .map_err(|err| GenericErrorMsg(format!("Error accessing transaction repository. {:?}", err)))?;
+        entries.sort_by_key(|entry| entry.transaction_id);
+
+        let mut audit = AuditProducer::from(filename)?;
+        entries.iter().try_for_each(|entry| audit.add(entry))?;
+        Ok(audit.flush()?)
     }
-    pub fn process_transactions(&mut self, transaction_iter: impl Iterator<Item=TransactionRequest>) -> Result<(), ServiceError> {
+
+    pub fn process_transactions(&mut self, transaction_iter: impl Iterator<Item=TransactionRequest>) -> Result<ProcessingSummary, ServiceError> {
+        let mut summary = ProcessingSummary::default();
+        let started = Instant::now();
+        let mut requests = 0;
         for tx in transaction_iter {
-            match self.process_transaction(tx) {
-                Ok(_) => (),
+            requests += 1;
+            if let Some(operation) = tx.transaction_type {
+                *summary.by_operation.entry(operation).or_default() += 1;
+            }
+            // Every log about the request carries its fields.
+            let span = info_span!("request",
+                row = tx.row,
+                tx = tx.transaction_id,
+                client = tx.client_id,
+                operation = tx.transaction_type.map(field::display));
+            let _entered = span.enter();
+            let operation = tx.transaction_type.map(|operation| operation.to_string()).unwrap_or_default();
+            let request_started = Instant::now();
+            let result = self.process_transaction(tx);
+            if let Some(metrics) = &self.metrics {
+                record_request_metrics(metrics, &operation, &result, request_started.elapsed().as_secs_f64());
+            }
+            match result {
+                Ok(outcome) => {
+                    summary.add_outcome(&outcome);
+                    match &outcome.status {
+                        TransactionStatus::Rejected(reason) => info!(outcome = %outcome.status, %reason, "Transaction rejected"),
+                        TransactionStatus::Error => info!(outcome = %outcome.status, "Transaction not applicable to the account balance"),
+                        TransactionStatus::Pending | TransactionStatus::Applied => debug!(outcome = %outcome.status, "Transaction processed"),
+                    }
+                    if !outcome.annotations.is_empty() {
+                        warn!(outcome = %outcome.status, annotations = %outcome.annotations.join("; "), "Transaction flagged");
+                    }
+                }
+                Err(ServiceError::DuplicateRequest(_))
+                | Err(ServiceError::DataError(RepositoryError::EntityAlreadyExists(_))) => {
+                    summary.skipped_duplicates += 1;
+                    debug!(outcome = "duplicate", "Request already processed, skipped");
+                }
                 Err(err) => {
                     // We want to continue processing other transactions so just notify the error
                     // and continue.
-                    eprintln!("{:?}",err)
+                    summary.failed += 1;
+                    warn!(outcome = "failed", error = ?err, "Request failed");
                 }
             }
 
         }
-        Ok(())
+        self.account_repository.account_visitor(|account: &Account| {
+            if account.locked {
+                summary.locked_accounts += 1;
+            }
+        })?;
+        summary.elapsed_seconds = started.elapsed().as_secs_f64();
+        if summary.elapsed_seconds > 0.0 {
+            summary.throughput = requests as f64 / summary.elapsed_seconds;
+        }
+        Ok(summary)
     }
 
     ///  This method initiates the transaction execution by checking minimum preconditions and then
     /// delegates the rest of the execution to the corresponding method.
-    pub fn process_transaction(&mut self, request: TransactionRequest) -> Result<(), ServiceError> {
+    pub fn process_transaction(&mut self, request: TransactionRequest) -> Result<TransactionOutcome, ServiceError> {
 
         // Obtain a valid transaction from the request or err.
         let transaction = request.valid_transaction()?;
+        let key = request.idempotency_key();
+
+        // Every write of the request is applied together, or not at all. Requests failing a
+        // business check are kept, along with the event reporting the rejection.
+        self.begin()?;
+        let result = self.apply_transaction(&transaction, key.as_deref());
+        match &result {
+            Ok(_) | Err(GenericErrorMsg(_)) => self.commit()?,
+            Err(_) => self.rollback()?,
+        }
+
+        // The request is committed whatever the subscribers do, the events they did not handle
+        // stay in the outbox.
+        if !self.subscribers.is_empty() {
+            if let Err(err) = self.publish_events() {
+                warn!(error = ?err, "Events not published, they stay in the outbox");
+            }
+        }
+        result
+    }
+
+    /// The writes of a request, within a unit of work.
+    fn apply_transaction(&mut self, transaction: &Transaction, key: Option<&str>) -> Result<TransactionOutcome, ServiceError> {
+        // Skip requests already processed, in this run or in a previous one over the same state.
+        if let Some(key) = key {
+            match self.transaction_repository.register_request(key) {
+                Ok(_) => (),
+                Err(RepositoryError::EntityAlreadyExists(_)) => return Err(ServiceError::DuplicateRequest(key.to_string())),
+                Err(err) => return Err(ServiceError::DataError(err)),
+            }
+        }
 
         match transaction.operation {
             Operation::Deposit | Operation::Withdrawal => {
                 // Check if we have already processed the transaction using the transaction id for idempotency.
                 // Note that the transaction status is Pending.
-                self.transaction_repository.post_transaction(&transaction)?;
+                self.transaction_repository.post_transaction(transaction)?;
             }
             Operation::Dispute | Operation::Resolve | Operation::Chargeback => {
                 //  For correctness, all cases need to be declared, in this case these transactions
@@ -268,148 +1257,350 @@ impl<AccRep, TxRep> TransactionService<AccRep, TxRep>
             }
         }
 
-        // Dispatch the operation.
-        let transaction_status = match &transaction.operation {
+        let mut result = self.execute(transaction);
+
+        // Store the events along with the state changes, they are relayed once committed.
+        match &result {
+            Ok(outcome) => match &outcome.status {
+                TransactionStatus::Rejected(reason) => self.raise_rejected(transaction, reason.to_string()),
+                TransactionStatus::Error => self.raise_rejected(transaction, "not applicable to the account balance".to_string()),
+                TransactionStatus::Pending | TransactionStatus::Applied => (),
+            },
+            Err(GenericErrorMsg(reason)) => self.raise_rejected(transaction, reason.clone()),
+            Err(_) => (),
+        }
+        if !self.events.is_empty() {
+            let events: Vec<DomainEvent> = self.events.drain(..).collect();
+            // Nobody would relay the events, the outbox would only grow.
+            if !self.subscribers.is_empty() {
+                self.transaction_repository.append_events(&events)?;
+            }
+            if let Ok(outcome) = &mut result {
+                outcome.events = events;
+            }
+        }
+        result
+    }
+
+    /// Begins a unit of work on both repositories.
+    fn begin(&mut self) -> Result<(), ServiceError> {
+        self.account_repository.begin()?;
+        if let Err(err) = self.transaction_repository.begin() {
+            self.account_repository.rollback()?;
+            return Err(ServiceError::DataError(err));
+        }
+        Ok(())
+    }
+
+    /// Commits the unit of work of both repositories. The transaction repository commits first, as
+    /// it can refuse entities created concurrently by another writer, and the accounts are rolled
+    /// back if it does. With independent stores a failure of the second commit cannot undo the
+    /// first one, stores sharing a database should implement both repositories on the same
+    /// database transaction.
+    fn commit(&mut self) -> Result<(), ServiceError> {
+        if let Err(err) = self.transaction_repository.commit() {
+            self.account_repository.rollback()?;
+            return Err(ServiceError::DataError(err));
+        }
+        self.account_repository.commit()?;
+        Ok(())
+    }
+
+    /// Discards the unit of work of both repositories.
+    fn rollback(&mut self) -> Result<(), ServiceError> {
+        self.events.clear();
+        let account_result = self.account_repository.rollback();
+        self.transaction_repository.rollback()?;
+        Ok(account_result?)
+    }
+
+    fn raise_rejected(&mut self, transaction: &Transaction, reason: String) {
+        self.events.push(DomainEvent::Rejected {
+            client_id: transaction.client_id,
+            transaction_id: transaction.transaction_id,
+            reason,
+        });
+    }
+
+    /// Evaluates the risk rules and the hooks, then dispatches the operation if allowed. The rules
+    /// are evaluated again on every retry, against the activity of the account read by the retry.
+    /// The hooks run once.
+    fn execute(&mut self, transaction: &Transaction) -> Result<TransactionOutcome, ServiceError> {
+        let transaction = transaction.clone();
+        let mut annotations = Vec::new();
+        let mut veto = None;
+        let transaction_status = self.retry_on_conflict(&transaction, |service| {
+            let activity = service.find_account_or_empty(&transaction.client_id)?.activity;
+            if let Some(rule) = service.rules.evaluate(&transaction, &activity) {
+                return service.reject_rule_violation(&transaction, rule);
+            }
+            let veto = match &veto {
+                Some(veto) => veto,
+                None => veto.insert(service.run_hooks_before(&transaction, &mut annotations)?),
+            };
+            match veto {
+                Some(veto) => Ok(TransactionStatus::Rejected(RejectionReason::HookVeto(veto.clone()))),
+                None => service.dispatch(&transaction),
+            }
+        })?;
+
+        // Mark the transaction resolution status, from pending to the target status. Dispute-family
+        // requests reference the disputed transaction, their status is only reported.
+        if matches!(transaction.operation, Operation::Deposit | Operation::Withdrawal) {
+            self.transaction_repository.update_transaction_status(&transaction.transaction_id(), &transaction_status)?;
+        }
+        self.run_hooks_after(&transaction, &transaction_status, &mut annotations)?;
+
+        Ok(TransactionOutcome {
+            status: transaction_status,
+            annotations,
+            events: Vec::new(),
+        })
+    }
+
+    fn dispatch(&mut self, transaction: &Transaction) -> Result<TransactionStatus, ServiceError> {
+        match &transaction.operation {
             Operation::Deposit => {
-                self.process_deposit(&transaction)
+                self.process_deposit(transaction)
             }
             Operation::Withdrawal => {
-                self.process_withdrawal(&transaction)
+                self.process_withdrawal(transaction)
             }
             Operation::Dispute => {
-                self.process_dispute(&transaction)
+                self.process_dispute(transaction)
             }
             Operation::Resolve => {
-                self.process_resolve(&transaction)
+                self.process_resolve(transaction)
             }
             Operation::Chargeback => {
-                self.process_chargeback(&transaction)
+                self.process_chargeback(transaction)
+            }
+        }
+    }
+
+    /// Applies the operation again, on a fresh read of the account, when the account was updated
+    /// by another writer between the read and the update. The handlers change nothing before the
+    /// account update, so a refused update leaves no trace to undo.
+    fn retry_on_conflict<F>(&mut self, transaction: &Transaction, mut operation: F) -> Result<TransactionStatus, ServiceError>
+        where F: FnMut(&mut Self) -> Result<TransactionStatus, ServiceError> {
+        let mut conflicts = 0;
+        loop {
+            match operation(self) {
+                Err(ServiceError::DataError(RepositoryError::InconsistencyDetected(reference))) if conflicts < self.config.conflict_retries => {
+                    conflicts += 1;
+                    debug!(tx = transaction.transaction_id, client = transaction.client_id, conflicts, %reference, "Account changed while the transaction was applied, retrying");
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs the before step of every hook, collecting flags. Returns the first veto if any.
+    fn run_hooks_before(&mut self, transaction: &Transaction, annotations: &mut Vec<String>) -> Result<Option<String>, ServiceError> {
+        if self.hooks.is_empty() {
+            return Ok(None);
+        }
+        let account = self.find_account_or_empty(&transaction.client_id)?;
+        for hook in self.hooks.iter_mut() {
+            match hook.before(transaction, &account) {
+                HookVerdict::Allow => (),
+                HookVerdict::Flag(note) => annotations.push(format!("{}: {}", hook.name(), note)),
+                HookVerdict::Veto(reason) => return Ok(Some(format!("{}: {}", hook.name(), reason))),
             }
-        }?; // Fail in case of a repository problem.
+        }
+        Ok(None)
+    }
+
+    /// Runs the after step of every hook, collecting their notes.
+    fn run_hooks_after(&mut self, transaction: &Transaction, status: &TransactionStatus, annotations: &mut Vec<String>) -> Result<(), ServiceError> {
+        if self.hooks.is_empty() {
+            return Ok(());
+        }
+        let account = self.find_account_or_empty(&transaction.client_id)?;
+        for hook in self.hooks.iter_mut() {
+            let name = hook.name().to_string();
+            annotations.extend(hook.after(transaction, &account, status).into_iter()
+                .map(|note| format!("{}: {}", name, note)));
+        }
+        Ok(())
+    }
 
-        // Mark the transaction resolution status, from pending to the target status.
-        self.transaction_repository.update_transaction_status(&transaction.transaction_id(), &transaction_status)?;
+    /// The account of the client, or an empty one that is not stored, for the operations that do
+    /// not open accounts. Only deposits open accounts.
+    fn find_account_or_empty(&mut self, client_id: &ClientId) -> Result<Account, ServiceError> {
+        Ok(self.account_repository.find_account(client_id)?.unwrap_or_else(|| Account::new(*client_id)))
+    }
 
+    /// Updates the account, if it is still at the version read, and records the new version in its
+    /// history unless the history is turned off.
+    fn update_account(&mut self, transaction: &Transaction, account: &Account, update: &Account) -> Result<(), ServiceError> {
+        let mut update = update.clone();
+        update.version = account.version + 1;
+        self.account_repository.update_account(account, &update)?;
+        if self.config.account_history {
+            self.account_repository.append_account_version(&AccountVersion::new(transaction, &update))?;
+        }
         Ok(())
     }
 
+    /// Rejects a transaction that violates a risk rule, locking the account if so configured.
+    fn reject_rule_violation(&mut self, transaction: &Transaction, rule: String) -> Result<TransactionStatus, ServiceError> {
+        info!(tx = transaction.transaction_id, client = transaction.client_id, %rule, "Transaction violates a risk rule");
+        if self.rules.lock_on_violation() {
+            // Clients without an account have nothing to lock.
+            let account = self.account_repository.find_account(&transaction.client_id)?;
+            if let Some(account) = account.filter(|account| !account.locked) {
+                let mut update = account.clone();
+                update.locked = true;
+                self.update_account(transaction, &account, &update)?;
+                self.events.push(DomainEvent::AccountLocked {
+                    client_id: transaction.client_id,
+                    transaction_id: transaction.transaction_id,
+                });
+            }
+        }
+        Ok(TransactionStatus::Rejected(RejectionReason::RuleViolation(rule)))
+    }
+
     fn process_chargeback(&mut self, transaction: &Transaction) -> Result<TransactionStatus, ServiceError> {
 
         // Must have a valid account.
-        let account = self.account_repository.get_account(&transaction.client_id)?;
+        let account = self.find_account_or_empty(&transaction.client_id)?;
 
         if account.locked {
             return Err(GenericErrorMsg(format!("The requested account is locked and cannot process chargebacks. {}", transaction.transaction_id)));
         }
 
-        // The reference transaction must exist
-        let ref_transaction_opt = self.transaction_repository.find_transaction_by_id(&transaction.transaction_id)?;
-        let ref_transaction = match ref_transaction_opt {
-            None => return Err(GenericErrorMsg(format!("The requested transaction id does not exist. {}", transaction.transaction_id))),
-            Some(ref_transaction) => {
-                if !matches!(ref_transaction.dispute, TransactionDispute::Disputed) {
-                    return Err(GenericErrorMsg(format!("The requested transaction is not disputed. {}", transaction.transaction_id)));
-                } else {
-                    ref_transaction
-                }
-            }
-        };
-        // The reference transaction preconditions are that this tx must be in "Disputed" status.
-
-        let amount_opt = ref_transaction.amount.to_owned();
-        let amount = match amount_opt {
-            None => return Err(GenericErrorMsg(format!("The requested transaction id does not specify an amount. {}", transaction.transaction_id))),
-            Some(amount) => {
-                if amount.gt(&account.held) {
-                    return Ok(TransactionStatus::Error);
-                } else {
-                    amount
-                }
-            }
-        };
+        // The reference transaction must exist and be in "Disputed" status.
+        let ref_transaction = self.find_disputed_transaction(transaction)?;
+        let index = self.find_settled_hold(&ref_transaction, transaction)?;
+        let amount = settlement_amount(&ref_transaction.disputes[index], transaction)?;
+        if amount.gt(&account.held) {
+            return Ok(TransactionStatus::Error);
+        }
 
         let mut update = account.clone();
         update.held = update.held.sub(&amount).round(ROUND_DIGITS);
         update.last_tx_applied = Some(transaction.transaction_id);
         update.locked = true;
-        self.account_repository.update_account(&account, &update)?;
+        self.update_account(transaction, &account, &update)?;
+        self.events.push(DomainEvent::ChargedBack {
+            client_id: transaction.client_id,
+            transaction_id: ref_transaction.transaction_id,
+            case_id: ref_transaction.disputes[index].case_id,
+            amount: amount.clone(),
+        });
+        self.events.push(DomainEvent::AccountLocked {
+            client_id: transaction.client_id,
+            transaction_id: transaction.transaction_id,
+        });
 
-        self.transaction_repository.update_transaction_dispute(&ref_transaction.transaction_id, &TransactionDispute::Chargeback)?;
+        let mut holds = ref_transaction.disputes.clone();
+        let (hold, closed) = release_hold(&mut holds, index, &amount);
+        let charged_back = ref_transaction.charged_back.clone().add(&amount).round(ROUND_DIGITS);
+        self.transaction_repository.update_transaction_holds(&ref_transaction.transaction_id, &holds, &charged_back)?;
+        let dispute = if holds.is_empty() { TransactionDispute::Chargeback } else { TransactionDispute::Disputed };
+        self.transaction_repository.update_transaction_dispute(&ref_transaction.transaction_id, &dispute)?;
+        if closed {
+            self.close_dispute_case(&hold, DisputeCaseState::ChargedBack)?;
+        }
+        self.record_dispute_action(&ref_transaction, transaction, DisputeAction::ChargedBack, &hold, amount)?;
 
         Ok(TransactionStatus::Applied)
     }
 
     fn process_resolve(&mut self, transaction: &Transaction) -> Result<TransactionStatus, ServiceError> {
-        let account = self.account_repository.get_account(&transaction.client_id)?;
+        let account = self.find_account_or_empty(&transaction.client_id)?;
 
         if account.locked {
             return Err(GenericErrorMsg(format!("The requested account is locked and cannot process further. {}", transaction.transaction_id)));
         }
 
-        let ref_transaction_opt = self.transaction_repository.find_transaction_by_id(&transaction.transaction_id)?;
-
-        let ref_transaction = match ref_transaction_opt {
-            None => return Err(GenericErrorMsg(format!("The requested transaction id does not exist. {}", transaction.transaction_id))),
-            Some(ref_transaction) => {
-                if !matches!(ref_transaction.dispute, TransactionDispute::Disputed) {
-                    return Err(GenericErrorMsg(format!("The requested transaction is not disputed. {}", transaction.transaction_id)));
-                } else {
-                    ref_transaction
-                }
-            }
-        };
-
-        let amount_opt = ref_transaction.amount();
-        let amount = match amount_opt {
-            None => return Err(GenericErrorMsg(format!("The requested transaction id does not specify an amount. {}", transaction.transaction_id))),
-            Some(amount) => {
-                if amount.gt(&account.held) {
-                    return Ok(TransactionStatus::Error);
-                } else {
-                    amount
-                }
-            }
-        };
+        let ref_transaction = self.find_disputed_transaction(transaction)?;
+        let index = self.find_settled_hold(&ref_transaction, transaction)?;
+        let amount = settlement_amount(&ref_transaction.disputes[index], transaction)?;
+        if amount.gt(&account.held) {
+            return Ok(TransactionStatus::Error);
+        }
 
         let mut update = account.clone();
         update.available = update.available.add(&amount).round(ROUND_DIGITS);
         update.held = update.held.sub(&amount).round(ROUND_DIGITS);
         update.last_tx_applied = Some(transaction.transaction_id);
-        self.account_repository.update_account(&account, &update)?;
+        self.update_account(transaction, &account, &update)?;
+        self.events.push(DomainEvent::DisputeResolved {
+            client_id: transaction.client_id,
+            transaction_id: ref_transaction.transaction_id,
+            case_id: ref_transaction.disputes[index].case_id,
+            amount: amount.clone(),
+        });
 
-        self.transaction_repository.update_transaction_dispute(&ref_transaction.transaction_id, &TransactionDispute::Resolved)?;
+        let mut holds = ref_transaction.disputes.clone();
+        let (hold, closed) = release_hold(&mut holds, index, &amount);
+        self.transaction_repository.update_transaction_holds(&ref_transaction.transaction_id, &holds, &ref_transaction.charged_back)?;
+        let dispute = if holds.is_empty() { TransactionDispute::Resolved } else { TransactionDispute::Disputed };
+        self.transaction_repository.update_transaction_dispute(&ref_transaction.transaction_id, &dispute)?;
+        if closed {
+            self.close_dispute_case(&hold, DisputeCaseState::Resolved)?;
+        }
+        self.record_dispute_action(&ref_transaction, transaction, DisputeAction::Resolved, &hold, amount)?;
 
         Ok(TransactionStatus::Applied)
     }
 
     fn process_dispute(&mut self, transaction: &Transaction) -> Result<TransactionStatus, ServiceError> {
-        let account = self.account_repository.get_account(&transaction.client_id)?;
+        let account = self.find_account_or_empty(&transaction.client_id)?;
 
         if account.locked {
             return Err(GenericErrorMsg(format!("The requested account is locked and cannot process further. {}", transaction.transaction_id)));
         }
 
+        // A retried dispute row carries a case that was already opened.
+        if let Some(case_id) = transaction.case_id {
+            if self.transaction_repository.find_dispute_case(&case_id)?.is_some() {
+                return Err(ServiceError::DataError(RepositoryError::EntityAlreadyExists(format!("case {}", case_id))));
+            }
+        }
+
         let ref_transaction_opt = self.transaction_repository.find_transaction_by_id(&transaction.transaction_id)?;
         let (ref_transaction, amount) = match ref_transaction_opt {
             None => return Err(GenericErrorMsg(format!("The requested transaction id does not exist. {}", transaction.transaction_id))),
+            Some(ref_transaction) if ref_transaction.client_id != transaction.client_id => return Err(other_client_error(transaction)),
             Some(ref_transaction) => {
-                match ref_transaction.dispute {
-                    TransactionDispute::Disputed | TransactionDispute::Chargeback => {
-                        return Err(GenericErrorMsg(format!("The requested transaction id is in dispute status and is immutable until resolution. {}", transaction.transaction_id)));
+                if matches!(ref_transaction.dispute, TransactionDispute::Chargeback) {
+                    return Err(GenericErrorMsg(format!("The requested transaction id was charged back and is immutable. {}", transaction.transaction_id)));
+                }
+
+                if let Some(max_dispute_cycles) = self.config.max_dispute_cycles {
+                    if self.dispute_cycles(&ref_transaction.transaction_id)? >= max_dispute_cycles {
+                        return Err(GenericErrorMsg(format!("The requested transaction id reached the maximum number of dispute cycles. {}", transaction.transaction_id)));
                     }
-                    TransactionDispute::No | TransactionDispute::Resolved => (),
-                };
+                }
 
-                let amount = match ref_transaction.amount() {
+                let disputable = match ref_transaction.disputable_amount() {
                     // This should never happen, if this happens the repository is corrupted.
                     None => return Err(GenericErrorMsg(format!("The requested transaction id does not specify an amount. {}", transaction.transaction_id))),
+                    // Refused transactions with a negative amount are kept, but never moved funds.
+                    Some(disputable) if disputable.is_negative() => return Err(GenericErrorMsg(format!("The requested transaction id has a negative amount. {}", transaction.transaction_id))),
+                    Some(disputable) => disputable,
+                };
+
+                // Disputes without an amount claim everything that is not disputed yet.
+                let amount = match transaction.amount() {
+                    None => disputable,
                     Some(amount) => {
-                        if amount.gt(&account.available) {
-                            return Ok(TransactionStatus::Error);
+                        if !amount.is_positive() || amount.gt(&disputable) {
+                            return Err(GenericErrorMsg(format!("The disputed amount exceeds the undisputed portion of the transaction. {}", transaction.transaction_id)));
                         }
                         amount
                     }
                 };
+                if amount.is_zero() {
+                    return Err(GenericErrorMsg(format!("The requested transaction id is fully disputed and is immutable until resolution. {}", transaction.transaction_id)));
+                }
+                if amount.gt(&account.available) {
+                    return Ok(TransactionStatus::Error);
+                }
                 (ref_transaction, amount)
             }
         };
@@ -418,20 +1609,123 @@ impl<AccRep, TxRep> TransactionService<AccRep, TxRep>
         update.available = update.available.sub(&amount).round(ROUND_DIGITS);
         update.held = update.held.add(&amount).round(ROUND_DIGITS);
         update.last_tx_applied = Some(transaction.transaction_id);
-        self.account_repository.update_account(&account, &update)?;
+        self.update_account(transaction, &account, &update)?;
+        self.events.push(DomainEvent::DisputeOpened {
+            client_id: transaction.client_id,
+            transaction_id: ref_transaction.transaction_id,
+            case_id: transaction.case_id,
+            amount: amount.clone(),
+        });
 
+        let cycle = self.dispute_cycles(&ref_transaction.transaction_id)? + 1;
+        let hold = DisputeHold {
+            held: amount.clone(),
+            cycle,
+            case_id: transaction.case_id,
+            row: transaction.row,
+            opened_at: transaction_time(transaction),
+        };
+        if let Some(case_id) = transaction.case_id {
+            self.transaction_repository.post_dispute_case(&DisputeCase {
+                case_id,
+                transaction_id: ref_transaction.transaction_id,
+                client_id: transaction.client_id,
+                state: DisputeCaseState::Open,
+            })?;
+        }
+        let mut holds = ref_transaction.disputes.clone();
+        holds.push(hold.clone());
+        self.transaction_repository.update_transaction_holds(&ref_transaction.transaction_id, &holds, &ref_transaction.charged_back)?;
         self.transaction_repository.update_transaction_dispute(&ref_transaction.transaction_id, &TransactionDispute::Disputed)?;
+        self.record_dispute_action(&ref_transaction, transaction, DisputeAction::Opened, &hold, amount)?;
 
         Ok(TransactionStatus::Applied)
     }
 
+    /// Finds the transaction referenced by a resolve or chargeback, which must be a disputed
+    /// transaction of the same client.
+    fn find_disputed_transaction(&mut self, transaction: &Transaction) -> Result<Transaction, ServiceError> {
+        match self.transaction_repository.find_transaction_by_id(&transaction.transaction_id)? {
+            None => Err(GenericErrorMsg(format!("The requested transaction id does not exist. {}", transaction.transaction_id))),
+            Some(ref_transaction) if ref_transaction.client_id != transaction.client_id => Err(other_client_error(transaction)),
+            Some(ref_transaction) => {
+                if !matches!(ref_transaction.dispute, TransactionDispute::Disputed) || ref_transaction.disputes.is_empty() {
+                    Err(GenericErrorMsg(format!("The requested transaction is not disputed. {}", transaction.transaction_id)))
+                } else {
+                    Ok(ref_transaction)
+                }
+            }
+        }
+    }
+
+    /// Finds the index of the open dispute a resolve or chargeback settles: the one of the
+    /// referenced case, or the oldest one if the request does not reference a case.
+    fn find_settled_hold(&mut self, ref_transaction: &Transaction, transaction: &Transaction) -> Result<usize, ServiceError> {
+        let case_id = match transaction.case_id {
+            None => return Ok(0),
+            Some(case_id) => case_id,
+        };
+        let case = match self.transaction_repository.find_dispute_case(&case_id)? {
+            None => return Err(GenericErrorMsg(format!("The requested dispute case does not exist. {}", case_id))),
+            Some(case) => case,
+        };
+        if case.transaction_id != ref_transaction.transaction_id || case.client_id != transaction.client_id {
+            return Err(GenericErrorMsg(format!("The requested dispute case does not belong to the transaction. {}", case_id)));
+        }
+        if !matches!(case.state, DisputeCaseState::Open) {
+            return Err(GenericErrorMsg(format!("The requested dispute case is already closed. {}", case_id)));
+        }
+        ref_transaction.disputes.iter()
+            .position(|hold| hold.case_id == Some(case_id))
+            .ok_or_else(|| GenericErrorMsg(format!("The requested dispute case holds no funds. {}", case_id)))
+    }
+
+    /// Closes the case of a fully settled hold, if the hold was opened with one.
+    fn close_dispute_case(&mut self, hold: &DisputeHold, state: DisputeCaseState) -> Result<(), ServiceError> {
+        match hold.case_id {
+            None => Ok(()),
+            Some(case_id) => Ok(self.transaction_repository.update_dispute_case_state(&case_id, &state)?),
+        }
+    }
+
+    /// Number of dispute cycles opened so far for the transaction.
+    fn dispute_cycles(&mut self, transaction_id: &TransactionId) -> Result<u32, ServiceError> {
+        let history = self.transaction_repository.find_dispute_history(transaction_id)?;
+        Ok(history.iter().filter(|entry| entry.action == DisputeAction::Opened).count() as u32)
+    }
+
+    /// Appends the action to the dispute history of the referenced transaction.
+    fn record_dispute_action(&mut self, ref_transaction: &Transaction, transaction: &Transaction, action: DisputeAction, hold: &DisputeHold, amount: Amount) -> Result<(), ServiceError> {
+        let entry = DisputeHistoryEntry {
+            transaction_id: ref_transaction.transaction_id,
+            client_id: ref_transaction.client_id,
+            case_id: hold.case_id,
+            cycle: hold.cycle,
+            action,
+            amount,
+            row: transaction.row,
+        };
+        Ok(self.transaction_repository.append_dispute_history(&entry)?)
+    }
+
+    /// The rules allowed the transaction on an earlier read of the account. Fails with a conflict,
+    /// retried on a fresh read, if another writer changed the activity of the account since so
+    /// that they no longer do.
+    fn check_rules_still_allow(&self, transaction: &Transaction, account: &Account) -> Result<(), ServiceError> {
+        match self.rules.evaluate(transaction, &account.activity) {
+            None => Ok(()),
+            Some(_) => Err(ServiceError::DataError(RepositoryError::InconsistencyDetected(format!("{}", transaction.client_id)))),
+        }
+    }
+
     fn process_withdrawal(&mut self, transaction: &Transaction) -> Result<TransactionStatus, ServiceError> {
-        let amount = sanitize_transaction_amount(&transaction)?;
-        let account = self.account_repository.get_account(&transaction.client_id)?;
+        let amount = sanitize_transaction_amount(transaction)?;
+        let account = self.find_account_or_empty(&transaction.client_id)?;
 
         if account.locked {
             return Err(GenericErrorMsg(format!("The requested account is locked and cannot process withdrawals. {}", transaction.transaction_id)));
         }
+        self.check_rules_still_allow(transaction, &account)?;
 
         // We reject the withdrawal as it is not applicable to our view of the balance.
         if amount.gt(&account.available) {
@@ -440,47 +1734,172 @@ impl<AccRep, TxRep> TransactionService<AccRep, TxRep>
         let mut update = account.clone();
         update.available = update.available.sub(&amount).round(ROUND_DIGITS);
         update.last_tx_applied = Some(transaction.transaction_id);
-        self.account_repository.update_account(&account, &update)?;
+        self.rules.record(&mut update.activity, transaction);
+        self.update_account(transaction, &account, &update)?;
+        self.events.push(DomainEvent::Withdrawn {
+            client_id: transaction.client_id,
+            transaction_id: transaction.transaction_id,
+            amount,
+        });
         Ok(TransactionStatus::Applied)
     }
 
     fn process_deposit(&mut self, transaction: &Transaction) -> Result<TransactionStatus, ServiceError> {
-        let amount = sanitize_transaction_amount(&transaction)?;
+        let amount = sanitize_transaction_amount(transaction)?;
 
-        let account = self.account_repository.get_account(&transaction.client_id)?;
+        let account = match self.account_repository.find_account(&transaction.client_id)? {
+            Some(account) => account,
+            None => self.account_repository.open_account(&transaction.client_id)?,
+        };
 
         if account.locked {
             return Err(GenericErrorMsg(format!("The requested account is locked and cannot process deposits. {}", transaction.transaction_id)));
         }
+        self.check_rules_still_allow(transaction, &account)?;
 
         let mut update = account.clone();
         update.available = update.available.add(&amount).round(ROUND_DIGITS);
         update.last_tx_applied = Some(transaction.transaction_id);
-        self.account_repository.update_account(&account, &update)?;
+        self.rules.record(&mut update.activity, transaction);
+        self.update_account(transaction, &account, &update)?;
+        self.events.push(DomainEvent::Deposited {
+            client_id: transaction.client_id,
+            transaction_id: transaction.transaction_id,
+            amount,
+        });
         Ok(TransactionStatus::Applied)
     }
 
-    #[allow(dead_code)]
-    pub fn get_account_status(&mut self, client_id: &ClientId) -> Result<Account, ServiceError> {
-        match self.account_repository.get_account(client_id) {
-            Ok(account) => { Ok(account) }
-            Err(e) => { Err(ServiceError::DataError(e)) }
+    /// The account of the client, fails with EntityNotFound if the client has none.
+    pub fn get_account_status(&mut self, client_id: &ClientId) -> Result<Account, ServiceError> {
+        match self.account_repository.find_account(client_id) {
+            Ok(Some(account)) => { Ok(account) }
+            Ok(None) => { Err(ServiceError::DataError(RepositoryError::EntityNotFound(client_id.to_string()))) }
+            Err(e) => { Err(ServiceError::DataError(e)) }
+        }
+    }
+
+    /// Every account known to the service.
+    pub fn accounts(&mut self) -> Result<Vec<Account>, ServiceError> {
+        let mut accounts = Vec::new();
+        self.account_repository.account_visitor(|account: &Account| accounts.push(account.clone()))?;
+        Ok(accounts)
+    }
+
+    /// The account of the client as it was at the point of its history, None if the account had
+    /// no state yet at that point, or never applied the transaction.
+    pub fn get_account_at(&mut self, client_id: &ClientId, point: &AccountPoint) -> Result<Option<Account>, ServiceError> {
+        let versions = self.account_repository.find_account_versions(client_id)?;
+        let version = match point {
+            AccountPoint::AfterTransaction(transaction_id) => versions.iter().find(|version| {
+                version.transaction_id == *transaction_id
+                    && matches!(version.operation, Operation::Deposit | Operation::Withdrawal)
+            }),
+            AccountPoint::At(timestamp) => versions.iter()
+                .take_while(|version| version.timestamp.is_none_or(|time| time <= *timestamp))
+                .last(),
+        };
+        Ok(version.map(|version| version.account.clone()))
+    }
+
+    pub fn find_transaction(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, ServiceError> {
+        Ok(self.transaction_repository.find_transaction_by_id(transaction_id)?)
+    }
+
+    pub fn find_transactions(&mut self, query: &TransactionQuery) -> Result<TransactionPage, ServiceError> {
+        Ok(self.transaction_repository.find_transactions(query)?)
+    }
+
+    /// Lists the open disputes, aged as of the given time, and cross-checks the funds they hold
+    /// with the held funds of every account.
+    pub fn open_disputes(&mut self, as_of: Timestamp) -> Result<OpenDisputesReport, ServiceError> {
+        let disputed = self.find_transactions(&TransactionQuery {
+            dispute: Some(TransactionDispute::Disputed),
+            ..TransactionQuery::default()
+        })?;
+        let mut disputed_by_client_id: HashMap<ClientId, Amount> = HashMap::new();
+        let mut report = OpenDisputesReport::default();
+        // Only the client of a transaction disputes it, so its holds are held on that client's account.
+        for transaction in disputed.transactions.iter() {
+            for hold in transaction.disputes.iter() {
+                let disputed = disputed_by_client_id.entry(transaction.client_id).or_insert_with(BigDecimal::zero);
+                *disputed = disputed.clone().add(&hold.held).round(ROUND_DIGITS);
+                report.disputes.push(OpenDispute {
+                    transaction_id: transaction.transaction_id,
+                    client_id: transaction.client_id,
+                    case_id: hold.case_id,
+                    amount: hold.held.clone(),
+                    row: hold.row,
+                    age: as_of.saturating_sub(hold.opened_at),
+                });
+            }
         }
+        self.account_repository.account_visitor(|account: &Account| {
+            let disputed = disputed_by_client_id.remove(&account.client_id).unwrap_or_else(BigDecimal::zero);
+            if !disputed.is_zero() || !account.held.is_zero() {
+                report.clients.push(ClientHeld {
+                    client_id: account.client_id,
+                    disputed,
+                    held: account.held.clone(),
+                });
+            }
+        })?;
+        // Disputes of clients without an account.
+        report.clients.extend(disputed_by_client_id.into_iter().map(|(client_id, disputed)| ClientHeld {
+            client_id,
+            disputed,
+            held: BigDecimal::zero(),
+        }));
+        report.clients.sort_by_key(|client| client.client_id);
+        Ok(report)
     }
+
+    /// The dispute history of a transaction, oldest entry first.
+    pub fn dispute_history(&mut self, transaction_id: &TransactionId) -> Result<Vec<DisputeHistoryEntry>, ServiceError> {
+        Ok(self.transaction_repository.find_dispute_history(transaction_id)?)
+    }
+}
+
+/// Groups the writes made to a repository so that they are applied together or not at all. The
+/// in-memory repositories stage the writes until committed, a database backed one would map the
+/// unit to a database transaction. Reads made within a unit see its own writes.
+pub trait UnitOfWork {
+    /// Starts staging the writes. Fails if a unit of work is already open.
+    fn begin(&mut self) -> Result<(), RepositoryError>;
+
+    /// Applies the writes staged since the unit of work began. The unit is closed even if the
+    /// commit fails, nothing of it being applied then.
+    fn commit(&mut self) -> Result<(), RepositoryError>;
+
+    /// Discards the writes staged since the unit of work began.
+    fn rollback(&mut self) -> Result<(), RepositoryError>;
 }
 
-pub trait AccountRepository {
-    /// Always returns an account, if the account does not exists it is created.
-    fn get_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError>;
+/// Storage of the client accounts.
+pub trait AccountRepository: UnitOfWork {
+    /// The account of the client, None if the client has no account. Never creates an account.
+    fn find_account(&mut self, client_id: &ClientId) -> Result<Option<Account>, RepositoryError>;
+
+    /// Opens an empty account for the client. Fails with EntityAlreadyExists if the client
+    /// already has one.
+    fn open_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError>;
 
     /// Do a CAS update validating that the account is in the state we believe it is before applying
     /// changes. This is useful in optimistic locking mechanisms at shared remote repositories.
+    /// Fails with InconsistencyDetected if the stored account is no longer at the version read.
     fn update_account(&mut self, account: &Account, update: &Account) -> Result<(), RepositoryError>;
 
     fn account_visitor<F>(&mut self, f: F) -> Result<(), RepositoryError> where F: FnMut(&Account);
+
+    /// Appends a version to the history of the account.
+    fn append_account_version(&mut self, version: &AccountVersion) -> Result<(), RepositoryError>;
+
+    /// The history of the account of the client, oldest version first.
+    fn find_account_versions(&mut self, client_id: &ClientId) -> Result<Vec<AccountVersion>, RepositoryError>;
 }
 
-pub trait TransactionRepository {
+/// Storage of the transactions, their disputes and the processed requests.
+pub trait TransactionRepository: UnitOfWork {
     /// Attempt to post the transaction into the repository. Fails if the transaction already exists.
     fn post_transaction(&mut self, transaction: &Transaction) -> Result<(), RepositoryError>;
 
@@ -490,26 +1909,90 @@ pub trait TransactionRepository {
     /// Updates the target transaction id dispute.
     fn update_transaction_dispute(&mut self, transaction_id: &TransactionId, dispute: &TransactionDispute) -> Result<(), RepositoryError>;
 
+    /// Updates the open dispute holds and the charged back amount of the target transaction id.
+    fn update_transaction_holds(&mut self, transaction_id: &TransactionId, holds: &[DisputeHold], charged_back: &Amount) -> Result<(), RepositoryError>;
+
     /// Optionally find a transaction by id.
     fn find_transaction_by_id(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, RepositoryError>;
+
+    /// The page of the transactions selected by the query.
+    fn find_transactions(&mut self, query: &TransactionQuery) -> Result<TransactionPage, RepositoryError>;
+
+    /// Registers the idempotency key of a processed request. Fails if the key already exists.
+    fn register_request(&mut self, key: &str) -> Result<(), RepositoryError>;
+
+    /// Attempt to post a dispute case into the repository. Fails if the case already exists.
+    fn post_dispute_case(&mut self, case: &DisputeCase) -> Result<(), RepositoryError>;
+
+    /// Updates the target dispute case state.
+    fn update_dispute_case_state(&mut self, case_id: &CaseId, state: &DisputeCaseState) -> Result<(), RepositoryError>;
+
+    /// Optionally find a dispute case by id.
+    fn find_dispute_case(&mut self, case_id: &CaseId) -> Result<Option<DisputeCase>, RepositoryError>;
+
+    /// Appends events to the outbox, assigning them consecutive sequence numbers.
+    fn append_events(&mut self, events: &[DomainEvent]) -> Result<(), RepositoryError>;
+
+    /// The events of the outbox not published yet with a sequence number above `after`, oldest
+    /// first.
+    fn unpublished_events(&mut self, after: u64) -> Result<Vec<OutboxEntry>, RepositoryError>;
+
+    /// Marks every event up to the sequence number as published.
+    fn mark_events_published(&mut self, sequence: u64) -> Result<(), RepositoryError>;
+
+    /// Appends an entry to the dispute history of the entry transaction id.
+    fn append_dispute_history(&mut self, entry: &DisputeHistoryEntry) -> Result<(), RepositoryError>;
+
+    /// Returns the dispute history of the transaction, oldest entry first. Empty if never disputed.
+    fn find_dispute_history(&mut self, transaction_id: &TransactionId) -> Result<Vec<DisputeHistoryEntry>, RepositoryError>;
+
+    fn dispute_history_visitor(&mut self, f: &mut dyn FnMut(&DisputeHistoryEntry)) -> Result<(), RepositoryError>;
 }
 
+#[cfg(test)]
+mod properties;
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
     use mockall::mock;
     use mockall::predicate::*;
 
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::thread;
     use crate::domain::*;
-    use crate::{InMemAccountRepository, InMemTransactionRepository, TransactionService};
+    use crate::repository::{InMemAccountRepository, InMemSnapshot, InMemTransactionRepository, ShardedTransactionRepository};
+    use crate::rules::RulesConfig;
+    use crate::hooks::{LargeDepositThenWithdraw, RapidDisputes};
+    use crate::infrastructure::EventLogWriter;
 
     mock! {
         pub TransactionRepo {}
+        impl UnitOfWork for TransactionRepo {
+            fn begin(&mut self) -> Result<(), RepositoryError>;
+            fn commit(&mut self) -> Result<(), RepositoryError>;
+            fn rollback(&mut self) -> Result<(), RepositoryError>;
+        }
         impl TransactionRepository for TransactionRepo {
             fn post_transaction(&mut self, transaction: &Transaction) -> Result<(), RepositoryError>;
             fn update_transaction_status(&mut self, transaction_id: &TransactionId, status: &TransactionStatus) -> Result<(), RepositoryError>;
             fn find_transaction_by_id(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, RepositoryError>;
+            fn find_transactions(&mut self, query: &TransactionQuery) -> Result<TransactionPage, RepositoryError>;
             fn update_transaction_dispute(&mut self, transaction_id: &TransactionId, dispute: &TransactionDispute) -> Result<(), RepositoryError>;
+            fn update_transaction_holds(&mut self, transaction_id: &TransactionId, holds: &[DisputeHold], charged_back: &Amount) -> Result<(), RepositoryError>;
+            fn register_request(&mut self, key: &str) -> Result<(), RepositoryError>;
+            fn post_dispute_case(&mut self, case: &DisputeCase) -> Result<(), RepositoryError>;
+            fn update_dispute_case_state(&mut self, case_id: &CaseId, state: &DisputeCaseState) -> Result<(), RepositoryError>;
+            fn find_dispute_case(&mut self, case_id: &CaseId) -> Result<Option<DisputeCase>, RepositoryError>;
+            fn append_events(&mut self, events: &[DomainEvent]) -> Result<(), RepositoryError>;
+            fn unpublished_events(&mut self, after: u64) -> Result<Vec<OutboxEntry>, RepositoryError>;
+            fn mark_events_published(&mut self, sequence: u64) -> Result<(), RepositoryError>;
+            fn append_dispute_history(&mut self, entry: &DisputeHistoryEntry) -> Result<(), RepositoryError>;
+            fn find_dispute_history(&mut self, transaction_id: &TransactionId) -> Result<Vec<DisputeHistoryEntry>, RepositoryError>;
+            fn dispute_history_visitor(&mut self, f: &mut dyn FnMut(&DisputeHistoryEntry)) -> Result<(), RepositoryError>;
         }
     }
 
@@ -523,6 +2006,10 @@ mod test {
             client_id: Some(1),
             transaction_id: Some(1),
             amount: Some(BigDecimal::from_str("1.2345").unwrap()),
+            case_id: None,
+            timestamp: None,
+            row: None,
+            request_id: Some("1".to_string()),
         });
         assert!(result.is_ok());
         let result = transaction_service.process_transaction(TransactionRequest {
@@ -530,15 +2017,13 @@ mod test {
             client_id: Some(1),
             transaction_id: Some(1),
             amount: Some(BigDecimal::from_str("1.2345").unwrap()),
+            case_id: None,
+            timestamp: None,
+            row: None,
+            request_id: Some("2".to_string()),
         });
         assert!(result.is_err());
-        let err = result.map_err(|e| match e {
-            ServiceError::DataError(e) => match e {
-                RepositoryError::EntityAlreadyExists(_) => true,
-                _ => false,
-            },
-            _ => false
-        });
+        let err = result.map_err(|e| matches!(e, ServiceError::DataError(RepositoryError::EntityAlreadyExists(_))));
         assert!(err.err().unwrap());
 
         let result = transaction_service.process_transaction(TransactionRequest {
@@ -546,6 +2031,10 @@ mod test {
             client_id: Some(1),
             transaction_id: Some(2),
             amount: Some(BigDecimal::from_str("1.2345").unwrap()),
+            case_id: None,
+            timestamp: None,
+            row: None,
+            request_id: None,
         });
         assert!(result.is_ok());
     }
@@ -562,8 +2051,25 @@ mod test {
             client_id: Some(1),
             transaction_id: None,
             amount: Some(BigDecimal::from_str("1.2345").unwrap()),
+            case_id: None,
+            timestamp: None,
+            row: None,
+            request_id: None,
         });
-        assert!(result.is_err());
+        assert!(matches!(result, Err(ServiceError::InvalidRequest(RequestError::MissingTransactionId))));
+
+        // No client.
+        let result = transaction_service.process_transaction(TransactionRequest {
+            transaction_type: Some(Operation::Deposit),
+            client_id: None,
+            transaction_id: Some(1),
+            amount: Some(BigDecimal::from_str("1.2345").unwrap()),
+            case_id: None,
+            timestamp: None,
+            row: None,
+            request_id: None,
+        });
+        assert!(matches!(result, Err(ServiceError::InvalidRequest(RequestError::MissingClient))));
 
         // Invalid number of decimal positions.
         let result = transaction_service.process_transaction(TransactionRequest {
@@ -571,8 +2077,54 @@ mod test {
             client_id: Some(1),
             transaction_id: Some(1),
             amount: Some(BigDecimal::from_str("1.23456").unwrap()),
+            case_id: None,
+            timestamp: None,
+            row: None,
+            request_id: None,
         });
-        assert!(result.is_err());
+        assert!(matches!(result, Err(ServiceError::InvalidRequest(RequestError::InvalidAmount(_)))));
+
+        // No amount, nothing is kept of the invalid request.
+        let deposit = TransactionRequest::builder(Operation::Deposit, 1, 1);
+        let result = transaction_service.process_transaction(deposit.clone().build());
+        assert!(matches!(result, Err(ServiceError::InvalidRequest(RequestError::MissingAmount(Operation::Deposit)))));
+        let result = transaction_service.process_transaction(deposit.amount(BigDecimal::from_str("1.5").unwrap()).build());
+        assert!(matches!(result.unwrap().status, TransactionStatus::Applied));
+    }
+
+    #[test]
+    fn transactions_require_the_fields_of_their_operation() {
+        let operations = [Operation::Deposit, Operation::Withdrawal, Operation::Dispute, Operation::Resolve, Operation::Chargeback];
+        for operation in operations {
+            // Every combination of the type, client, tx and amount fields.
+            for present in 0..16 {
+                let has = |field: u32| present & (1 << field) != 0;
+                let request = TransactionRequest {
+                    transaction_type: has(0).then_some(operation),
+                    client_id: has(1).then_some(1),
+                    transaction_id: has(2).then_some(2),
+                    amount: has(3).then(|| BigDecimal::from_str("1.5").unwrap()),
+                    case_id: None,
+                    timestamp: None,
+                    row: None,
+                    request_id: None,
+                };
+                let expected = if !has(0) {
+                    Err(RequestError::MissingOperation)
+                } else if !has(1) {
+                    Err(RequestError::MissingClient)
+                } else if !has(2) {
+                    Err(RequestError::MissingTransactionId)
+                } else if !has(3) && operation.requires_amount() {
+                    Err(RequestError::MissingAmount(operation))
+                } else {
+                    Ok((operation, 1, 2, has(3).then(|| BigDecimal::from_str("1.5000").unwrap())))
+                };
+                let result = Transaction::try_from(request)
+                    .map(|transaction| (*transaction.operation(), transaction.client_id(), transaction.transaction_id(), transaction.amount()));
+                assert_eq!(expected, result, "{} with fields {:04b}", operation, present);
+            }
+        }
     }
 
     #[test]
@@ -586,30 +2138,50 @@ mod test {
                 transaction_id: Some(1),
                 client_id: Some(1),
                 amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Deposit),
                 transaction_id: Some(2),
                 client_id: Some(1),
                 amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Withdrawal),
                 transaction_id: Some(3),
                 client_id: Some(1),
                 amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Dispute),
                 transaction_id: Some(2),
                 client_id: Some(1),
                 amount: None,
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Resolve),
                 transaction_id: Some(2),
                 client_id: Some(1),
                 amount: None,
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
         ];
         let result = transaction_service.process_transactions(valid_transactions.into_iter());
@@ -634,18 +2206,30 @@ mod test {
                 transaction_id: Some(1),
                 client_id: Some(1),
                 amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Dispute),
                 transaction_id: Some(1),
                 client_id: Some(1),
                 amount: None,
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Chargeback),
                 transaction_id: Some(1),
                 client_id: Some(1),
                 amount: None,
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
         ];
         transaction_service.process_transactions(valid_transactions.into_iter())?;
@@ -670,18 +2254,30 @@ mod test {
                 transaction_id: Some(1),
                 client_id: Some(1),
                 amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Dispute),
                 transaction_id: Some(1),
                 client_id: Some(1),
                 amount: None,
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Chargeback),
                 transaction_id: Some(1),
                 client_id: Some(1),
                 amount: None,
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
 
         ];
@@ -692,6 +2288,10 @@ mod test {
             transaction_id: Some(2),
             client_id: Some(1),
             amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+            case_id: None,
+            timestamp: None,
+            row: None,
+            request_id: None,
         });
 
         assert!(result.is_err());
@@ -717,30 +2317,50 @@ mod test {
                 transaction_id: Some(1),
                 client_id: Some(1),
                 amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Deposit),
                 transaction_id: Some(2),
                 client_id: Some(1),
                 amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Withdrawal),
                 transaction_id: Some(3),
                 client_id: Some(1),
                 amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Dispute),
                 transaction_id: Some(2),
                 client_id: Some(1),
                 amount: None,
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
             TransactionRequest {
                 transaction_type: Some(Operation::Resolve),
                 transaction_id: Some(2),
                 client_id: Some(1),
                 amount: None,
+                case_id: None,
+                timestamp: None,
+                row: None,
+                request_id: None,
             },
         ];
         transaction_service.process_transactions(valid_transactions.into_iter())?;
@@ -748,4 +2368,921 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_dispute_history_and_max_cycles() -> Result<(), Box<dyn std::error::Error>> {
+        let acc_repo = InMemAccountRepository::default();
+        let tx_repo = InMemTransactionRepository::default();
+        let config = ServiceConfig { max_dispute_cycles: Some(2), ..ServiceConfig::default() };
+        let mut transaction_service = TransactionService::with_config(acc_repo, tx_repo, config);
+        let request = |transaction_type: Operation, row: RowNumber| TransactionRequest {
+            transaction_type: Some(transaction_type),
+            transaction_id: Some(1),
+            client_id: Some(1),
+            amount: None,
+            case_id: None,
+            timestamp: None,
+            row: Some(row),
+            request_id: Some(row.to_string()),
+        };
+        transaction_service.process_transaction(TransactionRequest {
+            amount: Some(BigDecimal::from_str("1.1234").unwrap()),
+            ..request(Operation::Deposit, 2)
+        })?;
+        transaction_service.process_transaction(request(Operation::Dispute, 3))?;
+        transaction_service.process_transaction(request(Operation::Resolve, 4))?;
+        transaction_service.process_transaction(request(Operation::Dispute, 5))?;
+        transaction_service.process_transaction(request(Operation::Resolve, 6))?;
+
+        // The third cycle exceeds the configured maximum.
+        assert!(transaction_service.process_transaction(request(Operation::Dispute, 7)).is_err());
+
+        let history = transaction_service.transaction_repository.find_dispute_history(&1)?;
+        let summary: Vec<(u32, DisputeAction, Option<RowNumber>)> = history.iter()
+            .map(|entry| (entry.cycle(), entry.action().clone(), entry.row()))
+            .collect();
+        assert_eq!(vec![
+            (1, DisputeAction::Opened, Some(3)),
+            (1, DisputeAction::Resolved, Some(4)),
+            (2, DisputeAction::Opened, Some(5)),
+            (2, DisputeAction::Resolved, Some(6)),
+        ], summary);
+
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from_str("1.1234").unwrap(), account.available());
+        assert_eq!(BigDecimal::from_str("0.0000").unwrap(), account.held());
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_disputes() -> Result<(), Box<dyn std::error::Error>> {
+        let acc_repo = InMemAccountRepository::default();
+        let tx_repo = InMemTransactionRepository::default();
+        let mut transaction_service = TransactionService::new(acc_repo, tx_repo);
+        let request = |transaction_type: Operation, amount: Option<&str>| TransactionRequest {
+            transaction_type: Some(transaction_type),
+            transaction_id: Some(1),
+            client_id: Some(1),
+            amount: amount.map(|amount| BigDecimal::from_str(amount).unwrap()),
+            case_id: None,
+            timestamp: None,
+            row: None,
+            request_id: None,
+        };
+        let assert_balance = |service: &mut TransactionService<_, _>, available: &str, held: &str| {
+            let account = service.get_account_status(&1).unwrap();
+            assert_eq!(BigDecimal::from_str(available).unwrap(), account.available());
+            assert_eq!(BigDecimal::from_str(held).unwrap(), account.held());
+        };
+        transaction_service.process_transaction(request(Operation::Deposit, Some("10.0")))?;
+
+        // Two concurrent partial disputes, the second one cannot exceed the undisputed portion.
+        transaction_service.process_transaction(request(Operation::Dispute, Some("3.0")))?;
+        assert!(transaction_service.process_transaction(request(Operation::Dispute, Some("7.5"))).is_err());
+        transaction_service.process_transaction(request(Operation::Dispute, Some("5.0")))?;
+        assert_balance(&mut transaction_service, "2.0", "8.0");
+
+        // Settlements apply to the oldest open dispute and cannot exceed its held amount.
+        assert!(transaction_service.process_transaction(request(Operation::Resolve, Some("4.0"))).is_err());
+        transaction_service.process_transaction(request(Operation::Resolve, Some("1.0")))?;
+        assert_balance(&mut transaction_service, "3.0", "7.0");
+        transaction_service.process_transaction(request(Operation::Resolve, None))?;
+        assert_balance(&mut transaction_service, "5.0", "5.0");
+
+        let transaction = transaction_service.transaction_repository.find_transaction_by_id(&1)?.unwrap();
+        assert_eq!(Some(BigDecimal::from_str("5.0").unwrap()), transaction.disputable_amount());
+
+        // Partially charge back the remaining dispute.
+        transaction_service.process_transaction(request(Operation::Chargeback, Some("2.0")))?;
+        assert_balance(&mut transaction_service, "5.0", "3.0");
+        let transaction = transaction_service.transaction_repository.find_transaction_by_id(&1)?.unwrap();
+        assert_eq!(Some(BigDecimal::from_str("5.0").unwrap()), transaction.disputable_amount());
+        assert!(matches!(transaction.dispute, TransactionDispute::Disputed));
+        assert!(transaction_service.get_account_status(&1)?.is_locked());
+        Ok(())
+    }
+
+    #[test]
+    fn test_disputes_of_another_client() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        let of_client = |client_id: ClientId, request: TransactionRequest| TransactionRequest { client_id: Some(client_id), ..request };
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 0))?;
+        transaction_service.process_transaction(of_client(2, timed_request(Operation::Deposit, 2, "10", 0)))?;
+
+        let result = transaction_service.process_transaction(of_client(2, timed_request(Operation::Dispute, 1, "4", 0)));
+        assert!(matches!(result, Err(ServiceError::GenericErrorMsg(_))));
+        transaction_service.process_transaction(timed_request(Operation::Dispute, 1, "4", 0))?;
+        // The hold opened by the client of the transaction is settled by no other client.
+        for operation in [Operation::Resolve, Operation::Chargeback] {
+            let result = transaction_service.process_transaction(of_client(2, TransactionRequest {
+                amount: None,
+                ..timed_request(operation, 1, "0", 0)
+            }));
+            assert!(matches!(result, Err(ServiceError::GenericErrorMsg(_))));
+        }
+
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!((BigDecimal::from(6), BigDecimal::from(4)), (account.available(), account.held()));
+        let account = transaction_service.get_account_status(&2)?;
+        assert_eq!((BigDecimal::from(10), BigDecimal::from(0)), (account.available(), account.held()));
+        assert!(!account.is_locked());
+        let transaction = transaction_service.transaction_repository.find_transaction_by_id(&1)?.unwrap();
+        assert_eq!(1, transaction.disputes.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_cases() -> Result<(), Box<dyn std::error::Error>> {
+        let acc_repo = InMemAccountRepository::default();
+        let tx_repo = InMemTransactionRepository::default();
+        let mut transaction_service = TransactionService::new(acc_repo, tx_repo);
+        let request = |transaction_type: Operation, amount: Option<&str>, case_id: Option<CaseId>| TransactionRequest {
+            transaction_type: Some(transaction_type),
+            transaction_id: Some(1),
+            client_id: Some(1),
+            amount: amount.map(|amount| BigDecimal::from_str(amount).unwrap()),
+            case_id,
+            timestamp: None,
+            row: None,
+            request_id: None,
+        };
+        transaction_service.process_transaction(request(Operation::Deposit, Some("10.0"), None))?;
+        transaction_service.process_transaction(request(Operation::Dispute, Some("4.0"), Some(7)))?;
+        transaction_service.process_transaction(request(Operation::Dispute, Some("3.0"), Some(8)))?;
+
+        // A retried dispute row is detected by its case.
+        let result = transaction_service.process_transaction(request(Operation::Dispute, Some("1.0"), Some(7)));
+        assert!(matches!(result, Err(ServiceError::DataError(RepositoryError::EntityAlreadyExists(_)))));
+
+        // The case picks the settled dispute regardless of its age, and can only be settled once.
+        transaction_service.process_transaction(request(Operation::Resolve, None, Some(8)))?;
+        assert!(transaction_service.process_transaction(request(Operation::Resolve, None, Some(8))).is_err());
+        assert!(transaction_service.process_transaction(request(Operation::Resolve, None, Some(9))).is_err());
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from_str("6.0").unwrap(), account.available());
+        assert_eq!(BigDecimal::from_str("4.0").unwrap(), account.held());
+
+        transaction_service.process_transaction(request(Operation::Chargeback, None, Some(7)))?;
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from_str("0.0").unwrap(), account.held());
+        assert!(account.is_locked());
+
+        let case = transaction_service.transaction_repository.find_dispute_case(&7)?.unwrap();
+        assert!(matches!(case.state, DisputeCaseState::ChargedBack));
+        let case = transaction_service.transaction_repository.find_dispute_case(&8)?.unwrap();
+        assert!(matches!(case.state, DisputeCaseState::Resolved));
+        let history = transaction_service.transaction_repository.find_dispute_history(&1)?;
+        let cases: Vec<Option<CaseId>> = history.iter().map(|entry| entry.case_id()).collect();
+        assert_eq!(vec![Some(7), Some(8), Some(8), Some(7)], cases);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_is_idempotent() -> Result<(), Box<dyn std::error::Error>> {
+        let request = |transaction_type: Operation, amount: Option<&str>, row: RowNumber| TransactionRequest {
+            transaction_type: Some(transaction_type),
+            transaction_id: Some(1),
+            client_id: Some(1),
+            amount: amount.map(|amount| BigDecimal::from_str(amount).unwrap()),
+            case_id: None,
+            timestamp: None,
+            row: Some(row),
+            request_id: Some(format!("monday.csv:{}", row)),
+        };
+        let requests = vec![
+            request(Operation::Deposit, Some("10.0"), 2),
+            request(Operation::Dispute, None, 3),
+            request(Operation::Resolve, None, 4),
+            request(Operation::Dispute, None, 5),
+        ];
+
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        let summary = transaction_service.process_transactions(requests.clone().into_iter())?;
+        assert_eq!(4, summary.processed);
+        assert_eq!(0, summary.skipped_duplicates);
+
+        // A new run over the same state skips every request of the replayed input.
+        let (acc_repo, tx_repo) = transaction_service.into_repositories();
+        let mut transaction_service = TransactionService::new(acc_repo, tx_repo);
+        let summary = transaction_service.process_transactions(requests.clone().into_iter())?;
+        assert_eq!(0, summary.processed);
+        assert_eq!(4, summary.skipped_duplicates);
+
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from_str("0.0").unwrap(), account.available());
+        assert_eq!(BigDecimal::from_str("10.0").unwrap(), account.held());
+
+        // Same again after persisting the state between runs.
+        let (acc_repo, tx_repo) = transaction_service.into_repositories();
+        let json = serde_json::to_string(&InMemSnapshot::capture(&acc_repo, &tx_repo))?;
+        let snapshot: InMemSnapshot = serde_json::from_str(&json)?;
+        let (acc_repo, tx_repo) = snapshot.restore();
+        let mut transaction_service = TransactionService::new(acc_repo, tx_repo);
+        let summary = transaction_service.process_transactions(requests.into_iter())?;
+        assert_eq!(4, summary.skipped_duplicates);
+        assert_eq!(account, transaction_service.get_account_status(&1)?);
+        assert_eq!(3, transaction_service.transaction_repository.find_dispute_history(&1)?.len());
+
+        // Requests without an identifier are not deduplicated, the same content can open another
+        // dispute cycle.
+        let resolve = TransactionRequest::builder(Operation::Resolve, 1, 1).build();
+        let dispute = TransactionRequest::builder(Operation::Dispute, 1, 1).build();
+        assert!(matches!(transaction_service.process_transaction(resolve.clone())?.status, TransactionStatus::Applied));
+        assert!(matches!(transaction_service.process_transaction(dispute)?.status, TransactionStatus::Applied));
+        assert!(matches!(transaction_service.process_transaction(resolve)?.status, TransactionStatus::Applied));
+        assert_eq!(6, transaction_service.transaction_repository.find_dispute_history(&1)?.len());
+        Ok(())
+    }
+
+    fn timed_request(transaction_type: Operation, transaction_id: TransactionId, amount: &str, timestamp: Timestamp) -> TransactionRequest {
+        TransactionRequest {
+            transaction_type: Some(transaction_type),
+            transaction_id: Some(transaction_id),
+            client_id: Some(1),
+            amount: Some(BigDecimal::from_str(amount).unwrap()),
+            case_id: None,
+            timestamp: Some(timestamp),
+            row: None,
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn test_rules_reject_withdrawals() -> Result<(), Box<dyn std::error::Error>> {
+        let rules: RulesConfig = serde_json::from_str(r#"{
+            "max_withdrawal_amount": "50",
+            "max_withdrawals_per_transactions": { "withdrawals": 2, "transactions": 3 },
+            "max_withdrawals_per_window": { "withdrawals": 2, "seconds": 60 }
+        }"#)?;
+        let config = ServiceConfig { rules, ..ServiceConfig::default() };
+        let mut transaction_service = TransactionService::with_config(InMemAccountRepository::default(), InMemTransactionRepository::default(), config);
+        let is_rejected = |status: TransactionStatus, expected: &str| match status {
+            TransactionStatus::Rejected(RejectionReason::RuleViolation(rule)) => rule == expected,
+            _ => false,
+        };
+
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "500", 0))?;
+        let status = transaction_service.process_transaction(timed_request(Operation::Withdrawal, 2, "60", 1))?.status;
+        assert!(is_rejected(status, "max_withdrawal_amount"));
+
+        // Two withdrawals among the last three transactions.
+        transaction_service.process_transaction(timed_request(Operation::Withdrawal, 3, "10", 100))?;
+        transaction_service.process_transaction(timed_request(Operation::Withdrawal, 4, "10", 200))?;
+        let status = transaction_service.process_transaction(timed_request(Operation::Withdrawal, 5, "10", 300))?.status;
+        assert!(is_rejected(status, "max_withdrawals_per_transactions"));
+
+        // Two withdrawals within a minute.
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 6, "10", 400))?;
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 7, "10", 401))?;
+        transaction_service.process_transaction(timed_request(Operation::Withdrawal, 8, "10", 402))?;
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 9, "10", 403))?;
+        transaction_service.process_transaction(timed_request(Operation::Withdrawal, 10, "10", 404))?;
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 11, "10", 405))?;
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 12, "10", 406))?;
+        let status = transaction_service.process_transaction(timed_request(Operation::Withdrawal, 13, "10", 407))?.status;
+        assert!(is_rejected(status, "max_withdrawals_per_window"));
+        let status = transaction_service.process_transaction(timed_request(Operation::Withdrawal, 14, "10", 470))?.status;
+        assert!(matches!(status, TransactionStatus::Applied));
+
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from_str("500").unwrap(), account.available());
+        assert!(!account.is_locked());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rules_daily_volume_and_lock() -> Result<(), Box<dyn std::error::Error>> {
+        let rules: RulesConfig = serde_json::from_str(r#"{ "max_daily_volume": "100", "lock_on_violation": true }"#)?;
+        let config = ServiceConfig { rules, ..ServiceConfig::default() };
+        let mut transaction_service = TransactionService::with_config(InMemAccountRepository::default(), InMemTransactionRepository::default(), config);
+        let day: Timestamp = 24 * 60 * 60;
+
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "80", day))?;
+        transaction_service.process_transaction(timed_request(Operation::Withdrawal, 2, "20", day + 10))?;
+        // The volume is counted per day.
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 3, "90", 2 * day))?;
+        let status = transaction_service.process_transaction(timed_request(Operation::Deposit, 4, "20", 2 * day + 10))?.status;
+        assert!(matches!(status, TransactionStatus::Rejected(_)));
+
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from_str("150").unwrap(), account.available());
+        assert!(account.is_locked());
+        let transaction = transaction_service.transaction_repository.find_transaction_by_id(&4)?.unwrap();
+        assert!(matches!(transaction.status, TransactionStatus::Rejected(RejectionReason::RuleViolation(_))));
+        Ok(())
+    }
+
+    /// Vetoes every operation of a client, and notes the balance after every operation.
+    struct BlockClient(ClientId);
+
+    impl TransactionHook for BlockClient {
+        fn name(&self) -> &str { "block_client" }
+
+        fn before(&mut self, transaction: &Transaction, _account: &Account) -> HookVerdict {
+            if transaction.client_id() == self.0 { HookVerdict::Veto("blocked".to_string()) } else { HookVerdict::Allow }
+        }
+
+        fn after(&mut self, _transaction: &Transaction, account: &Account, _status: &TransactionStatus) -> Vec<String> {
+            vec![format!("balance {}", account.total())]
+        }
+    }
+
+    #[test]
+    fn test_hooks() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        transaction_service.register_hook(Box::new(LargeDepositThenWithdraw::new(BigDecimal::from(100), 2, false)));
+        transaction_service.register_hook(Box::new(RapidDisputes::new(1, 5, true)));
+        transaction_service.register_hook(Box::new(BlockClient(2)));
+
+        let outcome = transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "150", 0))?;
+        assert_eq!(vec!["block_client: balance 150".to_string()], outcome.annotations);
+        let outcome = transaction_service.process_transaction(timed_request(Operation::Withdrawal, 2, "140", 0))?;
+        assert!(matches!(outcome.status, TransactionStatus::Applied));
+        assert_eq!("large_deposit_then_withdraw: withdrawal follows the large deposit 1", outcome.annotations[0]);
+
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 3, "10", 0))?;
+        let mut dispute = timed_request(Operation::Dispute, 3, "1", 0);
+        transaction_service.process_transaction(dispute.clone())?;
+        dispute.amount = Some(BigDecimal::from(2));
+        let outcome = transaction_service.process_transaction(dispute)?;
+        assert!(matches!(outcome.status, TransactionStatus::Rejected(RejectionReason::HookVeto(_))));
+        // The veto of a dispute leaves the status of the disputed deposit alone.
+        assert!(matches!(transaction_service.find_transaction(&3)?.map(|transaction| transaction.status), Some(TransactionStatus::Applied)));
+
+        let outcome = transaction_service.process_transaction(TransactionRequest {
+            client_id: Some(2),
+            ..timed_request(Operation::Deposit, 4, "10", 0)
+        })?;
+        assert!(matches!(outcome.status, TransactionStatus::Rejected(RejectionReason::HookVeto(_))));
+
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from_str("19").unwrap(), account.available());
+        assert_eq!(BigDecimal::from_str("1").unwrap(), account.held());
+        // The vetoed deposit did not open an account.
+        assert!(matches!(transaction_service.get_account_status(&2), Err(ServiceError::DataError(RepositoryError::EntityNotFound(_)))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rapid_disputes_count_applied_disputes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        transaction_service.register_hook(Box::new(RapidDisputes::new(1, 5, true)));
+
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 0))?;
+        // Disputes of unknown transactions fail and are not counted.
+        for transaction_id in [7, 8] {
+            assert!(transaction_service.process_transaction(timed_request(Operation::Dispute, transaction_id, "1", 0)).is_err());
+        }
+        let outcome = transaction_service.process_transaction(timed_request(Operation::Dispute, 1, "1", 0))?;
+        assert!(matches!(outcome.status, TransactionStatus::Applied));
+        transaction_service.process_transaction(timed_request(Operation::Resolve, 1, "1", 0))?;
+        let outcome = transaction_service.process_transaction(timed_request(Operation::Dispute, 1, "1", 0))?;
+        assert!(matches!(outcome.status, TransactionStatus::Rejected(RejectionReason::HookVeto(_))));
+        Ok(())
+    }
+
+    /// Collects the published events in a vector shared with the test.
+    struct EventRecorder(Rc<RefCell<Vec<OutboxEntry>>>);
+
+    impl EventSubscriber for EventRecorder {
+        fn on_event(&mut self, entry: &OutboxEntry) -> Result<(), io::Error> {
+            self.0.borrow_mut().push(entry.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+
+        // Events raised before a subscriber registers are not recorded.
+        let deposit = TransactionRequest::builder(Operation::Deposit, 2, 9).amount(BigDecimal::from(1)).build();
+        assert_eq!(1, transaction_service.process_transaction(deposit)?.events.len());
+        assert!(transaction_service.transaction_repository.unpublished_events(0)?.is_empty());
+        let published = Rc::new(RefCell::new(Vec::new()));
+        transaction_service.register_subscriber(Box::new(EventRecorder(published.clone())));
+        assert_eq!(0, transaction_service.publish_events()?);
+
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 0))?;
+        transaction_service.process_transaction(timed_request(Operation::Withdrawal, 2, "20", 0))?;
+        transaction_service.process_transaction(timed_request(Operation::Withdrawal, 3, "4", 0))?;
+        transaction_service.process_transaction(timed_request(Operation::Dispute, 1, "5", 0))?;
+        transaction_service.process_transaction(TransactionRequest {
+            amount: None,
+            ..timed_request(Operation::Chargeback, 1, "0", 0)
+        })?;
+
+        let published = published.borrow();
+        let sequences: Vec<u64> = published.iter().map(|entry| entry.sequence()).collect();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], sequences);
+        let events: Vec<&DomainEvent> = published.iter().map(|entry| entry.event()).collect();
+        assert_eq!(&DomainEvent::Deposited { client_id: 1, transaction_id: 1, amount: BigDecimal::from(10) }, events[0]);
+        assert!(matches!(events[1], DomainEvent::Rejected { transaction_id: 2, .. }));
+        assert_eq!(&DomainEvent::Withdrawn { client_id: 1, transaction_id: 3, amount: BigDecimal::from(4) }, events[2]);
+        assert!(matches!(events[3], DomainEvent::DisputeOpened { transaction_id: 1, .. }));
+        assert!(matches!(events[4], DomainEvent::ChargedBack { transaction_id: 1, .. }));
+        assert_eq!(&DomainEvent::AccountLocked { client_id: 1, transaction_id: 1 }, events[5]);
+        assert!(transaction_service.transaction_repository.unpublished_events(0)?.is_empty());
+        Ok(())
+    }
+
+    /// Records the published events, failing to while the shared flag is set.
+    struct UnreliableRecorder {
+        failing: Rc<Cell<bool>>,
+        handled: Rc<RefCell<Vec<OutboxEntry>>>,
+    }
+
+    impl EventSubscriber for UnreliableRecorder {
+        fn on_event(&mut self, entry: &OutboxEntry) -> Result<(), io::Error> {
+            if self.failing.get() {
+                return Err(io::Error::other("unavailable"));
+            }
+            self.handled.borrow_mut().push(entry.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events_wait_for_every_subscriber() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        let published = Rc::new(RefCell::new(Vec::new()));
+        let failing = Rc::new(Cell::new(true));
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        transaction_service.register_subscriber(Box::new(EventRecorder(published.clone())));
+        transaction_service.register_subscriber(Box::new(UnreliableRecorder { failing: failing.clone(), handled: handled.clone() }));
+
+        // The requests are processed while a subscriber fails, their events stay in the outbox.
+        assert!(matches!(transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 0))?.status, TransactionStatus::Applied));
+        assert!(matches!(transaction_service.process_transaction(timed_request(Operation::Deposit, 2, "10", 0))?.status, TransactionStatus::Applied));
+        assert!(matches!(transaction_service.publish_events(), Err(ServiceError::IOError(_))));
+        assert_eq!(2, transaction_service.transaction_repository.unpublished_events(0)?.len());
+
+        // Once it recovers it gets every event, the other subscriber does not get them again.
+        failing.set(false);
+        assert_eq!(2, transaction_service.publish_events()?);
+        assert_eq!(vec![1, 2], handled.borrow().iter().map(|entry| entry.sequence()).collect::<Vec<_>>());
+        assert_eq!(vec![1, 2], published.borrow().iter().map(|entry| entry.sequence()).collect::<Vec<_>>());
+        assert!(transaction_service.transaction_repository.unpublished_events(0)?.is_empty());
+        Ok(())
+    }
+
+    /// Subscriber handing the events over to be handled in the background, up to `handled`.
+    struct BackgroundRecorder {
+        handled: Rc<Cell<u64>>,
+    }
+
+    impl EventSubscriber for BackgroundRecorder {
+        fn on_event(&mut self, _entry: &OutboxEntry) -> Result<(), io::Error> {
+            Ok(())
+        }
+
+        fn handled(&self) -> Option<u64> {
+            Some(self.handled.get())
+        }
+    }
+
+    #[test]
+    fn test_events_wait_until_handled_in_the_background() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        let handled = Rc::new(Cell::new(0));
+        transaction_service.register_subscriber(Box::new(BackgroundRecorder { handled: handled.clone() }));
+
+        // The events handed over stay in the outbox until they are handled.
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 0))?;
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 2, "10", 0))?;
+        assert_eq!(2, transaction_service.transaction_repository.unpublished_events(0)?.len());
+
+        // Nothing is relayed again while they are handled.
+        handled.set(1);
+        assert_eq!(0, transaction_service.flush_events()?);
+        assert_eq!(vec![2], transaction_service.transaction_repository.unpublished_events(0)?.iter().map(|entry| entry.sequence()).collect::<Vec<_>>());
+        handled.set(2);
+        assert_eq!(0, transaction_service.flush_events()?);
+        assert!(transaction_service.transaction_repository.unpublished_events(0)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_log_skips_redeliveries() -> Result<(), Box<dyn std::error::Error>> {
+        let filename = std::env::temp_dir().join(format!("rails-events-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&filename);
+        let entry = |sequence: u64| OutboxEntry::new(sequence, DomainEvent::AccountLocked { client_id: 1, transaction_id: sequence });
+        let mut log = EventLogWriter::from(&filename)?;
+        log.on_event(&entry(1))?;
+        log.on_event(&entry(2))?;
+        drop(log);
+
+        // After a restart the events not marked published yet are relayed again.
+        let mut log = EventLogWriter::from(&filename)?;
+        (1..=3).try_for_each(|sequence| log.on_event(&entry(sequence)))?;
+        drop(log);
+        let content = std::fs::read_to_string(&filename)?;
+        std::fs::remove_file(&filename)?;
+        let sequences = content.lines()
+            .map(|line| serde_json::from_str::<OutboxEntry>(line).map(|entry| entry.sequence()))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(vec![1, 2, 3], sequences);
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_at() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 100))?;
+        transaction_service.process_transaction(timed_request(Operation::Withdrawal, 2, "4", 200))?;
+        transaction_service.process_transaction(timed_request(Operation::Dispute, 1, "5", 300))?;
+        transaction_service.process_transaction(TransactionRequest {
+            timestamp: None,
+            ..timed_request(Operation::Deposit, 3, "1", 0)
+        })?;
+
+        let after = |service: &mut TransactionService<_, _>, point| service.get_account_at(&1, &point).map(|account| {
+            account.map(|account: Account| (account.available(), account.held()))
+        });
+        let balance = |available: &str, held: &str| Some((BigDecimal::from_str(available).unwrap(), BigDecimal::from_str(held).unwrap()));
+        assert_eq!(balance("10.0000", "0"), after(&mut transaction_service, AccountPoint::AfterTransaction(1))?);
+        assert_eq!(balance("6.0000", "0"), after(&mut transaction_service, AccountPoint::AfterTransaction(2))?);
+        assert_eq!(None, after(&mut transaction_service, AccountPoint::AfterTransaction(4))?);
+        assert_eq!(None, after(&mut transaction_service, AccountPoint::At(99))?);
+        assert_eq!(balance("6.0000", "0"), after(&mut transaction_service, AccountPoint::At(299))?);
+        // The untimestamped deposit follows the dispute in the input, it is placed along with it.
+        assert_eq!(balance("2.0000", "5.0000"), after(&mut transaction_service, AccountPoint::At(300))?);
+        Ok(())
+    }
+    #[test]
+    fn test_find_transactions() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        for transaction_id in 1..=5 {
+            transaction_service.process_transaction(timed_request(Operation::Deposit, transaction_id, "10", 0))?;
+        }
+        transaction_service.process_transaction(TransactionRequest {
+            client_id: Some(2),
+            ..timed_request(Operation::Withdrawal, 6, "1", 0)
+        })?;
+        transaction_service.process_transaction(timed_request(Operation::Dispute, 2, "10", 0))?;
+        transaction_service.process_transaction(timed_request(Operation::Dispute, 4, "10", 0))?;
+
+        let ids = |page: TransactionPage| page.transactions.iter().map(|transaction| transaction.transaction_id()).collect::<Vec<_>>();
+        let page = transaction_service.find_transactions(&TransactionQuery::default())?;
+        assert_eq!(6, page.total);
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], ids(page));
+
+        let page = transaction_service.find_transactions(&TransactionQuery {
+            status: Some(StatusFilter::Error),
+            ..TransactionQuery::default()
+        })?;
+        assert_eq!(vec![6], ids(page));
+
+        let page = transaction_service.find_transactions(&TransactionQuery {
+            client_id: Some(1),
+            dispute: Some(TransactionDispute::Disputed),
+            ..TransactionQuery::default()
+        })?;
+        assert_eq!(vec![2, 4], ids(page));
+
+        let page = transaction_service.find_transactions(&TransactionQuery {
+            client_id: Some(1),
+            from_transaction_id: Some(2),
+            to_transaction_id: Some(5),
+            offset: 1,
+            limit: Some(2),
+            ..TransactionQuery::default()
+        })?;
+        assert_eq!(4, page.total);
+        assert_eq!(vec![3, 4], ids(page));
+        Ok(())
+    }
+    #[test]
+    fn test_open_disputes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 100))?;
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 2, "10", 100))?;
+        transaction_service.process_transaction(TransactionRequest {
+            row: Some(4),
+            request_id: None,
+            ..timed_request(Operation::Dispute, 1, "4", 150)
+        })?;
+        transaction_service.process_transaction(timed_request(Operation::Dispute, 1, "3", 160))?;
+        transaction_service.process_transaction(timed_request(Operation::Dispute, 2, "5", 170))?;
+        transaction_service.process_transaction(TransactionRequest {
+            amount: None,
+            ..timed_request(Operation::Resolve, 2, "0", 180)
+        })?;
+
+        let report = transaction_service.open_disputes(200)?;
+        let disputes: Vec<(TransactionId, Amount, Option<RowNumber>, Timestamp)> = report.disputes.iter()
+            .map(|dispute| (dispute.transaction_id, dispute.amount.clone(), dispute.row, dispute.age))
+            .collect();
+        assert_eq!(vec![(1, BigDecimal::from(4), Some(4), 50), (1, BigDecimal::from(3), None, 40)], disputes);
+        assert_eq!(1, report.clients.len());
+        assert_eq!(BigDecimal::from(7), report.clients[0].disputed);
+        assert!(report.clients[0].is_consistent());
+
+        // Held funds that no open dispute accounts for are reported.
+        let account = transaction_service.get_account_status(&1)?;
+        let update = Account::from_parts(1, account.available(), BigDecimal::from(8), false, account.last_tx_applied(), account.version() + 1);
+        transaction_service.account_repository.update_account(&account, &update)?;
+        let report = transaction_service.open_disputes(200)?;
+        assert!(!report.clients[0].is_consistent());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_disputes_of_another_client() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        let of_client = |client_id: ClientId, request: TransactionRequest| TransactionRequest { client_id: Some(client_id), ..request };
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 100))?;
+        transaction_service.process_transaction(of_client(2, timed_request(Operation::Deposit, 2, "10", 100)))?;
+        transaction_service.process_transaction(timed_request(Operation::Dispute, 1, "4", 150))?;
+        assert!(transaction_service.process_transaction(of_client(2, timed_request(Operation::Dispute, 1, "3", 160))).is_err());
+
+        let report = transaction_service.open_disputes(200)?;
+        assert_eq!(1, report.disputes.len());
+        let clients: Vec<(ClientId, Amount, Amount)> = report.clients.iter()
+            .map(|client| (client.client_id, client.disputed.clone(), client.held.clone()))
+            .collect();
+        assert_eq!(vec![(1, BigDecimal::from(4), BigDecimal::from(4))], clients);
+        assert!(report.clients.iter().all(|client| client.is_consistent()));
+        Ok(())
+    }
+
+    /// Account repository where another writer updates the account before each of the first
+    /// updates, and that can fail to record the account versions.
+    struct FaultyAccountRepo {
+        inner: InMemAccountRepository,
+        conflicts: u32,
+        fail_versions: bool,
+    }
+
+    impl UnitOfWork for FaultyAccountRepo {
+        fn begin(&mut self) -> Result<(), RepositoryError> {
+            self.inner.begin()
+        }
+
+        fn commit(&mut self) -> Result<(), RepositoryError> {
+            self.inner.commit()
+        }
+
+        fn rollback(&mut self) -> Result<(), RepositoryError> {
+            self.inner.rollback()
+        }
+    }
+
+    impl AccountRepository for FaultyAccountRepo {
+        fn find_account(&mut self, client_id: &ClientId) -> Result<Option<Account>, RepositoryError> {
+            self.inner.find_account(client_id)
+        }
+
+        fn open_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError> {
+            self.inner.open_account(client_id)
+        }
+
+        fn update_account(&mut self, account: &Account, update: &Account) -> Result<(), RepositoryError> {
+            if self.conflicts > 0 {
+                self.conflicts -= 1;
+                let mut concurrent = account.clone();
+                concurrent.available = concurrent.available.add(BigDecimal::from(1));
+                concurrent.version += 1;
+                self.inner.update_account(account, &concurrent)?;
+            }
+            self.inner.update_account(account, update)
+        }
+
+        fn account_visitor<F>(&mut self, f: F) -> Result<(), RepositoryError> where F: FnMut(&Account) {
+            self.inner.account_visitor(f)
+        }
+
+        fn append_account_version(&mut self, version: &AccountVersion) -> Result<(), RepositoryError> {
+            if self.fail_versions {
+                return Err(RepositoryError::EntityNotFound(format!("history of {}", version.account().client_id())));
+            }
+            self.inner.append_account_version(version)
+        }
+
+        fn find_account_versions(&mut self, client_id: &ClientId) -> Result<Vec<AccountVersion>, RepositoryError> {
+            self.inner.find_account_versions(client_id)
+        }
+    }
+
+    #[test]
+    fn test_retry_on_conflict() -> Result<(), Box<dyn std::error::Error>> {
+        let account_repository = FaultyAccountRepo { inner: InMemAccountRepository::default(), conflicts: 2, fail_versions: false };
+        let mut transaction_service = TransactionService::new(account_repository, InMemTransactionRepository::default());
+        let outcome = transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 0))?;
+        assert!(matches!(outcome.status, TransactionStatus::Applied));
+        // Both concurrent deposits of 1 are kept, the deposit is applied on top of them.
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from(12), account.available());
+        assert_eq!(3, account.version());
+        assert_eq!(1, outcome.events.iter().filter(|event| matches!(event, DomainEvent::Deposited { .. })).count());
+
+        // Past the retries the conflict is reported and nothing of the request is kept.
+        transaction_service.account_repository.conflicts = DEFAULT_CONFLICT_RETRIES + 1;
+        assert!(transaction_service.process_transaction(timed_request(Operation::Deposit, 2, "10", 0)).is_err());
+        assert_eq!(BigDecimal::from(12), transaction_service.get_account_status(&1)?.available());
+        assert!(transaction_service.find_transaction(&2)?.is_none());
+        Ok(())
+    }
+
+    /// Account repository shared by writers that update the accounts outside units of work, so
+    /// that their updates interleave and conflict.
+    #[derive(Clone)]
+    struct UnitlessAccountRepo {
+        inner: Arc<Mutex<InMemAccountRepository>>,
+        conflicts: Arc<AtomicU32>,
+    }
+
+    impl UnitOfWork for UnitlessAccountRepo {
+        fn begin(&mut self) -> Result<(), RepositoryError> {
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<(), RepositoryError> {
+            Ok(())
+        }
+
+        fn rollback(&mut self) -> Result<(), RepositoryError> {
+            Ok(())
+        }
+    }
+
+    impl AccountRepository for UnitlessAccountRepo {
+        fn find_account(&mut self, client_id: &ClientId) -> Result<Option<Account>, RepositoryError> {
+            let account = self.inner.lock().unwrap().find_account(client_id);
+            // Let another writer update the account in between.
+            thread::yield_now();
+            account
+        }
+
+        fn open_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError> {
+            self.inner.lock().unwrap().open_account(client_id)
+        }
+
+        fn update_account(&mut self, account: &Account, update: &Account) -> Result<(), RepositoryError> {
+            let result = self.inner.lock().unwrap().update_account(account, update);
+            if let Err(RepositoryError::InconsistencyDetected(_)) = result {
+                self.conflicts.fetch_add(1, Ordering::Relaxed);
+            }
+            result
+        }
+
+        fn account_visitor<F>(&mut self, f: F) -> Result<(), RepositoryError> where F: FnMut(&Account) {
+            self.inner.lock().unwrap().account_visitor(f)
+        }
+
+        fn append_account_version(&mut self, version: &AccountVersion) -> Result<(), RepositoryError> {
+            self.inner.lock().unwrap().append_account_version(version)
+        }
+
+        fn find_account_versions(&mut self, client_id: &ClientId) -> Result<Vec<AccountVersion>, RepositoryError> {
+            self.inner.lock().unwrap().find_account_versions(client_id)
+        }
+    }
+
+    #[test]
+    fn test_concurrent_conflicts_are_retried() -> Result<(), Box<dyn std::error::Error>> {
+        const THREADS: u64 = 8;
+        const DEPOSITS: u64 = 50;
+        const MAX_VOLUME: u64 = 100;
+        let mut accounts = UnitlessAccountRepo { inner: Arc::new(Mutex::new(InMemAccountRepository::default())), conflicts: Arc::new(AtomicU32::new(0)) };
+        accounts.open_account(&1)?;
+        let transactions = ShardedTransactionRepository::new();
+        let rules: RulesConfig = serde_json::from_str(&format!(r#"{{ "max_daily_volume": "{}" }}"#, MAX_VOLUME))?;
+        let config = ServiceConfig { rules, conflict_retries: u32::MAX, ..ServiceConfig::default() };
+
+        let writers: Vec<_> = (0..THREADS).map(|thread| {
+            let (accounts, transactions, config) = (accounts.clone(), transactions.clone(), config.clone());
+            thread::spawn(move || {
+                let mut service = TransactionService::with_config(accounts, transactions, config);
+                (0..DEPOSITS).filter(|deposit| {
+                    let request = timed_request(Operation::Deposit, thread * DEPOSITS + deposit + 1, "1", 0);
+                    matches!(service.process_transaction(request).unwrap().status, TransactionStatus::Applied)
+                }).count()
+            })
+        }).collect();
+        let applied: usize = writers.into_iter().map(|writer| writer.join().unwrap()).sum();
+
+        // Every conflict was retried on a fresh read, the rules included: no deposit was lost, and
+        // none passed the daily volume on the activity of a stale read.
+        assert!(accounts.conflicts.load(Ordering::Relaxed) > 0);
+        assert_eq!(MAX_VOLUME as usize, applied);
+        let account = accounts.find_account(&1)?.unwrap();
+        assert_eq!(BigDecimal::from(MAX_VOLUME), account.available());
+        assert_eq!(MAX_VOLUME, account.version());
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_deposits_open_accounts() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 0))?;
+
+        // Rows of clients without an account leave no account behind.
+        let requests = vec![
+            TransactionRequest::builder(Operation::Withdrawal, 2, 2).amount(BigDecimal::from(1)).build(),
+            TransactionRequest::builder(Operation::Dispute, 3, 1).build(),
+            TransactionRequest::builder(Operation::Resolve, 4, 1).build(),
+            TransactionRequest::builder(Operation::Chargeback, 5, 1).build(),
+        ];
+        transaction_service.process_transactions(requests.into_iter())?;
+        let clients: Vec<ClientId> = transaction_service.accounts()?.iter().map(|account| account.client_id()).collect();
+        assert_eq!(vec![1], clients);
+        assert!(transaction_service.get_account_status(&3).is_err());
+
+        transaction_service.process_transaction(TransactionRequest::builder(Operation::Deposit, 2, 3).amount(BigDecimal::from(1)).build())?;
+        assert_eq!(BigDecimal::from(1), transaction_service.get_account_status(&2)?.available());
+        assert_eq!(2, transaction_service.accounts()?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unit_of_work() -> Result<(), Box<dyn std::error::Error>> {
+        let account_repository = FaultyAccountRepo { inner: InMemAccountRepository::default(), conflicts: 0, fail_versions: false };
+        let mut transaction_service = TransactionService::new(account_repository, InMemTransactionRepository::default());
+        transaction_service.register_subscriber(Box::new(BackgroundRecorder { handled: Rc::new(Cell::new(0)) }));
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", 0))?;
+
+        // The dispute fails once the account is updated, every write of the request is undone.
+        transaction_service.account_repository.fail_versions = true;
+        let dispute = TransactionRequest::builder(Operation::Dispute, 1, 1).build();
+        assert!(matches!(transaction_service.process_transaction(dispute.clone()), Err(ServiceError::DataError(_))));
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from(10), account.available());
+        assert_eq!(BigDecimal::zero(), account.held());
+        assert!(matches!(transaction_service.find_transaction(&1)?.map(|transaction| transaction.dispute), Some(TransactionDispute::No)));
+        assert!(transaction_service.dispute_history(&1)?.is_empty());
+        assert_eq!(1, transaction_service.transaction_repository.unpublished_events(0)?.len());
+
+        // A rejected request is kept along with the rejection.
+        transaction_service.account_repository.fail_versions = false;
+        let withdrawal = timed_request(Operation::Withdrawal, 2, "20", 0);
+        assert!(matches!(transaction_service.process_transaction(withdrawal)?.status, TransactionStatus::Error));
+        assert!(matches!(transaction_service.find_transaction(&2)?.map(|transaction| transaction.status), Some(TransactionStatus::Error)));
+        assert_eq!(2, transaction_service.transaction_repository.unpublished_events(0)?.len());
+
+        // The failed dispute was not recorded as processed.
+        assert!(matches!(transaction_service.process_transaction(dispute)?.status, TransactionStatus::Applied));
+        assert_eq!(BigDecimal::from(10), transaction_service.get_account_status(&1)?.held());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rules_activity_follows_the_account() -> Result<(), Box<dyn std::error::Error>> {
+        let rules: RulesConfig = serde_json::from_str(r#"{ "max_daily_volume": "100" }"#)?;
+        let config = ServiceConfig { rules, ..ServiceConfig::default() };
+        let account_repository = FaultyAccountRepo { inner: InMemAccountRepository::default(), conflicts: 0, fail_versions: false };
+        let mut transaction_service = TransactionService::with_config(account_repository, InMemTransactionRepository::default(), config.clone());
+        let day: Timestamp = 24 * 60 * 60;
+
+        // The volume of a request rolled back is not counted.
+        transaction_service.process_transaction(timed_request(Operation::Deposit, 1, "10", day))?;
+        transaction_service.account_repository.fail_versions = true;
+        assert!(transaction_service.process_transaction(timed_request(Operation::Deposit, 2, "80", day + 1)).is_err());
+        transaction_service.account_repository.fail_versions = false;
+        let status = transaction_service.process_transaction(timed_request(Operation::Deposit, 3, "80", day + 2))?.status;
+        assert!(matches!(status, TransactionStatus::Applied));
+
+        // The volume is kept along with the state, for the next run.
+        let (account_repository, transaction_repository) = transaction_service.into_repositories();
+        let json = serde_json::to_string(&InMemSnapshot::capture(&account_repository.inner, &transaction_repository))?;
+        let (account_repository, transaction_repository) = serde_json::from_str::<InMemSnapshot>(&json)?.restore();
+        let mut transaction_service = TransactionService::with_config(account_repository, transaction_repository, config);
+        let status = transaction_service.process_transaction(timed_request(Operation::Deposit, 4, "20", day + 3))?.status;
+        assert!(matches!(status, TransactionStatus::Rejected(RejectionReason::RuleViolation(_))));
+
+        // Transactions without a time are not counted in any day.
+        let untimed = TransactionRequest::builder(Operation::Deposit, 1, 5).amount(BigDecimal::from(500)).build();
+        assert!(matches!(transaction_service.process_transaction(untimed)?.status, TransactionStatus::Applied));
+        assert_eq!(BigDecimal::from(590), transaction_service.get_account_status(&1)?.available());
+        Ok(())
+    }
+
+    #[test]
+    fn test_processing_summary() -> Result<(), Box<dyn std::error::Error>> {
+        let rules: RulesConfig = serde_json::from_str(r#"{ "max_withdrawal_amount": "5" }"#)?;
+        let config = ServiceConfig { rules, ..ServiceConfig::default() };
+        let mut transaction_service = TransactionService::with_config(InMemAccountRepository::default(), InMemTransactionRepository::default(), config);
+        let requests = vec![
+            timed_request(Operation::Deposit, 1, "10", 0),
+            timed_request(Operation::Deposit, 1, "10", 0),
+            timed_request(Operation::Withdrawal, 2, "6", 0),
+            timed_request(Operation::Withdrawal, 3, "2", 0),
+            timed_request(Operation::Deposit, 4, "3", 0),
+            timed_request(Operation::Dispute, 4, "3", 0),
+            timed_request(Operation::Chargeback, 4, "3", 0),
+        ];
+        let summary = transaction_service.process_transactions(requests.into_iter())?;
+        assert_eq!(6, summary.processed);
+        assert_eq!(5, summary.applied);
+        assert_eq!(1, summary.rejected);
+        assert_eq!(Some(&1), summary.rejected_by_cause.get("max_withdrawal_amount"));
+        assert_eq!(1, summary.skipped_duplicates);
+        assert_eq!(Some(&3), summary.by_operation.get(&Operation::Deposit));
+        assert_eq!(Some(&2), summary.by_operation.get(&Operation::Withdrawal));
+        assert_eq!(BigDecimal::from(13), summary.deposited);
+        assert_eq!(BigDecimal::from(2), summary.withdrawn);
+        assert_eq!(BigDecimal::from(3), summary.held);
+        assert_eq!(BigDecimal::from(3), summary.charged_back);
+        assert_eq!(1, summary.locked_accounts);
+        Ok(())
+    }
 }
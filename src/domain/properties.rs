@@ -0,0 +1,351 @@
+//! Property tests of the service. Random streams of valid and invalid requests are applied to the
+//! service and to a reference model of the balances, written from the rules rather than from the
+//! service, and both must agree after every request.
+
+use std::collections::HashMap;
+use bigdecimal::{BigDecimal, ToPrimitive};
+use bigdecimal::num_bigint::BigInt;
+use proptest::prelude::*;
+use crate::domain::*;
+use crate::repository::{InMemAccountRepository, InMemTransactionRepository, ShardedAccountRepository, ShardedTransactionRepository};
+
+/// Amounts of the model, in units of the last decimal position.
+type Units = i64;
+
+fn units(amount: &Amount) -> Units {
+    amount.with_scale(ROUND_DIGITS).as_bigint_and_exponent().0.to_i64().expect("amount out of the model range")
+}
+
+fn amount(units: Units) -> Amount {
+    BigDecimal::new(BigInt::from(units), ROUND_DIGITS)
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+struct ModelAccount {
+    available: Units,
+    held: Units,
+    locked: bool,
+}
+
+impl From<&Account> for ModelAccount {
+    fn from(account: &Account) -> Self {
+        ModelAccount { available: units(&account.available()), held: units(&account.held()), locked: account.is_locked() }
+    }
+}
+
+#[derive(Debug)]
+struct ModelHold {
+    held: Units,
+    case_id: Option<CaseId>,
+}
+
+#[derive(Debug)]
+struct ModelTransaction {
+    client_id: ClientId,
+    amount: Units,
+    charged_back: Units,
+    holds: Vec<ModelHold>,
+    dispute: TransactionDispute,
+}
+
+#[derive(Debug)]
+struct ModelCase {
+    transaction_id: TransactionId,
+    client_id: ClientId,
+    open: bool,
+}
+
+/// The expected state of the accounts. Requests the service refuses leave the model untouched,
+/// except for the deposits and withdrawals it keeps along with the refusal.
+#[derive(Debug, Default)]
+struct Model {
+    accounts: HashMap<ClientId, ModelAccount>,
+    transactions: HashMap<TransactionId, ModelTransaction>,
+    cases: HashMap<CaseId, ModelCase>,
+}
+
+impl Model {
+    fn apply(&mut self, request: &TransactionRequest) {
+        let requested = match request.amount() {
+            // Amounts with more decimal positions than supported are invalid.
+            Some(requested) if requested.round(ROUND_DIGITS) != requested => return,
+            requested => requested.map(|requested| units(&requested)),
+        };
+        // Requests missing their type, client or transaction id are invalid.
+        let (Some(&operation), Some(client_id), Some(transaction_id)) = (request.transaction_type(), request.client_id(), request.transaction_id()) else {
+            return;
+        };
+        match operation {
+            Operation::Deposit | Operation::Withdrawal => {
+                // Requests without an amount are invalid.
+                let requested = match requested {
+                    Some(requested) if !self.transactions.contains_key(&transaction_id) => requested,
+                    _ => return,
+                };
+                let kept = match operation {
+                    Operation::Deposit => self.deposit(client_id, requested),
+                    _ => self.withdraw(client_id, requested),
+                };
+                if kept {
+                    self.transactions.insert(transaction_id, ModelTransaction { client_id, amount: requested, charged_back: 0, holds: Vec::new(), dispute: TransactionDispute::No });
+                }
+            }
+            Operation::Dispute => self.dispute(client_id, transaction_id, requested, request.case_id()),
+            Operation::Resolve => self.settle(client_id, transaction_id, requested, request.case_id(), false),
+            Operation::Chargeback => self.settle(client_id, transaction_id, requested, request.case_id(), true),
+        }
+    }
+
+    /// Returns whether the deposit is kept, applied or not.
+    fn deposit(&mut self, client_id: ClientId, requested: Units) -> bool {
+        if requested < 0 {
+            return true;
+        }
+        let account = self.accounts.entry(client_id).or_default();
+        if !account.locked {
+            account.available += requested;
+        }
+        true
+    }
+
+    /// Returns whether the withdrawal is kept, applied or not.
+    fn withdraw(&mut self, client_id: ClientId, requested: Units) -> bool {
+        if requested < 0 {
+            return true;
+        }
+        match self.accounts.get_mut(&client_id) {
+            Some(account) if account.locked || requested > account.available => true,
+            Some(account) => {
+                account.available -= requested;
+                true
+            }
+            // Only deposits open accounts, there is no account to withdraw nothing from.
+            None => requested > 0,
+        }
+    }
+
+    fn dispute(&mut self, client_id: ClientId, transaction_id: TransactionId, requested: Option<Units>, case_id: Option<CaseId>) {
+        let account = self.accounts.get(&client_id).copied().unwrap_or_default();
+        if account.locked || case_id.is_some_and(|case_id| self.cases.contains_key(&case_id)) {
+            return;
+        }
+        let transaction = match self.transactions.get_mut(&transaction_id) {
+            // Only the client of a transaction disputes it.
+            Some(transaction) if transaction.client_id == client_id && !matches!(transaction.dispute, TransactionDispute::Chargeback) => transaction,
+            _ => return,
+        };
+        if transaction.amount < 0 {
+            return;
+        }
+        let disputable = transaction.amount - transaction.charged_back - transaction.holds.iter().map(|hold| hold.held).sum::<Units>();
+        let disputed = match requested {
+            None => disputable,
+            Some(requested) if requested > 0 && requested <= disputable => requested,
+            Some(_) => return,
+        };
+        if disputed == 0 || disputed > account.available || !self.accounts.contains_key(&client_id) {
+            return;
+        }
+
+        let account = self.accounts.get_mut(&client_id).expect("no account");
+        account.available -= disputed;
+        account.held += disputed;
+        transaction.holds.push(ModelHold { held: disputed, case_id });
+        transaction.dispute = TransactionDispute::Disputed;
+        if let Some(case_id) = case_id {
+            self.cases.insert(case_id, ModelCase { transaction_id, client_id, open: true });
+        }
+    }
+
+    fn settle(&mut self, client_id: ClientId, transaction_id: TransactionId, requested: Option<Units>, case_id: Option<CaseId>, chargeback: bool) {
+        let account = self.accounts.get(&client_id).copied().unwrap_or_default();
+        if account.locked {
+            return;
+        }
+        let transaction = match self.transactions.get_mut(&transaction_id) {
+            Some(transaction) if transaction.client_id == client_id && matches!(transaction.dispute, TransactionDispute::Disputed) && !transaction.holds.is_empty() => transaction,
+            _ => return,
+        };
+        // The hold of the case, or the oldest one.
+        let index = match case_id {
+            None => 0,
+            Some(case_id) => match self.cases.get(&case_id) {
+                Some(case) if case.transaction_id == transaction_id && case.client_id == client_id && case.open => {
+                    match transaction.holds.iter().position(|hold| hold.case_id == Some(case_id)) {
+                        Some(index) => index,
+                        None => return,
+                    }
+                }
+                _ => return,
+            },
+        };
+        let settled = match requested {
+            None => transaction.holds[index].held,
+            Some(requested) if requested > 0 && requested <= transaction.holds[index].held => requested,
+            Some(_) => return,
+        };
+        if settled > account.held || !self.accounts.contains_key(&client_id) {
+            return;
+        }
+
+        let account = self.accounts.get_mut(&client_id).expect("no account");
+        account.held -= settled;
+        if chargeback {
+            account.locked = true;
+            transaction.charged_back += settled;
+        } else {
+            account.available += settled;
+        }
+        transaction.holds[index].held -= settled;
+        if transaction.holds[index].held == 0 {
+            if let Some(case_id) = transaction.holds.remove(index).case_id {
+                self.cases.get_mut(&case_id).expect("no case").open = false;
+            }
+        }
+        transaction.dispute = match (transaction.holds.is_empty(), chargeback) {
+            (false, _) => TransactionDispute::Disputed,
+            (true, false) => TransactionDispute::Resolved,
+            (true, true) => TransactionDispute::Chargeback,
+        };
+    }
+}
+
+/// Amounts of deposits and withdrawals, mostly valid.
+fn funds() -> impl Strategy<Value = Option<Amount>> {
+    prop_oneof![
+        12 => (0..=100_000i64).prop_map(|units| Some(amount(units))),
+        1 => Just(None),
+        1 => (-100_000..0i64).prop_map(|units| Some(amount(units))),
+        1 => (1..=1_000_000i64).prop_map(|units| Some(BigDecimal::new(BigInt::from(units), ROUND_DIGITS + 1))),
+    ]
+}
+
+/// Amounts of dispute-family requests, mostly the whole disputed amount.
+fn partial_funds() -> impl Strategy<Value = Option<Amount>> {
+    prop_oneof![
+        6 => Just(None),
+        3 => (1..=60_000i64).prop_map(|units| Some(amount(units))),
+        1 => (-10_000..=0i64).prop_map(|units| Some(amount(units))),
+    ]
+}
+
+/// Requests over a few clients and transaction ids, so that they often collide, a few of them
+/// missing their type, client or transaction id.
+fn request() -> impl Strategy<Value = TransactionRequest> {
+    let transfer = (prop_oneof![2 => Just(Operation::Deposit), 1 => Just(Operation::Withdrawal)], funds(), Just(None));
+    let settlement = (prop_oneof![Just(Operation::Dispute), Just(Operation::Resolve), Just(Operation::Chargeback)], partial_funds(), proptest::option::weighted(0.3, 1..=4u64));
+    (prop_oneof![3 => transfer, 2 => settlement], 1..=3u64, 1..=12u64).prop_map(|((operation, amount, case_id), client_id, transaction_id)| {
+        let mut builder = TransactionRequest::builder(operation, client_id, transaction_id);
+        if let Some(amount) = amount {
+            builder = builder.amount(amount);
+        }
+        if let Some(case_id) = case_id {
+            builder = builder.case_id(case_id);
+        }
+        builder.build()
+    }).prop_flat_map(|request| prop_oneof![
+        27 => Just(request.clone()),
+        1 => Just(TransactionRequest { transaction_type: None, ..request.clone() }),
+        1 => Just(TransactionRequest { client_id: None, ..request.clone() }),
+        1 => Just(TransactionRequest { transaction_id: None, ..request }),
+    ])
+}
+
+/// The error refusing a request that is missing a field, if it is.
+fn missing_field(request: &TransactionRequest) -> Option<RequestError> {
+    if request.transaction_type().is_none() {
+        Some(RequestError::MissingOperation)
+    } else if request.client_id().is_none() {
+        Some(RequestError::MissingClient)
+    } else if request.transaction_id().is_none() {
+        Some(RequestError::MissingTransactionId)
+    } else {
+        None
+    }
+}
+
+/// Requests mostly identified by their position, as the rows of a file, the others sent without an
+/// identifier.
+fn stream() -> impl Strategy<Value = Vec<TransactionRequest>> {
+    proptest::collection::vec((request(), proptest::bool::weighted(0.8)), 1..60).prop_map(|requests| {
+        requests.into_iter().enumerate()
+            .map(|(row, (request, identified))| request.with_request_id(identified.then(|| format!("stream:{}", row))))
+            .collect()
+    })
+}
+
+fn model_accounts<AccRep: AccountRepository, TxRep: TransactionRepository>(service: &mut TransactionService<AccRep, TxRep>) -> HashMap<ClientId, ModelAccount> {
+    service.accounts().unwrap().iter().map(|account| (account.client_id(), ModelAccount::from(account))).collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1000))]
+
+    #[test]
+    fn service_agrees_with_the_model(requests in stream()) {
+        let mut service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        let mut model = Model::default();
+        for request in &requests {
+            let before = service.accounts().unwrap();
+            let replayed_id = matches!(request.transaction_type(), Some(Operation::Deposit | Operation::Withdrawal))
+                && request.valid_transaction().is_ok()
+                && model.transactions.contains_key(&request.transaction_id().unwrap());
+            let result = service.process_transaction(request.clone());
+            model.apply(request);
+            if let Some(missing) = missing_field(request) {
+                prop_assert!(matches!(&result, Err(ServiceError::InvalidRequest(error)) if *error == missing), "{:?} was not refused for {}: {:?}", request, missing, result);
+            }
+
+            let after = service.accounts().unwrap();
+            prop_assert_eq!(&model.accounts, &after.iter().map(|account| (account.client_id(), ModelAccount::from(account))).collect(), "after {:?}", request);
+            for account in &after {
+                prop_assert!(!account.held().is_negative(), "negative held funds after {:?}", request);
+                prop_assert!(!account.available().is_negative(), "negative available funds after {:?}", request);
+                prop_assert_eq!(account.total(), account.available() + account.held());
+            }
+            for account in before.iter().filter(|account| account.is_locked()) {
+                prop_assert_eq!(Some(account), after.iter().find(|update| update.client_id() == account.client_id()), "a locked account changed after {:?}", request);
+            }
+            if replayed_id {
+                prop_assert!(matches!(result, Err(ServiceError::DataError(RepositoryError::EntityAlreadyExists(_)))), "a reused transaction id was not refused: {:?}", request);
+                prop_assert_eq!(&before, &after);
+            }
+        }
+    }
+
+    #[test]
+    fn replayed_streams_are_no_ops(requests in stream()) {
+        let mut service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        // Requests with an identifier are registered along with their writes, refused ones
+        // included, unless the request failed on the store and its unit of work was rolled back.
+        let registered: Vec<bool> = requests.iter().map(|request| {
+            let processed = service.process_transaction(request.clone());
+            request.request_id().is_some() && request.valid_transaction().is_ok() && matches!(processed, Ok(_) | Err(ServiceError::GenericErrorMsg(_)))
+        }).collect();
+        let processed = model_accounts(&mut service);
+
+        let (account_repository, transaction_repository) = service.into_repositories();
+        let mut service = TransactionService::new(account_repository, transaction_repository);
+        // Requests without an identifier cannot be told from new ones, so only the identified ones
+        // are sent again.
+        for (request, registered) in requests.iter().zip(registered).filter(|(request, _)| request.request_id().is_some()) {
+            let result = service.process_transaction(request.clone());
+            if registered {
+                prop_assert!(matches!(result, Err(ServiceError::DuplicateRequest(_))), "{:?} was processed again: {:?}", request, result);
+            }
+        }
+        prop_assert_eq!(processed, model_accounts(&mut service));
+    }
+
+    #[test]
+    fn repositories_agree(requests in stream()) {
+        let mut in_mem = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        let mut sharded = TransactionService::new(ShardedAccountRepository::new(), ShardedTransactionRepository::new());
+        for request in &requests {
+            let in_mem_status = in_mem.process_transaction(request.clone()).map(|outcome| outcome.status.to_string()).map_err(|error| error.to_string());
+            let sharded_status = sharded.process_transaction(request.clone()).map(|outcome| outcome.status.to_string()).map_err(|error| error.to_string());
+            prop_assert_eq!(in_mem_status, sharded_status, "{:?}", request);
+        }
+        prop_assert_eq!(model_accounts(&mut in_mem), model_accounts(&mut sharded));
+    }
+}
@@ -0,0 +1,82 @@
+use std::io;
+use serde::{Deserialize, Serialize};
+use crate::domain::{Amount, CaseId, ClientId, TransactionId};
+
+/// Something that happened to an account, published to the subscribers of the service.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DomainEvent {
+    Deposited { client_id: ClientId, transaction_id: TransactionId, amount: Amount },
+    Withdrawn { client_id: ClientId, transaction_id: TransactionId, amount: Amount },
+    DisputeOpened { client_id: ClientId, transaction_id: TransactionId, case_id: Option<CaseId>, amount: Amount },
+    DisputeResolved { client_id: ClientId, transaction_id: TransactionId, case_id: Option<CaseId>, amount: Amount },
+    ChargedBack { client_id: ClientId, transaction_id: TransactionId, case_id: Option<CaseId>, amount: Amount },
+    /// The account was locked while processing the transaction.
+    AccountLocked { client_id: ClientId, transaction_id: TransactionId },
+    /// A valid request that was not applied.
+    Rejected { client_id: ClientId, transaction_id: TransactionId, reason: String },
+}
+
+impl DomainEvent {
+    /// The name of the event type, as serialized in the `type` field.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DomainEvent::Deposited { .. } => "deposited",
+            DomainEvent::Withdrawn { .. } => "withdrawn",
+            DomainEvent::DisputeOpened { .. } => "dispute_opened",
+            DomainEvent::DisputeResolved { .. } => "dispute_resolved",
+            DomainEvent::ChargedBack { .. } => "charged_back",
+            DomainEvent::AccountLocked { .. } => "account_locked",
+            DomainEvent::Rejected { .. } => "rejected",
+        }
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        match self {
+            DomainEvent::Deposited { client_id, .. }
+            | DomainEvent::Withdrawn { client_id, .. }
+            | DomainEvent::DisputeOpened { client_id, .. }
+            | DomainEvent::DisputeResolved { client_id, .. }
+            | DomainEvent::ChargedBack { client_id, .. }
+            | DomainEvent::AccountLocked { client_id, .. }
+            | DomainEvent::Rejected { client_id, .. } => *client_id,
+        }
+    }
+}
+
+/// An event in the outbox. Events are stored together with the state changes that produced them
+/// and relayed to the subscribers afterwards, sequence numbers let subscribers detect redeliveries.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    sequence: u64,
+    event: DomainEvent,
+}
+
+impl OutboxEntry {
+    pub fn new(sequence: u64, event: DomainEvent) -> Self {
+        OutboxEntry {
+            sequence,
+            event,
+        }
+    }
+
+    pub fn sequence(&self) -> u64 { self.sequence }
+    pub fn event(&self) -> &DomainEvent { &self.event }
+}
+
+/// Receives the events published by the service, in sequence order.
+pub trait EventSubscriber {
+    /// Handles the event. An error leaves the event, and the ones after it, in the outbox to be
+    /// relayed again by the next publication.
+    fn on_event(&mut self, entry: &OutboxEntry) -> Result<(), io::Error>;
+
+    /// Sequence of the last event handled for good, for subscribers that hand the events over to
+    /// be handled in the background. The events handed over after it stay in the outbox. None if
+    /// the events are handled once handed over.
+    fn handled(&self) -> Option<u64> {
+        None
+    }
+
+    /// Waits until the events handed over are handled, or failed to be.
+    fn flush(&mut self) {}
+}
@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+use crate::domain::{Account, Amount, ClientId, Operation, Transaction, TransactionId, TransactionStatus};
+
+/// What a hook decides about an operation before it is applied.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HookVerdict {
+    /// Let the operation proceed.
+    Allow,
+    /// Let the operation proceed, annotating its outcome with the note.
+    Flag(String),
+    /// Reject the operation for the given reason.
+    Veto(String),
+}
+
+/// Fraud heuristics plugged around every operation processed by the TransactionService. Hooks
+/// run in registration order, the first veto rejects the operation.
+///
+/// The state a hook keeps is its own and lives in the process: it is neither rolled back with the
+/// unit of work of an operation nor saved with the state of the service, so a run resumed from a
+/// saved state starts its hooks afresh.
+pub trait TransactionHook {
+    /// Name identifying the hook in annotations and rejection reasons.
+    fn name(&self) -> &str;
+
+    /// Inspects the transaction and the current state of the account before the operation.
+    fn before(&mut self, _transaction: &Transaction, _account: &Account) -> HookVerdict {
+        HookVerdict::Allow
+    }
+
+    /// Inspects the outcome of the operation and the resulting account, returns notes to annotate
+    /// the outcome with.
+    fn after(&mut self, _transaction: &Transaction, _account: &Account, _status: &TransactionStatus) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+fn verdict(veto: bool, reason: String) -> HookVerdict {
+    if veto { HookVerdict::Veto(reason) } else { HookVerdict::Flag(reason) }
+}
+
+/// Flags withdrawals made within the next `window` transactions of a client after a deposit of at
+/// least `threshold`, a common pattern to cash out stolen funds.
+pub struct LargeDepositThenWithdraw {
+    threshold: Amount,
+    window: usize,
+    veto: bool,
+    /// Last large deposit of each client and the number of transactions applied since.
+    large_deposits_by_client_id: HashMap<ClientId, (TransactionId, usize)>,
+}
+
+impl LargeDepositThenWithdraw {
+    pub fn new(threshold: Amount, window: usize, veto: bool) -> Self {
+        LargeDepositThenWithdraw {
+            threshold,
+            window,
+            veto,
+            large_deposits_by_client_id: HashMap::default(),
+        }
+    }
+}
+
+impl TransactionHook for LargeDepositThenWithdraw {
+    fn name(&self) -> &str {
+        "large_deposit_then_withdraw"
+    }
+
+    fn before(&mut self, transaction: &Transaction, _account: &Account) -> HookVerdict {
+        if !matches!(transaction.operation(), Operation::Withdrawal) {
+            return HookVerdict::Allow;
+        }
+        match self.large_deposits_by_client_id.get(&transaction.client_id()) {
+            Some((deposit_id, since)) if *since < self.window => {
+                verdict(self.veto, format!("withdrawal follows the large deposit {}", deposit_id))
+            }
+            _ => HookVerdict::Allow,
+        }
+    }
+
+    fn after(&mut self, transaction: &Transaction, _account: &Account, status: &TransactionStatus) -> Vec<String> {
+        if !matches!(status, TransactionStatus::Applied) {
+            return Vec::new();
+        }
+        let is_large_deposit = matches!(transaction.operation(), Operation::Deposit)
+            && transaction.amount().is_some_and(|amount| amount >= self.threshold);
+        if is_large_deposit {
+            self.large_deposits_by_client_id.insert(transaction.client_id(), (transaction.transaction_id(), 0));
+        } else if let Some((_, since)) = self.large_deposits_by_client_id.get_mut(&transaction.client_id()) {
+            *since += 1;
+            if *since >= self.window {
+                self.large_deposits_by_client_id.remove(&transaction.client_id());
+            }
+        }
+        Vec::new()
+    }
+}
+
+/// Flags a client opening more than `max_disputes` disputes within `window` consecutive applied
+/// operations. Rejected requests do not count, so disputes refused for other reasons do not get the
+/// next legitimate dispute of the client flagged.
+pub struct RapidDisputes {
+    max_disputes: usize,
+    window: usize,
+    veto: bool,
+    /// Whether each of the last applied operations of a client was a dispute, newest last.
+    recent_disputes_by_client_id: HashMap<ClientId, VecDeque<bool>>,
+}
+
+impl RapidDisputes {
+    pub fn new(max_disputes: usize, window: usize, veto: bool) -> Self {
+        RapidDisputes {
+            max_disputes,
+            window,
+            veto,
+            recent_disputes_by_client_id: HashMap::default(),
+        }
+    }
+}
+
+impl TransactionHook for RapidDisputes {
+    fn name(&self) -> &str {
+        "rapid_disputes"
+    }
+
+    fn before(&mut self, transaction: &Transaction, _account: &Account) -> HookVerdict {
+        if !matches!(transaction.operation(), Operation::Dispute) {
+            return HookVerdict::Allow;
+        }
+        let disputes = self.recent_disputes_by_client_id.get(&transaction.client_id())
+            .map_or(0, |recent| recent.iter().rev()
+                .take(self.window.saturating_sub(1))
+                .filter(|dispute| **dispute)
+                .count());
+        if disputes + 1 > self.max_disputes {
+            verdict(self.veto, format!("{} disputes within {} operations", disputes + 1, self.window))
+        } else {
+            HookVerdict::Allow
+        }
+    }
+
+    fn after(&mut self, transaction: &Transaction, _account: &Account, status: &TransactionStatus) -> Vec<String> {
+        if !matches!(status, TransactionStatus::Applied) {
+            return Vec::new();
+        }
+        let recent = self.recent_disputes_by_client_id.entry(transaction.client_id()).or_default();
+        recent.push_back(matches!(transaction.operation(), Operation::Dispute));
+        while recent.len() > self.window {
+            recent.pop_front();
+        }
+        Vec::new()
+    }
+}
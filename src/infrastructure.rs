@@ -1,9 +1,15 @@
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{BufReader, BufWriter, stdout, Stdout, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, stdout, Stdout, Write};
 use std::path::Path;
-use csv::{DeserializeRecordsIter, Reader, Trim};
-use crate::domain::{Account, TransactionRequest};
+use std::str::FromStr;
+use csv::{Reader, StringRecord, StringRecordsIter, Trim};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{error, warn, Level};
+use crate::domain::{Account, ClientHeld, DisputeHistoryEntry, OpenDispute, Transaction, TransactionRequest};
+use crate::events::{EventSubscriber, OutboxEntry};
 
 pub struct ReportProducer {
    writer: BufWriter<Stdout>
@@ -15,7 +21,7 @@ impl ReportProducer {
         match writeln!(writer, "client, available, held, total, locked") {
             Ok(_) => (),
             Err(_) => {
-                eprintln!("Error writing report to sdout!, will continue to work regardless.");
+                error!("Error writing report to stdout, will continue to work regardless.");
             }
         }
         ReportProducer {
@@ -33,34 +39,301 @@ impl ReportProducer {
         ) {
             Ok(_) => (),
             Err(_) => {
-                eprintln!("Error writing entry to sdout!, will continue to work regardless.");
+                error!("Error writing entry to stdout, will continue to work regardless.");
             }
         }
     }
 
 }
 
+impl Default for ReportProducer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for ReportProducer {
     fn drop(&mut self) {
         match self.writer.flush() {
             Ok(_) => (),
             Err(_) => {
-                eprintln!("Error flushing report to sdout!, will continue to work regardless.");
+                error!("Error flushing report to stdout, will continue to work regardless.");
+            }
+        }
+    }
+}
+
+/// Writes a list of transactions to the stdout as CSV.
+pub struct TransactionListProducer {
+    writer: BufWriter<Stdout>
+}
+
+impl TransactionListProducer {
+    pub fn new() -> Self {
+        let mut writer = BufWriter::new(stdout());
+        if writeln!(writer, "tx, client, type, amount, status, dispute, row").is_err() {
+            error!("Error writing transactions to stdout, will continue to work regardless.");
+        }
+        TransactionListProducer {
+            writer
+        }
+    }
+
+    pub fn add(&mut self, transaction: &Transaction) {
+        let written = writeln!(self.writer, "{},{},{},{},{},{},{}",
+                               transaction.transaction_id(),
+                               transaction.client_id(),
+                               transaction.operation(),
+                               transaction.amount().map(|amount| amount.to_string()).unwrap_or_default(),
+                               transaction.status(),
+                               transaction.dispute(),
+                               transaction.row().map(|row| row.to_string()).unwrap_or_default(),
+        );
+        if written.is_err() {
+            error!("Error writing entry to stdout, will continue to work regardless.");
+        }
+    }
+}
+
+impl Default for TransactionListProducer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TransactionListProducer {
+    fn drop(&mut self) {
+        if self.writer.flush().is_err() {
+            error!("Error flushing transactions to stdout, will continue to work regardless.");
+        }
+    }
+}
+
+/// Writes the open disputes, or the funds they hold per client, to the stdout as CSV.
+pub struct OpenDisputesProducer {
+    writer: BufWriter<Stdout>
+}
+
+impl OpenDisputesProducer {
+    fn with_header(header: &str) -> Self {
+        let mut writer = BufWriter::new(stdout());
+        if writeln!(writer, "{}", header).is_err() {
+            error!("Error writing report to stdout, will continue to work regardless.");
+        }
+        OpenDisputesProducer {
+            writer
+        }
+    }
+
+    pub fn disputes() -> Self {
+        Self::with_header("tx, client, case, amount, row, age")
+    }
+
+    pub fn clients() -> Self {
+        Self::with_header("client, disputed, held, consistent")
+    }
+
+    pub fn add_dispute(&mut self, dispute: &OpenDispute) {
+        let written = writeln!(self.writer, "{},{},{},{},{},{}",
+                               dispute.transaction_id,
+                               dispute.client_id,
+                               dispute.case_id.map(|case_id| case_id.to_string()).unwrap_or_default(),
+                               dispute.amount,
+                               dispute.row.map(|row| row.to_string()).unwrap_or_default(),
+                               dispute.age,
+        );
+        if written.is_err() {
+            error!("Error writing entry to stdout, will continue to work regardless.");
+        }
+    }
+
+    pub fn add_client(&mut self, client: &ClientHeld) {
+        let written = writeln!(self.writer, "{},{},{},{}",
+                               client.client_id,
+                               client.disputed,
+                               client.held,
+                               client.is_consistent(),
+        );
+        if written.is_err() {
+            error!("Error writing entry to stdout, will continue to work regardless.");
+        }
+    }
+}
+
+impl Drop for OpenDisputesProducer {
+    fn drop(&mut self) {
+        if self.writer.flush().is_err() {
+            error!("Error flushing report to stdout, will continue to work regardless.");
+        }
+    }
+}
+
+pub struct AuditProducer {
+    writer: BufWriter<File>
+}
+
+impl AuditProducer {
+    pub fn from<F>(filename: F) -> Result<Self, io::Error> where F: AsRef<Path> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+        writeln!(writer, "tx, client, case, cycle, action, amount, row")?;
+        Ok(AuditProducer {
+            writer
+        })
+    }
+
+    pub fn add(&mut self, entry: &DisputeHistoryEntry) -> Result<(), io::Error> {
+        writeln!(self.writer, "{},{},{},{},{},{},{}",
+                 entry.transaction_id(),
+                 entry.client_id(),
+                 entry.case_id().map(|case_id| case_id.to_string()).unwrap_or_default(),
+                 entry.cycle(),
+                 entry.action(),
+                 entry.amount(),
+                 entry.row().map(|row| row.to_string()).unwrap_or_default(),
+        )
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.writer.flush()
+    }
+}
+
+/// Appends the published events to a file, one json object per line. Events redelivered after a
+/// restart are skipped, the file already holds every event up to the last sequence it ends with.
+pub struct EventLogWriter {
+    writer: BufWriter<File>,
+    /// Sequence of the last event in the file.
+    written: u64,
+}
+
+impl EventLogWriter {
+    pub fn from<F>(filename: F) -> Result<Self, io::Error> where F: AsRef<Path> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(filename)?;
+        let mut written = 0;
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                written = serde_json::from_str::<OutboxEntry>(&line)?.sequence();
             }
         }
+        Ok(EventLogWriter {
+            writer: BufWriter::new(file),
+            written,
+        })
+    }
+
+    fn write(&mut self, entry: &OutboxEntry) -> Result<(), io::Error> {
+        if entry.sequence() <= self.written {
+            return Ok(());
+        }
+        serde_json::to_writer(&mut self.writer, entry)?;
+        writeln!(self.writer)?;
+        self.writer.flush()?;
+        self.written = entry.sequence();
+        Ok(())
+    }
+}
+
+impl EventSubscriber for EventLogWriter {
+    fn on_event(&mut self, entry: &OutboxEntry) -> Result<(), io::Error> {
+        self.write(entry)
+    }
+}
+
+/// How the logs are written to the stderr.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LogFormat {
+    Text,
+    /// One json object per line, with the fields of the current span.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}, expected text or json", value)),
+        }
+    }
+}
+
+/// Sends the logs of the given level and above to the stderr, keeping the stdout for the reports.
+pub fn init_logging(level: Level, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).init(),
+    }
+}
+
+/// Reads a json file, e.g. a configuration file.
+pub fn read_json<T, F>(filename: F) -> Result<T, io::Error>
+    where T: DeserializeOwned, F: AsRef<Path> {
+    let file = File::open(filename)?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+/// Writes a json file, e.g. a report.
+pub fn write_json<T, F>(filename: F, value: &T) -> Result<(), io::Error>
+    where T: Serialize, F: AsRef<Path> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writer.flush()
+}
+
+/// Loads state persisted by a previous run, None if the file does not exist yet.
+pub fn load_state<T, F>(filename: F) -> Result<Option<T>, io::Error>
+    where T: DeserializeOwned, F: AsRef<Path> {
+    match read_json(filename) {
+        Ok(state) => Ok(Some(state)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
     }
 }
 
-pub struct TransactionFileReader {
-    reader: Reader<BufReader<File>>,
+/// Persists state for the next run. The state is written aside and then moved in place so that a
+/// failure never leaves a truncated state file behind.
+pub fn save_state<T, F>(filename: F, state: &T) -> Result<(), io::Error>
+    where T: Serialize, F: AsRef<Path> {
+    let filename = filename.as_ref();
+    let staging = filename.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&staging)?);
+    serde_json::to_writer(&mut writer, state)?;
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(staging, filename)
+}
+
+pub struct TransactionFileReader<R: Read = BufReader<File>> {
+    reader: Reader<R>,
+    bad_rows: u64,
+    /// Hash of the source and of the rows read so far, identifying the next request along with
+    /// its own row.
+    chain: Option<[u8; 32]>,
 }
 
 impl TransactionFileReader {
 
+    /// Reads the requests of a file. Every request is identified by a hash chained over the rows
+    /// up to its own, so that replaying the file, a copy of it from any path or the file re-sent
+    /// with rows appended skips the rows already processed, while a file that differs before a
+    /// row, e.g. the next day's, can repeat its content.
     pub fn from<F>(filename: F) -> Result<Self, io::Error> where F: AsRef<Path> {
 
-        let file = File::open(filename)?;
-        let buf_reader = BufReader::new(file);
+        let file = File::open(&filename)?;
+        Ok(Self::from_reader(BufReader::new(file)).with_source(""))
+    }
+}
+
+impl<R: Read> TransactionFileReader<R> {
+
+    /// Reads the requests from any source, e.g. a buffer.
+    pub fn from_reader(reader: R) -> Self {
         let csv_reader = csv::ReaderBuilder::new()
             .has_headers(true)
             .double_quote(false)
@@ -68,39 +341,85 @@ impl TransactionFileReader {
             .flexible(true)
             .trim(Trim::All)
             .delimiter(b',')
-            .from_reader(buf_reader);
+            .from_reader(reader);
 
-        Ok(TransactionFileReader {
-            reader: csv_reader
-        })
+        TransactionFileReader {
+            reader: csv_reader,
+            bad_rows: 0,
+            chain: None,
+        }
+    }
+
+    /// Identifies the requests read by a hash chained from the source, e.g. the name of a
+    /// sender, over the rows up to their own. Sources with distinct names never share a request.
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.chain = Some(Sha256::digest(source.as_bytes()).into());
+        self
     }
 
-    pub fn values(&mut self) -> Visitor {
+    /// Rows skipped so far because they could not be read as a request.
+    pub fn bad_rows(&self) -> u64 {
+        self.bad_rows
+    }
+
+    pub fn values(&mut self) -> Visitor<'_, R> {
+        let headers = match self.reader.headers() {
+            Ok(headers) => Some(headers.clone()),
+            Err(err) => {
+                error!(error = %err, "Error while reading the file headers");
+                None
+            }
+        };
         Visitor {
-            iter: self.reader.deserialize::<TransactionRequest>()
+            headers,
+            iter: self.reader.records(),
+            bad_rows: &mut self.bad_rows,
+            chain: &mut self.chain,
         }
     }
 }
 
-pub struct Visitor<'a> {
-    iter: DeserializeRecordsIter<'a, BufReader<File>, TransactionRequest>
+pub struct Visitor<'a, R: Read = BufReader<File>> {
+    headers: Option<StringRecord>,
+    iter: StringRecordsIter<'a, R>,
+    bad_rows: &'a mut u64,
+    chain: &'a mut Option<[u8; 32]>,
+}
+
+/// Chains the hash of the rows read before with the fields of the row.
+fn chain_row(chain: &[u8; 32], record: &StringRecord) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(chain);
+    for field in record.iter() {
+        hasher.update(field.as_bytes());
+        // Separates the fields, so that moving a character across them changes the hash.
+        hasher.update([0x1f]);
+    }
+    hasher.finalize().into()
 }
 
-impl <'a> Iterator for Visitor<'a> {
+impl <'a, R: Read> Iterator for Visitor<'a, R> {
     type Item = TransactionRequest;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let opt = self.iter.next();
-        match opt {
-            None => None,
-            Some(result) => match result {
-                Ok(tx) => Some(tx),
+        for result in self.iter.by_ref() {
+            match result.and_then(|record| {
+                let row = record.position().map(|position| position.line());
+                let request_id = self.chain.as_mut().map(|chain| {
+                    *chain = chain_row(chain, &record);
+                    hex::encode(&chain[..16])
+                });
+                record.deserialize::<TransactionRequest>(self.headers.as_ref())
+                    .map(|tx| tx.with_row(row).with_request_id(request_id))
+            }) {
+                Ok(tx) => return Some(tx),
                 Err(err) => {
-                    eprintln!("Error while reading transaction request. Will skip the record and continue. {}", err);
-                    None
+                    *self.bad_rows += 1;
+                    warn!(row = err.position().map(|position| position.line()), error = %err, "Error while reading transaction request. Will skip the record and continue");
                 }
             }
         }
+        None
     }
 }
 
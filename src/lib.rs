@@ -0,0 +1,44 @@
+//! Transaction processing engine for client accounts: deposits, withdrawals and the dispute
+//! lifecycle (dispute, resolve, chargeback).
+//!
+//! The engine is the [`TransactionService`], built by injecting an [`AccountRepository`] and a
+//! [`TransactionRepository`]. The in-memory implementations and the embedded key-value store in
+//! [`repository`] are ready to use, custom storage can be plugged in by implementing both traits.
+//!
+//! ```
+//! use rails::{Amount, Operation, TransactionRequest, TransactionService, TransactionStatus};
+//! use rails::repository::{InMemAccountRepository, InMemTransactionRepository};
+//!
+//! let mut service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+//! let deposit = TransactionRequest::builder(Operation::Deposit, 1, 1)
+//!     .amount(Amount::from(10))
+//!     .build();
+//! let outcome = service.process_transaction(deposit).unwrap();
+//! assert!(matches!(outcome.status, TransactionStatus::Applied));
+//!
+//! let account = service.get_account_status(&1).unwrap();
+//! assert_eq!(Amount::from(10), account.available());
+//! ```
+
+pub mod application;
+pub mod domain;
+pub mod events;
+pub mod hooks;
+pub mod infrastructure;
+pub mod metrics;
+pub mod repository;
+pub mod rules;
+pub mod webhooks;
+mod controller;
+
+pub use crate::domain::{
+    Account, AccountPoint, AccountRepository, AccountVersion, Amount, CaseId, ClientHeld, ClientId,
+    DisputeAction, DisputeCase, DisputeCaseState, DisputeHistoryEntry, DisputeHold, OpenDispute,
+    OpenDisputesReport, Operation, ProcessingSummary, RejectionReason, RepositoryError, RowNumber,
+    ServiceConfig, ServiceError, StatusFilter, Timestamp, Transaction, TransactionDispute,
+    TransactionId, TransactionOutcome, TransactionPage, TransactionQuery, TransactionRepository,
+    TransactionRequest, TransactionRequestBuilder, TransactionService, TransactionStatus, UnitOfWork,
+};
+pub use crate::events::{DomainEvent, EventSubscriber, OutboxEntry};
+pub use crate::hooks::{HookVerdict, TransactionHook};
+pub use crate::rules::RulesConfig;
@@ -1,32 +1,415 @@
-mod application;
-mod infrastructure;
-mod controller;
-mod domain;
-mod repository;
-
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use clap::{Parser};
-use crate::application::AppError;
-use crate::domain::{ServiceError, TransactionService};
-use crate::repository::{InMemAccountRepository, InMemTransactionRepository};
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::{Parser, Subcommand};
+use tracing::{error, info, warn, Level};
+use rails::application::AppError;
+use rails::{AccountPoint, AccountRepository, Amount, ClientId, ServiceConfig, ServiceError, StatusFilter, Timestamp, TransactionDispute, TransactionId, TransactionQuery, TransactionRepository, TransactionService};
+use rails::hooks::{LargeDepositThenWithdraw, RapidDisputes};
+use rails::infrastructure::{init_logging, load_state, read_json, save_state, write_json, LogFormat, EventLogWriter, OpenDisputesProducer, ReportProducer, TransactionListProducer};
+use rails::metrics::{serve_metrics, MeteredAccountRepository, MeteredTransactionRepository, Metrics};
+use rails::repository::{CompactStoreConfig, CompactTransactionRepository, InMemAccountRepository, InMemSnapshot, InMemTransactionRepository, KvAccountRepository, KvStore, KvTransactionRepository};
+use rails::webhooks::WebhookDispatcher;
+
+/// Number of subsequent transactions of a client in which a withdrawal after a large deposit is
+/// flagged.
+const LARGE_DEPOSIT_WINDOW: usize = 3;
+/// Number of consecutive requests of a client in which rapid disputes are counted.
+const RAPID_DISPUTES_WINDOW: usize = 10;
 
 /// Application arguments.
 #[derive(Parser, Debug)]
+#[clap(subcommand_negates_reqs = true)]
 struct Arguments {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Lowest level logged to the stderr: error, warn, info, debug or trace.
+    #[clap(long, global = true, default_value = "warn")]
+    log_level: Level,
+
+    /// Format of the logs: text or json.
+    #[clap(long, global = true, default_value = "text")]
+    log_format: LogFormat,
+
     /// The input file name containing transactions.
-    input_filename: String,
+    #[clap(required = true)]
+    input_filename: Option<String>,
+
+    /// Maximum number of dispute cycles allowed per transaction, unlimited by default.
+    #[clap(long)]
+    max_dispute_cycles: Option<u32>,
+
+    /// Optional file to write the dispute history of every transaction to.
+    #[clap(long)]
+    audit: Option<String>,
+
+    /// Optional json file configuring the per client risk rules.
+    #[clap(long)]
+    rules: Option<String>,
+
+    /// Flag withdrawals that closely follow a deposit of at least this amount.
+    #[clap(long)]
+    flag_large_deposits: Option<Amount>,
+
+    /// Flag clients opening more than this number of disputes in a short sequence of requests.
+    #[clap(long)]
+    flag_rapid_disputes: Option<usize>,
+
+    /// Optional file holding the state of previous runs. It is restored before processing and
+    /// saved afterwards, requests already processed in previous runs are skipped.
+    #[clap(long)]
+    state: Option<String>,
+
+    /// Optional embedded key-value store holding the state of previous runs, created if missing.
+    /// Unlike --state every request is stored as soon as it is processed.
+    #[clap(long, conflicts_with_all = &["state", "memory-budget"])]
+    store: Option<PathBuf>,
+
+    /// Optional file to append the domain events to, one json object per line.
+    #[clap(long)]
+    events: Option<String>,
+
+    /// Optional json file configuring the webhook endpoints notified of account locks and
+    /// chargebacks.
+    #[clap(long)]
+    webhooks: Option<String>,
+
+    /// Optional file to write the run summary to as json, instead of the stderr.
+    #[clap(long)]
+    summary: Option<String>,
+
+    /// Optional file to write the metrics of the run to, in the Prometheus text format.
+    #[clap(long)]
+    metrics_file: Option<String>,
+
+    /// Serve the metrics at http://<address>/metrics while processing, and keep serving them once
+    /// the run completes until the process is stopped.
+    #[clap(long)]
+    metrics_listen: Option<String>,
+
+    /// Keep only what disputes need of the transactions, spilling them to disk beyond this number
+    /// of bytes of memory, and no history of the accounts. For inputs too large to fit in memory,
+    /// it cannot be combined with --state or --store.
+    #[clap(long, conflicts_with_all = &["state", "store"])]
+    memory_budget: Option<usize>,
+
+    /// Directory of the spill file of --memory-budget, the temporary directory by default.
+    #[clap(long, requires = "memory-budget")]
+    spill_dir: Option<PathBuf>,
+
+    /// Seconds after which a transaction can no longer be disputed, measured from the latest
+    /// transaction timestamp. Older transactions are dropped from the store of --memory-budget.
+    #[clap(long, requires = "memory-budget")]
+    dispute_window: Option<Timestamp>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Prints the balance of an account at a point of its history, from the state saved by
+    /// previous runs in a state file or a key-value store.
+    Query {
+        /// The state file of previous runs, see --state.
+        #[clap(long, required_unless_present = "store", conflicts_with = "store")]
+        state: Option<String>,
+
+        /// The key-value store of previous runs, see --store.
+        #[clap(long)]
+        store: Option<PathBuf>,
+
+        #[clap(long)]
+        client: ClientId,
+
+        /// Balance right after the deposit or withdrawal with this id was applied.
+        #[clap(long, required_unless_present = "at", conflicts_with = "at")]
+        after_tx: Option<TransactionId>,
+
+        /// Balance at this time, in seconds since the unix epoch.
+        #[clap(long)]
+        at: Option<Timestamp>,
+    },
+    /// Lists the stored transactions matching every filter, ordered by id, from the state saved
+    /// by previous runs in a state file or a key-value store.
+    Transactions {
+        /// The state file of previous runs, see --state.
+        #[clap(long, required_unless_present = "store", conflicts_with = "store")]
+        state: Option<String>,
+
+        /// The key-value store of previous runs, see --store.
+        #[clap(long)]
+        store: Option<PathBuf>,
+
+        #[clap(long)]
+        client: Option<ClientId>,
+
+        /// One of pending, applied, error or rejected.
+        #[clap(long)]
+        status: Option<StatusFilter>,
+
+        /// One of no, disputed, resolved or chargeback.
+        #[clap(long)]
+        dispute: Option<TransactionDispute>,
+
+        /// Lowest transaction id listed.
+        #[clap(long)]
+        from_tx: Option<TransactionId>,
+
+        /// Highest transaction id listed.
+        #[clap(long)]
+        to_tx: Option<TransactionId>,
+
+        /// Number of matching transactions skipped.
+        #[clap(long, default_value = "0")]
+        offset: usize,
+
+        /// Maximum number of transactions listed.
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+    /// Lists the open disputes from the state saved by previous runs in a state file or a
+    /// key-value store.
+    Disputes {
+        /// The state file of previous runs, see --state.
+        #[clap(long, required_unless_present = "store", conflicts_with = "store")]
+        state: Option<String>,
+
+        /// The key-value store of previous runs, see --store.
+        #[clap(long)]
+        store: Option<PathBuf>,
+
+        /// Time the disputes are aged at, in seconds since the unix epoch, now by default.
+        #[clap(long)]
+        as_of: Option<Timestamp>,
+
+        /// List the funds held by the open disputes of each client instead, next to the funds
+        /// held by the account.
+        #[clap(long)]
+        by_client: bool,
+    },
+    /// Copies the state saved by previous runs from a state file into a key-value store, replacing
+    /// its content, or the other way around.
+    Migrate {
+        /// The state file, see --state.
+        #[clap(long)]
+        state: String,
+
+        /// The key-value store, see --store.
+        #[clap(long)]
+        store: PathBuf,
+
+        /// Copy the store into the state file instead.
+        #[clap(long)]
+        to_state: bool,
+    },
+}
+
+/// Restores a service from the state saved by previous runs.
+fn restore_service(state_filename: &str) -> Result<TransactionService<InMemAccountRepository, InMemTransactionRepository>, ServiceError> {
+    let snapshot: InMemSnapshot = load_state(state_filename)?
+        .ok_or_else(|| ServiceError::GenericErrorMsg(format!("State file not found. {}", state_filename)))?;
+    let (account_repository, transaction_repository) = snapshot.restore();
+    Ok(TransactionService::new(account_repository, transaction_repository))
+}
+
+/// Opens a service on the key-value store of previous runs, which must exist.
+fn open_store_service(store: &Path) -> Result<TransactionService<KvAccountRepository, KvTransactionRepository>, ServiceError> {
+    if !store.exists() {
+        return Err(ServiceError::GenericErrorMsg(format!("Store not found. {}", store.display())));
+    }
+    let (account_repository, transaction_repository) = KvStore::open(store)?.repositories();
+    Ok(TransactionService::new(account_repository, transaction_repository))
+}
+
+fn query<AccRep, TxRep>(mut transaction_service: TransactionService<AccRep, TxRep>, client_id: ClientId, point: AccountPoint) -> Result<(), ServiceError>
+    where AccRep: AccountRepository,
+          TxRep: TransactionRepository {
+    match transaction_service.get_account_at(&client_id, &point)? {
+        Some(account) => {
+            ReportProducer::new().add(&account);
+            Ok(())
+        }
+        None => Err(ServiceError::GenericErrorMsg(format!("No balance of client {} at {:?}", client_id, point))),
+    }
 }
 
-fn run(arguments: Arguments) -> Result<(), ServiceError> {
+fn list_transactions<AccRep, TxRep>(mut transaction_service: TransactionService<AccRep, TxRep>, query: TransactionQuery) -> Result<(), ServiceError>
+    where AccRep: AccountRepository,
+          TxRep: TransactionRepository {
+    let page = transaction_service.find_transactions(&query)?;
+    let mut list = TransactionListProducer::new();
+    page.transactions.iter().for_each(|transaction| list.add(transaction));
+    info!(listed = page.transactions.len(), total = page.total, "Transactions listed");
+    Ok(())
+}
+
+fn open_disputes<AccRep, TxRep>(mut transaction_service: TransactionService<AccRep, TxRep>, as_of: Option<Timestamp>, by_client: bool) -> Result<(), ServiceError>
+    where AccRep: AccountRepository,
+          TxRep: TransactionRepository {
+    let as_of = as_of.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+    });
+    let report = transaction_service.open_disputes(as_of)?;
+    if by_client {
+        let mut producer = OpenDisputesProducer::clients();
+        report.clients.iter().for_each(|client| producer.add_client(client));
+    } else {
+        let mut producer = OpenDisputesProducer::disputes();
+        report.disputes.iter().for_each(|dispute| producer.add_dispute(dispute));
+    }
+    let inconsistent = report.clients.iter().filter(|client| !client.is_consistent()).count();
+    if inconsistent > 0 {
+        warn!(clients = inconsistent, "Clients hold funds that do not match their open disputes");
+    }
+    Ok(())
+}
 
-    // Build the app by injecting dependencies.
-    let account_repository = InMemAccountRepository::default();
-    let transaction_repository = InMemTransactionRepository::default();
-    let mut transaction_service = TransactionService::new(account_repository, transaction_repository);
+/// Processes the input file with the repositories, and reports the run.
+fn process<AccRep, TxRep>(arguments: &Arguments, input_filename: String, account_repository: AccRep, transaction_repository: TxRep, metrics: &Metrics)
+    -> Result<TransactionService<MeteredAccountRepository<AccRep>, MeteredTransactionRepository<TxRep>>, ServiceError>
+    where AccRep: AccountRepository,
+          TxRep: TransactionRepository {
+    let account_repository = MeteredAccountRepository::new(account_repository, metrics.clone());
+    let transaction_repository = MeteredTransactionRepository::new(transaction_repository, metrics.clone());
+    let config = ServiceConfig {
+        max_dispute_cycles: arguments.max_dispute_cycles,
+        rules: match &arguments.rules {
+            Some(rules_filename) => read_json(rules_filename)?,
+            None => Default::default(),
+        },
+        // The history of the accounts grows with every request, unlike the compact store.
+        account_history: arguments.memory_budget.is_none(),
+        ..ServiceConfig::default()
+    };
+    let mut transaction_service = TransactionService::with_config(account_repository, transaction_repository, config);
+    transaction_service.set_metrics(metrics.clone());
+    if let Some(threshold) = &arguments.flag_large_deposits {
+        transaction_service.register_hook(Box::new(LargeDepositThenWithdraw::new(threshold.clone(), LARGE_DEPOSIT_WINDOW, false)));
+    }
+    if let Some(max_disputes) = arguments.flag_rapid_disputes {
+        transaction_service.register_hook(Box::new(RapidDisputes::new(max_disputes, RAPID_DISPUTES_WINDOW, false)));
+    }
+    if let Some(events_filename) = &arguments.events {
+        transaction_service.register_subscriber(Box::new(EventLogWriter::from(events_filename)?));
+        // Relay the events left in the outbox by a previous run first.
+        transaction_service.publish_events()?;
+    }
+    if let Some(webhooks_filename) = &arguments.webhooks {
+        transaction_service.register_subscriber(Box::new(WebhookDispatcher::start(read_json(webhooks_filename)?)?));
+    }
 
     // Process the input file.
-    transaction_service.process_transactions_from_file(arguments.input_filename)?;
-    transaction_service.report_account_statuses()
+    let summary = transaction_service.process_transactions_from_file(input_filename)?;
+    // Events still being delivered stay in the outbox, to be relayed again by the next run.
+    if let Err(err) = transaction_service.flush_events() {
+        warn!(error = ?err, "Events not published, they stay in the outbox");
+    }
+    match &arguments.summary {
+        Some(summary_filename) => write_json(summary_filename, &summary)?,
+        None => eprintln!("{}", summary),
+    }
+    if let Some(audit_filename) = &arguments.audit {
+        transaction_service.report_dispute_history(audit_filename)?;
+    }
+    transaction_service.report_account_statuses()?;
+
+    if let Some(metrics_filename) = &arguments.metrics_file {
+        std::fs::write(metrics_filename, metrics.render())?;
+    }
+    Ok(transaction_service)
+}
+
+fn migrate(state_filename: String, store: PathBuf, to_state: bool) -> Result<(), ServiceError> {
+    let store = KvStore::open(store)?;
+    if to_state {
+        save_state(state_filename, &store.export()?)?;
+    } else {
+        let snapshot: InMemSnapshot = load_state(&state_filename)?
+            .ok_or_else(|| ServiceError::GenericErrorMsg(format!("State file not found. {}", state_filename)))?;
+        store.import(&snapshot)?;
+    }
+    Ok(())
+}
+
+fn run(mut arguments: Arguments) -> Result<(), ServiceError> {
+    let input_filename = match arguments.command.take() {
+        Some(Command::Query { state, store, client, after_tx, at }) => {
+            let point = match (after_tx, at) {
+                (Some(transaction_id), _) => AccountPoint::AfterTransaction(transaction_id),
+                (None, Some(timestamp)) => AccountPoint::At(timestamp),
+                (None, None) => unreachable!("clap requires one of --after-tx and --at"),
+            };
+            return match (store, state) {
+                (Some(store), _) => query(open_store_service(&store)?, client, point),
+                (None, state) => query(restore_service(&state.unwrap_or_default())?, client, point),
+            };
+        }
+        Some(Command::Transactions { state, store, client, status, dispute, from_tx, to_tx, offset, limit }) => {
+            let query = TransactionQuery {
+                client_id: client,
+                status,
+                dispute,
+                from_transaction_id: from_tx,
+                to_transaction_id: to_tx,
+                offset,
+                limit,
+            };
+            return match (store, state) {
+                (Some(store), _) => list_transactions(open_store_service(&store)?, query),
+                (None, state) => list_transactions(restore_service(&state.unwrap_or_default())?, query),
+            };
+        }
+        Some(Command::Disputes { state, store, as_of, by_client }) => {
+            return match (store, state) {
+                (Some(store), _) => open_disputes(open_store_service(&store)?, as_of, by_client),
+                (None, state) => open_disputes(restore_service(&state.unwrap_or_default())?, as_of, by_client),
+            };
+        }
+        Some(Command::Migrate { state, store, to_state }) => return migrate(state, store, to_state),
+        None => arguments.input_filename.take().unwrap_or_default(),
+    };
+
+    // Build the app by injecting dependencies, restoring the state of previous runs if any.
+    let metrics = Metrics::default();
+    let metrics_server = match &arguments.metrics_listen {
+        Some(address) => Some(serve_metrics(TcpListener::bind(address)?, metrics.clone())?),
+        None => None,
+    };
+    match (&arguments.store, arguments.memory_budget) {
+        (Some(store), _) => {
+            let (account_repository, transaction_repository) = KvStore::open(store)?.repositories();
+            process(&arguments, input_filename, account_repository, transaction_repository, &metrics)?;
+        }
+        (None, Some(memory_budget)) => {
+            let config = CompactStoreConfig {
+                memory_budget,
+                spill_dir: arguments.spill_dir.clone(),
+                dispute_window: arguments.dispute_window,
+            };
+            process(&arguments, input_filename, InMemAccountRepository::default(), CompactTransactionRepository::new(config), &metrics)?;
+        }
+        (None, None) => {
+            let snapshot = match &arguments.state {
+                Some(state_filename) => load_state(state_filename)?.unwrap_or_default(),
+                None => InMemSnapshot::default(),
+            };
+            let (account_repository, transaction_repository) = snapshot.restore();
+            let transaction_service = process(&arguments, input_filename, account_repository, transaction_repository, &metrics)?;
+            if let Some(state_filename) = &arguments.state {
+                let (account_repository, transaction_repository) = transaction_service.into_repositories();
+                let (account_repository, transaction_repository) = (account_repository.into_inner(), transaction_repository.into_inner());
+                save_state(state_filename, &InMemSnapshot::capture(&account_repository, &transaction_repository))?;
+            }
+        }
+    }
+    if let Some(metrics_server) = metrics_server {
+        // Server mode, keep exposing the metrics of the run until the process is stopped.
+        info!("Run completed, serving the metrics until stopped");
+        if metrics_server.join().is_err() {
+            error!("Metrics server panicked");
+        }
+    }
+    Ok(())
 }
 
 
@@ -34,13 +417,14 @@ fn main() -> Result<(), AppError> {
     // Run and handle program exit status.
     // Take arguments, if it fails to parse skip to USAGE.
     let arguments = Arguments::parse();
+    init_logging(arguments.log_level, arguments.log_format);
 
     match run(arguments) {
         Ok(_) => {
             exit(exitcode::OK);
         }
         Err(error) => {
-            eprintln!("Process exited with errors: {:?}", error);
+            error!(error = ?error, "Process exited with errors");
             match error {
                 ServiceError::IOError(_) => exit(exitcode::IOERR),
                 _ => exit(exitcode::DATAERR),
@@ -49,12 +433,12 @@ fn main() -> Result<(), AppError> {
     }
 }
 
-#[cfg(feature = "integration-tests")]
+#[cfg(all(test, feature = "integration-tests"))]
 mod test {
 
     use std::process::Command;
     use assert_cmd::prelude::{CommandCargoExt, OutputAssertExt};
-    use predicates::prelude::predicate;
+    use predicates::prelude::{predicate, PredicateBooleanExt};
 
     #[test]
     fn usage_with_missing_arguments() -> Result<(), Box<dyn std::error::Error>> {
@@ -83,4 +467,263 @@ mod test {
            .success();
         Ok(())
     }
-}
\ No newline at end of file
+    #[test]
+    fn query_balance_after_transaction() -> Result<(), Box<dyn std::error::Error>> {
+        let state = std::env::temp_dir().join(format!("rails-query-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state);
+        Command::cargo_bin("rails")?
+            .arg("transactions.csv")
+            .arg("--state").arg(&state)
+            .assert()
+            .success();
+
+        let mut cmd = Command::cargo_bin("rails")?;
+        cmd.args(["query", "--client", "1", "--after-tx", "3", "--state"]).arg(&state);
+        let assert = cmd.assert();
+        std::fs::remove_file(&state)?;
+        assert.success()
+           .stdout(predicate::str::contains("1,3.0000,0,3.0000,false"));
+        Ok(())
+    }
+    #[test]
+    fn list_transactions_with_filters() -> Result<(), Box<dyn std::error::Error>> {
+        let state = std::env::temp_dir().join(format!("rails-transactions-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state);
+        Command::cargo_bin("rails")?
+            .arg("transactions.csv")
+            .arg("--state").arg(&state)
+            .assert()
+            .success();
+
+        let mut cmd = Command::cargo_bin("rails")?;
+        cmd.args(["transactions", "--status", "error", "--state"]).arg(&state);
+        let assert = cmd.assert();
+        std::fs::remove_file(&state)?;
+        assert.success()
+           .stdout(predicate::str::contains("5,2,withdrawal,3.0000,error,no,6"))
+           .stdout(predicate::str::contains("deposit").not());
+        Ok(())
+    }
+    #[test]
+    fn summary_counts_bad_rows() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("rails-summary-input-{}.csv", std::process::id()));
+        let summary = dir.join(format!("rails-summary-{}.json", std::process::id()));
+        std::fs::write(&input, "type, client, tx, amount\ndeposit, 1, 1, 1.0\nunknown, 1, 2, 1.0\ndeposit, 1, 3, 2.0\n")?;
+        Command::cargo_bin("rails")?
+            .arg(&input)
+            .arg("--summary").arg(&summary)
+            .assert()
+            .success();
+
+        let content: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&summary)?)?;
+        std::fs::remove_file(&input)?;
+        std::fs::remove_file(&summary)?;
+        assert_eq!(2, content["processed"]);
+        assert_eq!(1, content["bad_rows"]);
+        assert_eq!("3.0000", content["deposited"]);
+        Ok(())
+    }
+    #[test]
+    fn json_logs_on_stderr_only() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("rails")?;
+        cmd.args(["transactions.csv", "--log-level", "debug", "--log-format", "json"]);
+        cmd.assert()
+           .success()
+           .stdout(predicate::str::contains("level").not())
+           .stderr(predicate::str::contains(r#""level":"DEBUG""#))
+           .stderr(predicate::str::contains(r#""tx":5"#))
+           .stderr(predicate::str::contains(r#""outcome":"error""#));
+        Ok(())
+    }
+    #[test]
+    fn memory_budget_refuses_persistent_state() -> Result<(), Box<dyn std::error::Error>> {
+        for persistence in ["--state", "--store"] {
+            let mut cmd = Command::cargo_bin("rails")?;
+            cmd.args(["transactions.csv", "--memory-budget", "0", persistence, "state"]);
+            cmd.assert()
+               .failure()
+               .stderr(predicate::str::contains("cannot be used with"));
+        }
+        Ok(())
+    }
+    #[test]
+    fn memory_budget_spills_to_disk() -> Result<(), Box<dyn std::error::Error>> {
+        let in_memory = Command::cargo_bin("rails")?.arg("transactions.csv").output()?;
+        let spill_dir = std::env::temp_dir().join(format!("rails-spill-{}", std::process::id()));
+        std::fs::create_dir_all(&spill_dir)?;
+        let compact = Command::cargo_bin("rails")?
+            .args(["transactions.csv", "--memory-budget", "0", "--spill-dir"]).arg(&spill_dir)
+            .output()?;
+        let spilled = std::fs::read_dir(&spill_dir)?.count();
+        std::fs::remove_dir(&spill_dir)?;
+        assert!(compact.status.success());
+        // Accounts are reported in no particular order.
+        let lines = |stdout: Vec<u8>| -> Result<Vec<String>, std::string::FromUtf8Error> {
+            let mut lines: Vec<String> = String::from_utf8(stdout)?.lines().map(String::from).collect();
+            lines.sort();
+            Ok(lines)
+        };
+        assert_eq!(lines(in_memory.stdout)?, lines(compact.stdout)?);
+        // The spill file does not outlive the run.
+        assert_eq!(0, spilled);
+        Ok(())
+    }
+    #[test]
+    fn read_only_commands_read_the_store() -> Result<(), Box<dyn std::error::Error>> {
+        let store = std::env::temp_dir().join(format!("rails-read-store-{}.redb", std::process::id()));
+        let _ = std::fs::remove_file(&store);
+        Command::cargo_bin("rails")?
+            .arg("transactions.csv")
+            .arg("--store").arg(&store)
+            .assert()
+            .success();
+
+        let query = Command::cargo_bin("rails")?
+            .args(["query", "--client", "1", "--after-tx", "3", "--store"]).arg(&store)
+            .output()?;
+        let transactions = Command::cargo_bin("rails")?
+            .args(["transactions", "--status", "error", "--store"]).arg(&store)
+            .output()?;
+        let disputes = Command::cargo_bin("rails")?
+            .args(["disputes", "--by-client", "--store"]).arg(&store)
+            .output()?;
+        let both = Command::cargo_bin("rails")?
+            .args(["disputes", "--state", "state.json", "--store"]).arg(&store)
+            .output()?;
+        std::fs::remove_file(&store)?;
+        let missing = Command::cargo_bin("rails")?
+            .args(["disputes", "--store"]).arg(&store)
+            .output()?;
+
+        assert!(String::from_utf8(query.stdout)?.contains("1,3.0000,0,3.0000,false"));
+        assert!(String::from_utf8(transactions.stdout)?.contains("5,2,withdrawal,3.0000,error,no,6"));
+        assert!(disputes.status.success());
+        assert!(!both.status.success());
+        // Reading a store does not create it.
+        assert!(!missing.status.success());
+        assert!(!store.exists());
+        Ok(())
+    }
+    #[test]
+    fn migrate_between_state_and_store() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir();
+        let state = dir.join(format!("rails-migrate-state-{}.json", std::process::id()));
+        let store = dir.join(format!("rails-migrate-store-{}.redb", std::process::id()));
+        let _ = std::fs::remove_file(&state);
+        let _ = std::fs::remove_file(&store);
+        Command::cargo_bin("rails")?
+            .arg("transactions.csv")
+            .arg("--state").arg(&state)
+            .assert()
+            .success();
+        Command::cargo_bin("rails")?
+            .arg("migrate").arg("--state").arg(&state).arg("--store").arg(&store)
+            .assert()
+            .success();
+
+        // The requests processed before the migration are skipped.
+        let rerun = Command::cargo_bin("rails")?
+            .arg("transactions.csv")
+            .arg("--store").arg(&store)
+            .output()?;
+        std::fs::remove_file(&state)?;
+        Command::cargo_bin("rails")?
+            .arg("migrate").arg("--state").arg(&state).arg("--store").arg(&store).arg("--to-state")
+            .assert()
+            .success();
+        let mut cmd = Command::cargo_bin("rails")?;
+        cmd.args(["transactions", "--status", "error", "--state"]).arg(&state);
+        let assert = cmd.assert();
+        std::fs::remove_file(&state)?;
+        std::fs::remove_file(&store)?;
+        assert!(rerun.status.success());
+        assert!(String::from_utf8(rerun.stderr)?.contains("Skipped 5 duplicates"));
+        assert.success()
+           .stdout(predicate::str::contains("5,2,withdrawal,3.0000,error,no,6"));
+        Ok(())
+    }
+    #[test]
+    fn later_files_dispute_again() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir();
+        let monday = dir.join(format!("rails-monday-{}.csv", std::process::id()));
+        let tuesday = dir.join(format!("rails-tuesday-{}.csv", std::process::id()));
+        let state = dir.join(format!("rails-dispute-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state);
+        std::fs::write(&monday, "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndispute, 1, 1,\nresolve, 1, 1,\n")?;
+        std::fs::write(&tuesday, "type, client, tx, amount\ndispute, 1, 1,\n")?;
+        let run = |input: &std::path::Path| -> Result<std::process::Output, Box<dyn std::error::Error>> {
+            Ok(Command::cargo_bin("rails")?.arg(input).arg("--state").arg(&state).output()?)
+        };
+        let first = run(&monday)?;
+        let second = run(&tuesday)?;
+        // Replaying the files changes nothing.
+        let replay = run(&monday)?;
+        let second_replay = run(&tuesday)?;
+        std::fs::remove_file(&monday)?;
+        std::fs::remove_file(&tuesday)?;
+        std::fs::remove_file(&state)?;
+
+        assert!(first.status.success());
+        assert!(String::from_utf8(first.stdout)?.contains("1,10.0000,0.0000,10.0000,false"));
+        assert!(second.status.success());
+        assert!(String::from_utf8(second.stderr)?.contains("Skipped 0 duplicates"));
+        assert!(String::from_utf8(second.stdout)?.contains("1,0.0000,10.0000,10.0000,false"));
+        assert!(String::from_utf8(replay.stderr)?.contains("Skipped 3 duplicates"));
+        assert!(String::from_utf8(second_replay.stderr)?.contains("Skipped 1 duplicates"));
+        assert!(String::from_utf8(second_replay.stdout)?.contains("1,0.0000,10.0000,10.0000,false"));
+        Ok(())
+    }
+    #[test]
+    fn copies_are_replays_and_rewrites_are_not() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir();
+        let daily = dir.join(format!("rails-daily-{}.csv", std::process::id()));
+        let archived = dir.join(format!("rails-archived-{}.csv", std::process::id()));
+        let state = dir.join(format!("rails-daily-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state);
+        let run = |input: &std::path::Path| -> Result<std::process::Output, Box<dyn std::error::Error>> {
+            Ok(Command::cargo_bin("rails")?.arg(input).arg("--state").arg(&state).output()?)
+        };
+        std::fs::write(&daily, "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndispute, 1, 1,\nresolve, 1, 1,\n")?;
+        std::fs::copy(&daily, &archived)?;
+        let first = run(&daily)?;
+        // The archived copy is the same input read from another path.
+        let copy = run(&archived)?;
+        // The next day's file lands on the same path, with a row identical to the first day's.
+        std::fs::write(&daily, "type, client, tx, amount\ndeposit, 1, 2, 5.0\ndispute, 1, 1,\n")?;
+        let next = run(&daily)?;
+        std::fs::remove_file(&daily)?;
+        std::fs::remove_file(&archived)?;
+        std::fs::remove_file(&state)?;
+
+        assert!(first.status.success());
+        assert!(String::from_utf8(copy.stderr)?.contains("Skipped 3 duplicates"));
+        assert!(String::from_utf8(copy.stdout)?.contains("1,10.0000,0.0000,10.0000,false"));
+        assert!(String::from_utf8(next.stderr)?.contains("Skipped 0 duplicates"));
+        assert!(String::from_utf8(next.stdout)?.contains("1,5.0000,10.0000,15.0000,false"));
+        Ok(())
+    }
+    #[test]
+    fn appended_files_skip_the_rows_already_processed() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir();
+        let daily = dir.join(format!("rails-appended-{}.csv", std::process::id()));
+        let state = dir.join(format!("rails-appended-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state);
+        let run = |input: &std::path::Path| -> Result<std::process::Output, Box<dyn std::error::Error>> {
+            Ok(Command::cargo_bin("rails")?.arg(input).arg("--state").arg(&state).output()?)
+        };
+        let rows = "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndispute, 1, 1,\nresolve, 1, 1,\n";
+        std::fs::write(&daily, rows)?;
+        let first = run(&daily)?;
+        // The file is sent again with a row appended, disputing the deposit once more.
+        std::fs::write(&daily, format!("{}dispute, 1, 1,\n", rows))?;
+        let appended = run(&daily)?;
+        std::fs::remove_file(&daily)?;
+        std::fs::remove_file(&state)?;
+
+        assert!(first.status.success());
+        assert!(String::from_utf8(appended.stderr)?.contains("Skipped 3 duplicates"));
+        assert!(String::from_utf8(appended.stdout)?.contains("1,0.0000,10.0000,10.0000,false"));
+        Ok(())
+    }
+}
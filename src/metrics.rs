@@ -0,0 +1,456 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use crate::domain::{Account, AccountRepository, AccountVersion, CaseId, ClientId, DisputeCase, DisputeCaseState, DisputeHistoryEntry, DisputeHold, Amount, RepositoryError, Transaction, TransactionDispute, TransactionId, TransactionPage, TransactionQuery, TransactionRepository, TransactionStatus, UnitOfWork};
+use crate::events::{DomainEvent, OutboxEntry};
+
+/// Time a metrics client has to send its request line, and to take the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Histogram,
+}
+
+/// Name, help and type of a metric family, as exported.
+#[derive(Debug)]
+pub struct MetricDesc {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+}
+
+pub const ROWS_READ: MetricDesc = MetricDesc {
+    name: "rails_rows_read_total",
+    help: "Input rows read, including the ones that could not be read as a request.",
+    kind: MetricKind::Counter,
+};
+pub const BAD_ROWS: MetricDesc = MetricDesc {
+    name: "rails_bad_rows_total",
+    help: "Input rows that could not be read as a request.",
+    kind: MetricKind::Counter,
+};
+pub const REQUESTS: MetricDesc = MetricDesc {
+    name: "rails_requests_total",
+    help: "Requests per operation and outcome.",
+    kind: MetricKind::Counter,
+};
+pub const REJECTIONS: MetricDesc = MetricDesc {
+    name: "rails_rejections_total",
+    help: "Rejected requests per rule or hook.",
+    kind: MetricKind::Counter,
+};
+pub const REQUEST_DURATION: MetricDesc = MetricDesc {
+    name: "rails_request_duration_seconds",
+    help: "Time taken to process a request.",
+    kind: MetricKind::Histogram,
+};
+pub const REPOSITORY_DURATION: MetricDesc = MetricDesc {
+    name: "rails_repository_duration_seconds",
+    help: "Time taken by the repository calls.",
+    kind: MetricKind::Histogram,
+};
+pub const CAS_CONFLICTS: MetricDesc = MetricDesc {
+    name: "rails_cas_conflicts_total",
+    help: "Account updates refused because the account changed since it was read.",
+    kind: MetricKind::Counter,
+};
+
+type Labels = Vec<(String, String)>;
+
+enum Series {
+    Counter(u64),
+    Histogram { buckets: Vec<u64>, sum: f64, count: u64 },
+}
+
+struct Family {
+    desc: &'static MetricDesc,
+    series: BTreeMap<Labels, Series>,
+}
+
+/// Registry of the counters and histograms of a run. Handles are cheap to clone and share the
+/// same registry, so that the service, the repositories and the exporters see the same values.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    families: Arc<Mutex<BTreeMap<&'static str, Family>>>,
+}
+
+fn owned_labels(labels: &[(&str, &str)]) -> Labels {
+    labels.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_labels(labels: &[(String, String)], extra: Option<(&str, String)>) -> String {
+    let mut pairs: Vec<String> = labels.iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some((name, value)) = extra {
+        pairs.push(format!("{}=\"{}\"", name, value));
+    }
+    if pairs.is_empty() { String::new() } else { format!("{{{}}}", pairs.join(",")) }
+}
+
+impl Metrics {
+    fn update<F>(&self, desc: &'static MetricDesc, labels: &[(&str, &str)], f: F) where F: FnOnce(&mut Series) {
+        let mut families = self.families.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let family = families.entry(desc.name).or_insert_with(|| Family {
+            desc,
+            series: BTreeMap::new(),
+        });
+        let series = family.series.entry(owned_labels(labels)).or_insert_with(|| match desc.kind {
+            MetricKind::Counter => Series::Counter(0),
+            MetricKind::Histogram => Series::Histogram { buckets: vec![0; LATENCY_BUCKETS.len()], sum: 0.0, count: 0 },
+        });
+        f(series)
+    }
+
+    /// Adds to a counter.
+    pub fn inc(&self, desc: &'static MetricDesc, labels: &[(&str, &str)], by: u64) {
+        self.update(desc, labels, |series| {
+            if let Series::Counter(value) = series {
+                *value += by;
+            }
+        })
+    }
+
+    /// Records a value, in seconds, in a histogram.
+    pub fn observe(&self, desc: &'static MetricDesc, labels: &[(&str, &str)], seconds: f64) {
+        self.update(desc, labels, |series| {
+            if let Series::Histogram { buckets, sum, count } = series {
+                for (bucket, bound) in buckets.iter_mut().zip(LATENCY_BUCKETS) {
+                    if seconds <= bound {
+                        *bucket += 1;
+                    }
+                }
+                *sum += seconds;
+                *count += 1;
+            }
+        })
+    }
+
+    /// The value of a counter, 0 if it was never incremented.
+    pub fn counter(&self, desc: &'static MetricDesc, labels: &[(&str, &str)]) -> u64 {
+        let families = self.families.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match families.get(desc.name).and_then(|family| family.series.get(&owned_labels(labels))) {
+            Some(Series::Counter(value)) => *value,
+            _ => 0,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut text = String::new();
+        for family in families.values() {
+            let name = family.desc.name;
+            let kind = match family.desc.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Histogram => "histogram",
+            };
+            // Writing to a String cannot fail.
+            let _ = writeln!(text, "# HELP {} {}", name, family.desc.help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for (labels, series) in family.series.iter() {
+                match series {
+                    Series::Counter(value) => {
+                        let _ = writeln!(text, "{}{} {}", name, format_labels(labels, None), value);
+                    }
+                    Series::Histogram { buckets, sum, count } => {
+                        for (bucket, bound) in buckets.iter().zip(LATENCY_BUCKETS) {
+                            let _ = writeln!(text, "{}_bucket{} {}", name, format_labels(labels, Some(("le", bound.to_string()))), bucket);
+                        }
+                        let _ = writeln!(text, "{}_bucket{} {}", name, format_labels(labels, Some(("le", "+Inf".to_string()))), count);
+                        let _ = writeln!(text, "{}_sum{} {}", name, format_labels(labels, None), sum);
+                        let _ = writeln!(text, "{}_count{} {}", name, format_labels(labels, None), count);
+                    }
+                }
+            }
+        }
+        text
+    }
+}
+
+/// Serves the metrics at `/metrics` on a background thread, until the process exits. Every
+/// connection is answered on a thread of its own, so that a client slow to send its request does
+/// not hold back the scrapes of the others, and is dropped once REQUEST_TIMEOUT elapses.
+pub fn serve_metrics(listener: TcpListener, metrics: Metrics) -> Result<JoinHandle<()>, io::Error> {
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let metrics = metrics.clone();
+                let result = stream.and_then(|stream| {
+                    thread::Builder::new()
+                        .name("metrics-request".to_string())
+                        .spawn(move || {
+                            if let Err(err) = answer_metrics_request(stream, &metrics) {
+                                warn!(error = %err, "Metrics request failed");
+                            }
+                        })
+                });
+                if let Err(err) = result {
+                    warn!(error = %err, "Metrics request failed");
+                }
+            }
+        })
+}
+
+fn answer_metrics_request(mut stream: TcpStream, metrics: &Metrics) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    if path == "/metrics" {
+        let body = metrics.render();
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    } else {
+        write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    }
+}
+
+/// Account repository decorator timing every call and counting the CAS conflicts.
+pub struct MeteredAccountRepository<R> {
+    inner: R,
+    metrics: Metrics,
+}
+
+impl<R> MeteredAccountRepository<R> {
+    pub fn new(inner: R, metrics: Metrics) -> Self {
+        MeteredAccountRepository {
+            inner,
+            metrics,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn timed<T, F>(&mut self, method: &str, f: F) -> Result<T, RepositoryError> where F: FnOnce(&mut R) -> Result<T, RepositoryError> {
+        let started = Instant::now();
+        let result = f(&mut self.inner);
+        self.metrics.observe(&REPOSITORY_DURATION, &[("repository", "account"), ("method", method)], started.elapsed().as_secs_f64());
+        result
+    }
+}
+
+impl<R: UnitOfWork> UnitOfWork for MeteredAccountRepository<R> {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        self.timed("begin", |inner| inner.begin())
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        self.timed("commit", |inner| inner.commit())
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        self.timed("rollback", |inner| inner.rollback())
+    }
+}
+
+impl<R: AccountRepository> AccountRepository for MeteredAccountRepository<R> {
+    fn find_account(&mut self, client_id: &ClientId) -> Result<Option<Account>, RepositoryError> {
+        self.timed("find_account", |inner| inner.find_account(client_id))
+    }
+
+    fn open_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError> {
+        self.timed("open_account", |inner| inner.open_account(client_id))
+    }
+
+    fn update_account(&mut self, account: &Account, update: &Account) -> Result<(), RepositoryError> {
+        let result = self.timed("update_account", |inner| inner.update_account(account, update));
+        if let Err(RepositoryError::InconsistencyDetected(_)) = result {
+            self.metrics.inc(&CAS_CONFLICTS, &[], 1);
+        }
+        result
+    }
+
+    fn account_visitor<F>(&mut self, f: F) -> Result<(), RepositoryError> where F: FnMut(&Account) {
+        self.timed("account_visitor", |inner| inner.account_visitor(f))
+    }
+
+    fn append_account_version(&mut self, version: &AccountVersion) -> Result<(), RepositoryError> {
+        self.timed("append_account_version", |inner| inner.append_account_version(version))
+    }
+
+    fn find_account_versions(&mut self, client_id: &ClientId) -> Result<Vec<AccountVersion>, RepositoryError> {
+        self.timed("find_account_versions", |inner| inner.find_account_versions(client_id))
+    }
+}
+
+/// Transaction repository decorator timing every call.
+pub struct MeteredTransactionRepository<R> {
+    inner: R,
+    metrics: Metrics,
+}
+
+impl<R> MeteredTransactionRepository<R> {
+    pub fn new(inner: R, metrics: Metrics) -> Self {
+        MeteredTransactionRepository {
+            inner,
+            metrics,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn timed<T, F>(&mut self, method: &str, f: F) -> Result<T, RepositoryError> where F: FnOnce(&mut R) -> Result<T, RepositoryError> {
+        let started = Instant::now();
+        let result = f(&mut self.inner);
+        self.metrics.observe(&REPOSITORY_DURATION, &[("repository", "transaction"), ("method", method)], started.elapsed().as_secs_f64());
+        result
+    }
+}
+
+impl<R: UnitOfWork> UnitOfWork for MeteredTransactionRepository<R> {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        self.timed("begin", |inner| inner.begin())
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        self.timed("commit", |inner| inner.commit())
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        self.timed("rollback", |inner| inner.rollback())
+    }
+}
+
+impl<R: TransactionRepository> TransactionRepository for MeteredTransactionRepository<R> {
+    fn post_transaction(&mut self, transaction: &Transaction) -> Result<(), RepositoryError> {
+        self.timed("post_transaction", |inner| inner.post_transaction(transaction))
+    }
+
+    fn update_transaction_status(&mut self, transaction_id: &TransactionId, status: &TransactionStatus) -> Result<(), RepositoryError> {
+        self.timed("update_transaction_status", |inner| inner.update_transaction_status(transaction_id, status))
+    }
+
+    fn update_transaction_dispute(&mut self, transaction_id: &TransactionId, dispute: &TransactionDispute) -> Result<(), RepositoryError> {
+        self.timed("update_transaction_dispute", |inner| inner.update_transaction_dispute(transaction_id, dispute))
+    }
+
+    fn update_transaction_holds(&mut self, transaction_id: &TransactionId, holds: &[DisputeHold], charged_back: &Amount) -> Result<(), RepositoryError> {
+        self.timed("update_transaction_holds", |inner| inner.update_transaction_holds(transaction_id, holds, charged_back))
+    }
+
+    fn find_transaction_by_id(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, RepositoryError> {
+        self.timed("find_transaction_by_id", |inner| inner.find_transaction_by_id(transaction_id))
+    }
+
+    fn find_transactions(&mut self, query: &TransactionQuery) -> Result<TransactionPage, RepositoryError> {
+        self.timed("find_transactions", |inner| inner.find_transactions(query))
+    }
+
+    fn register_request(&mut self, key: &str) -> Result<(), RepositoryError> {
+        self.timed("register_request", |inner| inner.register_request(key))
+    }
+
+    fn post_dispute_case(&mut self, case: &DisputeCase) -> Result<(), RepositoryError> {
+        self.timed("post_dispute_case", |inner| inner.post_dispute_case(case))
+    }
+
+    fn update_dispute_case_state(&mut self, case_id: &CaseId, state: &DisputeCaseState) -> Result<(), RepositoryError> {
+        self.timed("update_dispute_case_state", |inner| inner.update_dispute_case_state(case_id, state))
+    }
+
+    fn find_dispute_case(&mut self, case_id: &CaseId) -> Result<Option<DisputeCase>, RepositoryError> {
+        self.timed("find_dispute_case", |inner| inner.find_dispute_case(case_id))
+    }
+
+    fn append_events(&mut self, events: &[DomainEvent]) -> Result<(), RepositoryError> {
+        self.timed("append_events", |inner| inner.append_events(events))
+    }
+
+    fn unpublished_events(&mut self, after: u64) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        self.timed("unpublished_events", |inner| inner.unpublished_events(after))
+    }
+
+    fn mark_events_published(&mut self, sequence: u64) -> Result<(), RepositoryError> {
+        self.timed("mark_events_published", |inner| inner.mark_events_published(sequence))
+    }
+
+    fn append_dispute_history(&mut self, entry: &DisputeHistoryEntry) -> Result<(), RepositoryError> {
+        self.timed("append_dispute_history", |inner| inner.append_dispute_history(entry))
+    }
+
+    fn find_dispute_history(&mut self, transaction_id: &TransactionId) -> Result<Vec<DisputeHistoryEntry>, RepositoryError> {
+        self.timed("find_dispute_history", |inner| inner.find_dispute_history(transaction_id))
+    }
+
+    fn dispute_history_visitor(&mut self, f: &mut dyn FnMut(&DisputeHistoryEntry)) -> Result<(), RepositoryError> {
+        self.timed("dispute_history_visitor", |inner| inner.dispute_history_visitor(f))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use crate::domain::{Account, AccountRepository, RepositoryError};
+    use crate::metrics::{serve_metrics, Metrics, MeteredAccountRepository, CAS_CONFLICTS, REJECTIONS, REPOSITORY_DURATION};
+    use crate::repository::InMemAccountRepository;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.inc(&REJECTIONS, &[("cause", "max_\"withdrawal\"")], 2);
+        metrics.observe(&REPOSITORY_DURATION, &[("repository", "account"), ("method", "find_account")], 0.003);
+        let text = metrics.render();
+        assert!(text.contains("# TYPE rails_rejections_total counter\n"));
+        assert!(text.contains("rails_rejections_total{cause=\"max_\\\"withdrawal\\\"\"} 2\n"));
+        assert!(text.contains("rails_repository_duration_seconds_bucket{repository=\"account\",method=\"find_account\",le=\"0.001\"} 0\n"));
+        assert!(text.contains("rails_repository_duration_seconds_bucket{repository=\"account\",method=\"find_account\",le=\"0.005\"} 1\n"));
+        assert!(text.contains("rails_repository_duration_seconds_count{repository=\"account\",method=\"find_account\"} 1\n"));
+    }
+
+    #[test]
+    fn counts_cas_conflicts() {
+        let metrics = Metrics::default();
+        let mut repo = MeteredAccountRepository::new(InMemAccountRepository::default(), metrics.clone());
+        let account = repo.open_account(&1).unwrap();
+        let stale = Account::from_parts(1, 5.into(), 0.into(), false, None, 1);
+        let result = repo.update_account(&stale, &account);
+        assert!(matches!(result, Err(RepositoryError::InconsistencyDetected(_))));
+        assert_eq!(1, metrics.counter(&CAS_CONFLICTS, &[]));
+    }
+
+    #[test]
+    fn serves_metrics_endpoint() {
+        let metrics = Metrics::default();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve_metrics(listener, metrics.clone()).unwrap();
+        metrics.inc(&CAS_CONFLICTS, &[], 3);
+        // A client that connects and sends nothing does not hold back the others.
+        let _silent = TcpStream::connect(address).unwrap();
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("rails_cas_conflicts_total 3\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+    }
+}
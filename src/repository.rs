@@ -1,103 +1,345 @@
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::collections::hash_map::Entry;
-use crate::domain::{AccountRepository, TransactionRepository, TransactionId, RepositoryError, ClientId, Account, Transaction, TransactionStatus, TransactionDispute};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use crate::domain::{AccountRepository, TransactionRepository, TransactionId, RepositoryError, ClientId, Account, Transaction, TransactionStatus, TransactionDispute, DisputeHistoryEntry, DisputeHold, Amount, CaseId, DisputeCase, DisputeCaseState, AccountVersion, TransactionQuery, TransactionPage, UnitOfWork};
+use crate::events::{DomainEvent, OutboxEntry};
+use serde::{Deserialize, Serialize};
 
+mod compact;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+mod kv;
+mod sharded;
+
+pub use compact::{CompactStoreConfig, CompactTransactionRepository, DEFAULT_MEMORY_BUDGET};
+pub use kv::{KvAccountRepository, KvStore, KvTransactionRepository};
+pub use sharded::{ShardedAccountRepository, ShardedTransactionRepository};
+
+fn unit_already_open() -> RepositoryError {
+    RepositoryError::InconsistencyDetected("a unit of work is already open".to_string())
+}
+
+/// Writes of the open unit of work of the transaction repository.
+#[derive(Default)]
+struct TransactionStaging {
+    transactions_by_id: HashMap<TransactionId, Transaction>,
+    dispute_history: Vec<DisputeHistoryEntry>,
+    dispute_cases_by_id: HashMap<CaseId, DisputeCase>,
+    processed_requests: HashSet<String>,
+    outbox: Vec<OutboxEntry>,
+    /// The next event sequence when the unit began, restored on rollback.
+    next_event_sequence: u64,
+}
+
+#[derive(Default)]
 pub struct InMemTransactionRepository {
     transactions_by_id: HashMap<TransactionId, Transaction>,
+    dispute_history_by_id: HashMap<TransactionId, Vec<DisputeHistoryEntry>>,
+    dispute_cases_by_id: HashMap<CaseId, DisputeCase>,
+    processed_requests: HashSet<String>,
+    outbox: VecDeque<OutboxEntry>,
+    next_event_sequence: u64,
+    staging: Option<TransactionStaging>,
 }
 
 impl InMemTransactionRepository {
+    /// The transaction as seen by the open unit of work, if any.
+    fn transaction(&self, transaction_id: &TransactionId) -> Option<&Transaction> {
+        self.staging.as_ref()
+            .and_then(|staging| staging.transactions_by_id.get(transaction_id))
+            .or_else(|| self.transactions_by_id.get(transaction_id))
+    }
+
+    /// Changes a transaction, in the open unit of work if any.
+    fn update_transaction<F>(&mut self, transaction_id: &TransactionId, f: F) -> Result<(), RepositoryError> where F: FnOnce(&mut Transaction) {
+        let transactions_by_id = match self.staging.as_mut() {
+            None => &mut self.transactions_by_id,
+            Some(staging) => {
+                if let Some(committed) = self.transactions_by_id.get(transaction_id) {
+                    staging.transactions_by_id.entry(*transaction_id).or_insert_with(|| committed.clone());
+                }
+                &mut staging.transactions_by_id
+            }
+        };
+        match transactions_by_id.get_mut(transaction_id) {
+            None => Err(RepositoryError::EntityNotFound(transaction_id.to_string())),
+            Some(transaction) => {
+                f(transaction);
+                Ok(())
+            }
+        }
+    }
+
+    /// The dispute case as seen by the open unit of work, if any.
+    fn dispute_case(&self, case_id: &CaseId) -> Option<&DisputeCase> {
+        self.staging.as_ref()
+            .and_then(|staging| staging.dispute_cases_by_id.get(case_id))
+            .or_else(|| self.dispute_cases_by_id.get(case_id))
+    }
 }
 
-impl Default for InMemTransactionRepository {
-    fn default() -> Self {
-        InMemTransactionRepository{
-            transactions_by_id: HashMap::default()
+impl UnitOfWork for InMemTransactionRepository {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        if self.staging.is_some() {
+            return Err(unit_already_open());
         }
+        self.staging = Some(TransactionStaging {
+            next_event_sequence: self.next_event_sequence,
+            ..TransactionStaging::default()
+        });
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        if let Some(staging) = self.staging.take() {
+            self.transactions_by_id.extend(staging.transactions_by_id);
+            staging.dispute_history.into_iter().for_each(|entry| {
+                self.dispute_history_by_id.entry(entry.transaction_id()).or_default().push(entry)
+            });
+            self.dispute_cases_by_id.extend(staging.dispute_cases_by_id);
+            self.processed_requests.extend(staging.processed_requests);
+            self.outbox.extend(staging.outbox);
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        if let Some(staging) = self.staging.take() {
+            self.next_event_sequence = staging.next_event_sequence;
+        }
+        Ok(())
     }
 }
 
 impl TransactionRepository for InMemTransactionRepository {
     fn post_transaction(&mut self, transaction: &Transaction) -> Result<(), RepositoryError> {
-        match self.transactions_by_id.entry(transaction.transaction_id()) {
-            Entry::Occupied(o) => {
-                Err(RepositoryError::EntityAlreadyExists(o.key().to_string()))
-            }
-            Entry::Vacant(v) => {
-                v.insert(transaction.to_owned());
-                Ok(())
-            }
+        if self.transaction(&transaction.transaction_id()).is_some() {
+            return Err(RepositoryError::EntityAlreadyExists(transaction.transaction_id().to_string()));
         }
+        match self.staging.as_mut() {
+            None => self.transactions_by_id.insert(transaction.transaction_id(), transaction.to_owned()),
+            Some(staging) => staging.transactions_by_id.insert(transaction.transaction_id(), transaction.to_owned()),
+        };
+        Ok(())
     }
 
     fn update_transaction_status(&mut self, transaction_id: &TransactionId, status: &TransactionStatus) -> Result<(), RepositoryError> {
-        match self.transactions_by_id.entry(transaction_id.to_owned()) {
-            Entry::Vacant(_) => Err(RepositoryError::EntityNotFound(transaction_id.to_string())),
+        self.update_transaction(transaction_id, |tx| tx.set_status(status.to_owned()))
+    }
+
+    fn update_transaction_dispute(&mut self, transaction_id: &TransactionId, dispute: &TransactionDispute) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| tx.set_dispute(dispute.to_owned()))
+    }
+
+    fn update_transaction_holds(&mut self, transaction_id: &TransactionId, holds: &[DisputeHold], charged_back: &Amount) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| tx.set_holds(holds.to_vec(), charged_back.to_owned()))
+    }
+
+    fn find_transaction_by_id(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, RepositoryError> {
+       Ok(self.transaction(transaction_id).cloned())
+    }
+
+    fn find_transactions(&mut self, query: &TransactionQuery) -> Result<TransactionPage, RepositoryError> {
+        let staged = self.staging.as_ref().map(|staging| &staging.transactions_by_id);
+        let mut selected: Vec<&Transaction> = self.transactions_by_id.values()
+            .filter(|transaction| staged.is_none_or(|staged| !staged.contains_key(&transaction.transaction_id())))
+            .chain(staged.into_iter().flat_map(|staged| staged.values()))
+            .filter(|transaction| query.matches(transaction))
+            .collect();
+        selected.sort_by_key(|transaction| transaction.transaction_id());
+        let total = selected.len();
+        let transactions = selected.into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+        Ok(TransactionPage {
+            transactions,
+            total,
+        })
+    }
+
+    fn register_request(&mut self, key: &str) -> Result<(), RepositoryError> {
+        let inserted = match self.staging.as_mut() {
+            None => self.processed_requests.insert(key.to_owned()),
+            Some(staging) => !self.processed_requests.contains(key) && staging.processed_requests.insert(key.to_owned()),
+        };
+        match inserted {
+            true => Ok(()),
+            false => Err(RepositoryError::EntityAlreadyExists(key.to_owned())),
+        }
+    }
+
+    fn post_dispute_case(&mut self, case: &DisputeCase) -> Result<(), RepositoryError> {
+        if self.dispute_case(&case.case_id()).is_some() {
+            return Err(RepositoryError::EntityAlreadyExists(format!("case {}", case.case_id())));
+        }
+        match self.staging.as_mut() {
+            None => self.dispute_cases_by_id.insert(case.case_id(), case.to_owned()),
+            Some(staging) => staging.dispute_cases_by_id.insert(case.case_id(), case.to_owned()),
+        };
+        Ok(())
+    }
+
+    fn update_dispute_case_state(&mut self, case_id: &CaseId, state: &DisputeCaseState) -> Result<(), RepositoryError> {
+        let dispute_cases_by_id = match self.staging.as_mut() {
+            None => &mut self.dispute_cases_by_id,
+            Some(staging) => {
+                if let Some(committed) = self.dispute_cases_by_id.get(case_id) {
+                    staging.dispute_cases_by_id.entry(*case_id).or_insert_with(|| committed.clone());
+                }
+                &mut staging.dispute_cases_by_id
+            }
+        };
+        match dispute_cases_by_id.entry(case_id.to_owned()) {
+            Entry::Vacant(_) => Err(RepositoryError::EntityNotFound(format!("case {}", case_id))),
             Entry::Occupied(mut o) => {
-                let tx = o.get_mut();
-                tx.set_status(status.to_owned());
+                o.get_mut().set_state(state.to_owned());
                 Ok(())
             }
         }
     }
 
-    fn update_transaction_dispute(&mut self, transaction_id: &TransactionId, dispute: &TransactionDispute) -> Result<(), RepositoryError> {
-        match self.transactions_by_id.entry(transaction_id.to_owned()) {
-            Entry::Vacant(_) => Err(RepositoryError::EntityNotFound(transaction_id.to_string())),
-            Entry::Occupied(mut o) => {
-                let tx = o.get_mut();
-                tx.set_dispute(dispute.to_owned());
-                Ok(())
+    fn find_dispute_case(&mut self, case_id: &CaseId) -> Result<Option<DisputeCase>, RepositoryError> {
+        Ok(self.dispute_case(case_id).cloned())
+    }
+
+    fn append_events(&mut self, events: &[DomainEvent]) -> Result<(), RepositoryError> {
+        for event in events {
+            self.next_event_sequence += 1;
+            let entry = OutboxEntry::new(self.next_event_sequence, event.clone());
+            match self.staging.as_mut() {
+                None => self.outbox.push_back(entry),
+                Some(staging) => staging.outbox.push(entry),
             }
         }
+        Ok(())
     }
 
-    fn find_transaction_by_id(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, RepositoryError> {
-       Ok(self.transactions_by_id.get(&transaction_id).cloned())
+    /// The committed events only, the events of an open unit of work are not relayed until then.
+    fn unpublished_events(&mut self, after: u64) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        let start = self.outbox.partition_point(|entry| entry.sequence() <= after);
+        Ok(self.outbox.range(start..).cloned().collect())
+    }
+
+    fn mark_events_published(&mut self, sequence: u64) -> Result<(), RepositoryError> {
+        while self.outbox.front().is_some_and(|entry| entry.sequence() <= sequence) {
+            self.outbox.pop_front();
+        }
+        Ok(())
+    }
+
+    fn append_dispute_history(&mut self, entry: &DisputeHistoryEntry) -> Result<(), RepositoryError> {
+        if self.transaction(&entry.transaction_id()).is_none() {
+            return Err(RepositoryError::EntityNotFound(entry.transaction_id().to_string()));
+        }
+        match self.staging.as_mut() {
+            None => self.dispute_history_by_id.entry(entry.transaction_id()).or_default().push(entry.clone()),
+            Some(staging) => staging.dispute_history.push(entry.clone()),
+        }
+        Ok(())
+    }
+
+    fn find_dispute_history(&mut self, transaction_id: &TransactionId) -> Result<Vec<DisputeHistoryEntry>, RepositoryError> {
+        let mut history = self.dispute_history_by_id.get(transaction_id).cloned().unwrap_or_default();
+        if let Some(staging) = self.staging.as_ref() {
+            history.extend(staging.dispute_history.iter().filter(|entry| entry.transaction_id() == *transaction_id).cloned());
+        }
+        Ok(history)
     }
+
+    fn dispute_history_visitor(&mut self, f: &mut dyn FnMut(&DisputeHistoryEntry)) -> Result<(), RepositoryError> {
+        self.dispute_history_by_id.values().flatten().for_each(|entry| {f(entry)});
+        if let Some(staging) = self.staging.as_ref() {
+            staging.dispute_history.iter().for_each(|entry| {f(entry)});
+        }
+        Ok(())
+    }
+}
+
+/// Writes of the open unit of work of the account repository.
+#[derive(Default)]
+struct AccountStaging {
+    accounts_by_client_id: HashMap<ClientId, Account>,
+    account_versions: Vec<AccountVersion>,
 }
 
+#[derive(Default)]
 pub struct InMemAccountRepository {
     accounts_by_client_id: HashMap<ClientId, Account>,
+    account_versions_by_client_id: HashMap<ClientId, Vec<AccountVersion>>,
+    staging: Option<AccountStaging>,
 }
 
 impl InMemAccountRepository {
 }
 
-impl Default for InMemAccountRepository {
-    fn default() -> Self {
-        InMemAccountRepository {
-            accounts_by_client_id: HashMap::default()
+impl UnitOfWork for InMemAccountRepository {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        if self.staging.is_some() {
+            return Err(unit_already_open());
         }
+        self.staging = Some(AccountStaging::default());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        if let Some(staging) = self.staging.take() {
+            self.accounts_by_client_id.extend(staging.accounts_by_client_id);
+            staging.account_versions.into_iter().for_each(|version| {
+                self.account_versions_by_client_id.entry(version.account().client_id()).or_default().push(version)
+            });
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        self.staging = None;
+        Ok(())
     }
 }
 
 impl AccountRepository for InMemAccountRepository {
 
-    fn get_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError> {
-        match self.accounts_by_client_id.entry(client_id.clone()) {
-            Entry::Occupied(occupied) => {
-                Ok(occupied.get().clone())
-            }
-            Entry::Vacant(vacant) => {
-                Ok(vacant.insert(Account::new(client_id.clone())).clone())
-            }
+    fn find_account(&mut self, client_id: &ClientId) -> Result<Option<Account>, RepositoryError> {
+        Ok(self.staging.as_ref()
+            .and_then(|staging| staging.accounts_by_client_id.get(client_id))
+            .or_else(|| self.accounts_by_client_id.get(client_id))
+            .cloned())
+    }
+
+    fn open_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError> {
+        if self.find_account(client_id)?.is_some() {
+            return Err(RepositoryError::EntityAlreadyExists(format!("account {}", client_id)));
         }
+        let account = Account::new(*client_id);
+        match self.staging.as_mut() {
+            None => self.accounts_by_client_id.insert(*client_id, account.clone()),
+            Some(staging) => staging.accounts_by_client_id.insert(*client_id, account.clone()),
+        };
+        Ok(account)
     }
 
     fn update_account(&mut self, account: &Account, update: &Account) -> Result<(), RepositoryError> {
-        match self.accounts_by_client_id.entry(account.client_id()) {
-            Entry::Occupied(mut o) => {
+        let staged = self.staging.as_mut().map(|staging| &mut staging.accounts_by_client_id);
+        let current = staged.as_ref()
+            .and_then(|staged| staged.get(&account.client_id()))
+            .or_else(|| self.accounts_by_client_id.get(&account.client_id()));
+        match current {
+            Some(current) => {
                 // Do a CAS operation on what we believe is the last state of the account and what
                 // we got from the repo.
-               if o.get() == account {
-                   o.insert(update.clone());
+               if current.version() == account.version() {
+                   match staged {
+                       None => self.accounts_by_client_id.insert(account.client_id(), update.clone()),
+                       Some(staged) => staged.insert(account.client_id(), update.clone()),
+                   };
                    Ok(())
                } else {
                    Err(RepositoryError::InconsistencyDetected(format!("{}", account.client_id())))
                }
             }
-            Entry::Vacant(_) => {
+            None => {
                 Err(RepositoryError::EntityNotFound(format!("Account cannot be updated, it does not exist. {}", account.client_id())))
             }
         }
@@ -105,21 +347,363 @@ impl AccountRepository for InMemAccountRepository {
     }
 
     fn account_visitor<F>(&mut self, mut f: F) -> Result<(), RepositoryError> where F: FnMut(&Account) {
-        self.accounts_by_client_id.values().for_each(|account| {f(account)});
+        let staged = self.staging.as_ref().map(|staging| &staging.accounts_by_client_id);
+        self.accounts_by_client_id.values()
+            .filter(|account| staged.is_none_or(|staged| !staged.contains_key(&account.client_id())))
+            .chain(staged.into_iter().flat_map(|staged| staged.values()))
+            .for_each(|account| {f(account)});
         Ok(())
     }
+
+    fn append_account_version(&mut self, version: &AccountVersion) -> Result<(), RepositoryError> {
+        match self.staging.as_mut() {
+            None => self.account_versions_by_client_id.entry(version.account().client_id()).or_default().push(version.clone()),
+            Some(staging) => staging.account_versions.push(version.clone()),
+        }
+        Ok(())
+    }
+
+    fn find_account_versions(&mut self, client_id: &ClientId) -> Result<Vec<AccountVersion>, RepositoryError> {
+        let mut versions = self.account_versions_by_client_id.get(client_id).cloned().unwrap_or_default();
+        if let Some(staging) = self.staging.as_ref() {
+            versions.extend(staging.account_versions.iter().filter(|version| version.account().client_id() == *client_id).cloned());
+        }
+        Ok(versions)
+    }
+}
+
+struct Shared<R> {
+    repository: Mutex<R>,
+    /// Whether a handle has a unit of work open.
+    unit_open: Mutex<bool>,
+    unit_closed: Condvar,
+}
+
+/// Handle sharing a repository between several writers, e.g. services running on different
+/// threads. Every call locks the repository. A unit of work holds the repository for the handle
+/// until committed or rolled back, the other handles wait to begin theirs or to call outside one,
+/// so that no writer commits or rolls back the writes staged by another.
+///
+/// This is a coarse global lock: all the handles wait on one unit at a time, whatever accounts
+/// they touch, so writers sharing it run one after the other. It is a fallback for repositories
+/// without concurrency of their own; writers that should run concurrently use the sharded
+/// repositories instead.
+pub struct SharedRepository<R> {
+    shared: Arc<Shared<R>>,
+    in_unit: bool,
+}
+
+impl<R> SharedRepository<R> {
+    pub fn new(inner: R) -> Self {
+        SharedRepository {
+            shared: Arc::new(Shared {
+                repository: Mutex::new(inner),
+                unit_open: Mutex::new(false),
+                unit_closed: Condvar::new(),
+            }),
+            in_unit: false,
+        }
+    }
+
+    /// The repository, if this is the last handle to it.
+    pub fn into_inner(self) -> Option<R> {
+        let shared = self.shared.clone();
+        drop(self);
+        Arc::try_unwrap(shared).ok()
+            .map(|shared| shared.repository.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, R> {
+        self.shared.repository.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Runs a call outside a unit of work once no other handle has one open, so that the call
+    /// neither reads the writes another unit staged nor gets its own writes rolled back with them.
+    fn call<T>(&mut self, f: impl FnOnce(&mut R) -> T) -> T {
+        if self.in_unit {
+            return f(&mut self.lock());
+        }
+        self.acquire_unit();
+        let result = f(&mut self.lock());
+        self.release_unit();
+        result
+    }
+
+    fn acquire_unit(&mut self) {
+        let mut unit_open = self.shared.unit_open.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        while *unit_open {
+            unit_open = self.shared.unit_closed.wait(unit_open).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        *unit_open = true;
+        self.in_unit = true;
+    }
+
+    fn release_unit(&mut self) {
+        if self.in_unit {
+            self.in_unit = false;
+            *self.shared.unit_open.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = false;
+            self.shared.unit_closed.notify_one();
+        }
+    }
+}
+
+impl<R> Clone for SharedRepository<R> {
+    fn clone(&self) -> Self {
+        SharedRepository {
+            shared: self.shared.clone(),
+            in_unit: false,
+        }
+    }
+}
+
+impl<R> Drop for SharedRepository<R> {
+    fn drop(&mut self) {
+        self.release_unit();
+    }
+}
+
+impl<R: UnitOfWork> UnitOfWork for SharedRepository<R> {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        if self.in_unit {
+            return Err(unit_already_open());
+        }
+        self.acquire_unit();
+        let result = self.lock().begin();
+        if result.is_err() {
+            self.release_unit();
+        }
+        result
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        let result = self.lock().commit();
+        self.release_unit();
+        result
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        let result = self.lock().rollback();
+        self.release_unit();
+        result
+    }
+}
+
+impl<R: AccountRepository> AccountRepository for SharedRepository<R> {
+    fn find_account(&mut self, client_id: &ClientId) -> Result<Option<Account>, RepositoryError> {
+        self.call(|repository| repository.find_account(client_id))
+    }
+
+    fn open_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError> {
+        self.call(|repository| repository.open_account(client_id))
+    }
+
+    fn update_account(&mut self, account: &Account, update: &Account) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.update_account(account, update))
+    }
+
+    fn account_visitor<F>(&mut self, f: F) -> Result<(), RepositoryError> where F: FnMut(&Account) {
+        self.call(|repository| repository.account_visitor(f))
+    }
+
+    fn append_account_version(&mut self, version: &AccountVersion) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.append_account_version(version))
+    }
+
+    fn find_account_versions(&mut self, client_id: &ClientId) -> Result<Vec<AccountVersion>, RepositoryError> {
+        self.call(|repository| repository.find_account_versions(client_id))
+    }
+}
+
+impl<R: TransactionRepository> TransactionRepository for SharedRepository<R> {
+    fn post_transaction(&mut self, transaction: &Transaction) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.post_transaction(transaction))
+    }
+
+    fn update_transaction_status(&mut self, transaction_id: &TransactionId, status: &TransactionStatus) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.update_transaction_status(transaction_id, status))
+    }
+
+    fn update_transaction_dispute(&mut self, transaction_id: &TransactionId, dispute: &TransactionDispute) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.update_transaction_dispute(transaction_id, dispute))
+    }
+
+    fn update_transaction_holds(&mut self, transaction_id: &TransactionId, holds: &[DisputeHold], charged_back: &Amount) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.update_transaction_holds(transaction_id, holds, charged_back))
+    }
+
+    fn find_transaction_by_id(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, RepositoryError> {
+        self.call(|repository| repository.find_transaction_by_id(transaction_id))
+    }
+
+    fn find_transactions(&mut self, query: &TransactionQuery) -> Result<TransactionPage, RepositoryError> {
+        self.call(|repository| repository.find_transactions(query))
+    }
+
+    fn register_request(&mut self, key: &str) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.register_request(key))
+    }
+
+    fn post_dispute_case(&mut self, case: &DisputeCase) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.post_dispute_case(case))
+    }
+
+    fn update_dispute_case_state(&mut self, case_id: &CaseId, state: &DisputeCaseState) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.update_dispute_case_state(case_id, state))
+    }
+
+    fn find_dispute_case(&mut self, case_id: &CaseId) -> Result<Option<DisputeCase>, RepositoryError> {
+        self.call(|repository| repository.find_dispute_case(case_id))
+    }
+
+    fn append_events(&mut self, events: &[DomainEvent]) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.append_events(events))
+    }
+
+    fn unpublished_events(&mut self, after: u64) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        self.call(|repository| repository.unpublished_events(after))
+    }
+
+    fn mark_events_published(&mut self, sequence: u64) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.mark_events_published(sequence))
+    }
+
+    fn append_dispute_history(&mut self, entry: &DisputeHistoryEntry) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.append_dispute_history(entry))
+    }
+
+    fn find_dispute_history(&mut self, transaction_id: &TransactionId) -> Result<Vec<DisputeHistoryEntry>, RepositoryError> {
+        self.call(|repository| repository.find_dispute_history(transaction_id))
+    }
+
+    fn dispute_history_visitor(&mut self, f: &mut dyn FnMut(&DisputeHistoryEntry)) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.dispute_history_visitor(f))
+    }
+}
+
+/// Serializable image of the in-memory repositories, used to persist their state between runs.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InMemSnapshot {
+    accounts: Vec<Account>,
+    account_versions: Vec<AccountVersion>,
+    transactions: Vec<Transaction>,
+    dispute_cases: Vec<DisputeCase>,
+    dispute_history: Vec<DisputeHistoryEntry>,
+    processed_requests: Vec<String>,
+    outbox: Vec<OutboxEntry>,
+    next_event_sequence: u64,
+}
+
+impl InMemSnapshot {
+    pub fn capture(account_repository: &InMemAccountRepository, transaction_repository: &InMemTransactionRepository) -> Self {
+        InMemSnapshot {
+            accounts: account_repository.accounts_by_client_id.values().cloned().collect(),
+            account_versions: account_repository.account_versions_by_client_id.values().flatten().cloned().collect(),
+            transactions: transaction_repository.transactions_by_id.values().cloned().collect(),
+            dispute_cases: transaction_repository.dispute_cases_by_id.values().cloned().collect(),
+            dispute_history: transaction_repository.dispute_history_by_id.values().flatten().cloned().collect(),
+            processed_requests: transaction_repository.processed_requests.iter().cloned().collect(),
+            outbox: transaction_repository.outbox.iter().cloned().collect(),
+            next_event_sequence: transaction_repository.next_event_sequence,
+        }
+    }
+
+    pub fn restore(self) -> (InMemAccountRepository, InMemTransactionRepository) {
+        let mut account_versions_by_client_id: HashMap<ClientId, Vec<AccountVersion>> = HashMap::new();
+        self.account_versions.into_iter().for_each(|version| {
+            account_versions_by_client_id.entry(version.account().client_id()).or_default().push(version)
+        });
+        let account_repository = InMemAccountRepository {
+            accounts_by_client_id: self.accounts.into_iter()
+                .map(|account| (account.client_id(), account))
+                .collect(),
+            account_versions_by_client_id,
+            staging: None,
+        };
+        let mut dispute_history_by_id: HashMap<TransactionId, Vec<DisputeHistoryEntry>> = HashMap::new();
+        self.dispute_history.into_iter().for_each(|entry| {
+            dispute_history_by_id.entry(entry.transaction_id()).or_default().push(entry)
+        });
+        let transaction_repository = InMemTransactionRepository {
+            transactions_by_id: self.transactions.into_iter()
+                .map(|transaction| (transaction.transaction_id(), transaction))
+                .collect(),
+            dispute_history_by_id,
+            dispute_cases_by_id: self.dispute_cases.into_iter()
+                .map(|case| (case.case_id(), case))
+                .collect(),
+            processed_requests: self.processed_requests.into_iter().collect(),
+            outbox: self.outbox.into_iter().collect(),
+            next_event_sequence: self.next_event_sequence,
+            staging: None,
+        };
+        (account_repository, transaction_repository)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::domain::{AccountRepository, ClientId};
-    use crate::InMemAccountRepository;
+    use bigdecimal::BigDecimal;
+    use crate::domain::{Account, AccountRepository, ClientId, Operation, TransactionRepository, TransactionRequest, TransactionStatus, UnitOfWork};
+    use crate::repository::{conformance, InMemAccountRepository, InMemTransactionRepository, SharedRepository};
 
     #[test]
-    fn account_created_on_get() {
+    fn conforms() {
+        conformance::account_repository(InMemAccountRepository::default).unwrap();
+        conformance::transaction_repository(InMemTransactionRepository::default).unwrap();
+        conformance::account_repository(|| SharedRepository::new(InMemAccountRepository::default())).unwrap();
+        conformance::transaction_repository(|| SharedRepository::new(InMemTransactionRepository::default())).unwrap();
+    }
+
+    #[test]
+    fn shared_repositories_serialize_writers() {
+        let accounts = SharedRepository::new(InMemAccountRepository::default());
+        conformance::concurrent_account_writers(vec![accounts.clone(), accounts.clone(), accounts.clone(), accounts]).unwrap();
+        let transactions = SharedRepository::new(InMemTransactionRepository::default());
+        conformance::concurrent_transaction_writers(vec![transactions.clone(), transactions.clone(), transactions.clone(), transactions]).unwrap();
+        let transactions = SharedRepository::new(InMemTransactionRepository::default());
+        conformance::writers_outside_rolled_back_units(vec![transactions.clone(), transactions]).unwrap();
+    }
+
+    #[test]
+    fn account_opened_explicitly() {
         let mut repo = InMemAccountRepository::default();
         let client_id : ClientId = 1;
-        let result = repo.get_account(&client_id);
+        assert!(repo.find_account(&client_id).unwrap().is_none());
+        let result = repo.open_account(&client_id);
         assert_eq!(result.unwrap().client_id(), 1);
+        assert!(repo.open_account(&client_id).is_err());
+        assert_eq!(repo.find_account(&client_id).unwrap().map(|account| account.client_id()), Some(1));
+    }
+
+    #[test]
+    fn unit_of_work_stages_writes() {
+        let mut accounts = InMemAccountRepository::default();
+        let mut transactions = InMemTransactionRepository::default();
+        let deposit = TransactionRequest::builder(Operation::Deposit, 1, 1).amount(BigDecimal::from(5)).build();
+
+        accounts.begin().unwrap();
+        transactions.begin().unwrap();
+        assert!(transactions.begin().is_err());
+        let account = accounts.open_account(&1).unwrap();
+        let update = Account::from_parts(1, BigDecimal::from(5), BigDecimal::from(0), false, Some(1), 1);
+        accounts.update_account(&account, &update).unwrap();
+        transactions.post_transaction(&deposit.valid_transaction().unwrap()).unwrap();
+        transactions.update_transaction_status(&1, &TransactionStatus::Applied).unwrap();
+        // The unit sees its own writes.
+        assert_eq!(Some(BigDecimal::from(5)), accounts.find_account(&1).unwrap().map(|account| account.available()));
+        assert!(matches!(transactions.find_transaction_by_id(&1).unwrap().map(|tx| tx.status().clone()), Some(TransactionStatus::Applied)));
+        accounts.rollback().unwrap();
+        transactions.rollback().unwrap();
+
+        let mut visited = 0;
+        accounts.account_visitor(|_| visited += 1).unwrap();
+        assert_eq!(0, visited);
+        assert!(transactions.find_transaction_by_id(&1).unwrap().is_none());
+
+        transactions.begin().unwrap();
+        transactions.post_transaction(&deposit.valid_transaction().unwrap()).unwrap();
+        transactions.commit().unwrap();
+        assert_eq!(1, transactions.find_transactions(&Default::default()).unwrap().total);
     }
-}
\ No newline at end of file
+}
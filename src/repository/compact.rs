@@ -0,0 +1,824 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::Display;
+use std::mem::{size_of, take};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use bigdecimal::num_bigint::BigInt;
+use redb::{Database, Durability, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::domain::{Amount, CaseId, ClientId, DisputeCase, DisputeCaseState, DisputeHistoryEntry, DisputeHold, Operation, RejectionReason, RepositoryError, RowNumber, Timestamp, Transaction, TransactionDispute, TransactionId, TransactionPage, TransactionQuery, TransactionRepository, TransactionStatus, UnitOfWork};
+use crate::events::{DomainEvent, OutboxEntry};
+use super::unit_already_open;
+
+/// Default number of bytes of transactions and requests kept in memory before spilling to disk.
+pub const DEFAULT_MEMORY_BUDGET: usize = 256 * 1024 * 1024;
+
+/// Spilled transactions, encoded with bincode.
+const TRANSACTIONS: TableDefinition<TransactionId, &[u8]> = TableDefinition::new("transactions");
+/// Spilled transactions by timestamp, to find the ones past the dispute window.
+const TRANSACTIONS_BY_TIME: TableDefinition<(Timestamp, TransactionId), ()> = TableDefinition::new("transactions_by_time");
+/// Hashes of the idempotency keys of the spilled requests.
+const REQUESTS: TableDefinition<u128, ()> = TableDefinition::new("requests");
+
+/// Bytes taken in memory by a transaction, the heap of its disputes and the map nodes aside.
+const TRANSACTION_SIZE: usize = size_of::<TransactionId>() + size_of::<CompactTransaction>();
+/// Bytes taken in memory by a request, including the spare capacity of the set.
+const REQUEST_SIZE: usize = 2 * size_of::<u128>();
+
+/// Spill files created by the process, to name them apart.
+static SPILL_FILES: AtomicU64 = AtomicU64::new(0);
+
+fn storage_error<E: Display>(error: E) -> RepositoryError {
+    RepositoryError::StorageError(error.to_string())
+}
+
+/// Tunables of the compact transaction store.
+#[derive(Debug, Clone)]
+pub struct CompactStoreConfig {
+    /// Approximate number of bytes of transactions and requests kept in memory. Beyond it they are
+    /// spilled to disk.
+    pub memory_budget: usize,
+    /// Directory of the spill file, the temporary directory if None. The file is removed when the
+    /// store is dropped.
+    pub spill_dir: Option<PathBuf>,
+    /// Seconds after which a transaction can no longer be disputed, measured from the latest
+    /// transaction posted. Older transactions are no longer found, and are dropped on the next
+    /// spill unless under dispute. Every transaction is kept if None.
+    pub dispute_window: Option<Timestamp>,
+}
+
+impl Default for CompactStoreConfig {
+    fn default() -> Self {
+        CompactStoreConfig {
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            spill_dir: None,
+            dispute_window: None,
+        }
+    }
+}
+
+/// An amount as its unscaled digits and its scale.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct CompactAmount(i128, i64);
+
+impl CompactAmount {
+    fn compact(amount: &Amount) -> Result<Self, RepositoryError> {
+        let (digits, scale) = amount.as_bigint_and_exponent();
+        digits.to_i128()
+            .map(|digits| CompactAmount(digits, scale))
+            .ok_or_else(|| RepositoryError::StorageError(format!("Amount out of range {}", amount)))
+    }
+
+    fn amount(self) -> Amount {
+        BigDecimal::new(BigInt::from(self.0), self.1)
+    }
+}
+
+/// Status of a stored transaction, the rejection reason boxed as few transactions are rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum CompactStatus {
+    Pending,
+    Applied,
+    Error,
+    Rejected(Box<RejectionReason>),
+}
+
+impl From<&TransactionStatus> for CompactStatus {
+    fn from(status: &TransactionStatus) -> Self {
+        match status {
+            TransactionStatus::Pending => CompactStatus::Pending,
+            TransactionStatus::Applied => CompactStatus::Applied,
+            TransactionStatus::Error => CompactStatus::Error,
+            TransactionStatus::Rejected(reason) => CompactStatus::Rejected(Box::new(reason.clone())),
+        }
+    }
+}
+
+impl From<&CompactStatus> for TransactionStatus {
+    fn from(status: &CompactStatus) -> Self {
+        match status {
+            CompactStatus::Pending => TransactionStatus::Pending,
+            CompactStatus::Applied => TransactionStatus::Applied,
+            CompactStatus::Error => TransactionStatus::Error,
+            CompactStatus::Rejected(reason) => TransactionStatus::Rejected(reason.as_ref().clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CompactHold {
+    held: CompactAmount,
+    cycle: u32,
+    case_id: Option<CaseId>,
+    row: Option<RowNumber>,
+    opened_at: Timestamp,
+}
+
+/// The holds of a transaction disputed at least once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CompactHolds {
+    holds: Vec<CompactHold>,
+    charged_back: CompactAmount,
+}
+
+/// What the store keeps of a transaction, the fields disputes need. The case id and the input row of
+/// the transaction are dropped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CompactTransaction {
+    operation: Operation,
+    client_id: ClientId,
+    amount: Option<CompactAmount>,
+    timestamp: Option<Timestamp>,
+    status: CompactStatus,
+    dispute: TransactionDispute,
+    holds: Option<Box<CompactHolds>>,
+}
+
+impl CompactTransaction {
+    fn compact(transaction: &Transaction) -> Result<Self, RepositoryError> {
+        let mut compact = CompactTransaction {
+            operation: *transaction.operation(),
+            client_id: transaction.client_id(),
+            amount: transaction.amount().as_ref().map(CompactAmount::compact).transpose()?,
+            timestamp: transaction.timestamp(),
+            status: transaction.status().into(),
+            dispute: transaction.dispute().clone(),
+            holds: None,
+        };
+        compact.set_holds(transaction.disputes(), &transaction.charged_back())?;
+        Ok(compact)
+    }
+
+    fn transaction(&self, transaction_id: TransactionId) -> Transaction {
+        let amount = self.amount.map(|amount| amount.amount());
+        let mut transaction = Transaction::from_parts(self.operation, self.client_id, transaction_id, amount, self.timestamp);
+        transaction.set_status((&self.status).into());
+        transaction.set_dispute(self.dispute.clone());
+        if let Some(holds) = &self.holds {
+            let disputes = holds.holds.iter()
+                .map(|hold| DisputeHold::new(hold.held.amount(), hold.cycle, hold.case_id, hold.row, hold.opened_at))
+                .collect();
+            transaction.set_holds(disputes, holds.charged_back.amount());
+        }
+        transaction
+    }
+
+    fn set_holds(&mut self, holds: &[DisputeHold], charged_back: &Amount) -> Result<(), RepositoryError> {
+        self.holds = match holds.is_empty() && charged_back.is_zero() {
+            true => None,
+            false => Some(Box::new(CompactHolds {
+                holds: holds.iter()
+                    .map(|hold| Ok(CompactHold {
+                        held: CompactAmount::compact(&hold.held())?,
+                        cycle: hold.cycle(),
+                        case_id: hold.case_id(),
+                        row: hold.row(),
+                        opened_at: hold.opened_at(),
+                    }))
+                    .collect::<Result<_, RepositoryError>>()?,
+                charged_back: CompactAmount::compact(charged_back)?,
+            })),
+        };
+        Ok(())
+    }
+
+    fn under_dispute(&self) -> bool {
+        matches!(self.dispute, TransactionDispute::Disputed)
+    }
+
+    /// Bytes taken in memory, approximately.
+    fn size(&self) -> usize {
+        let rejection = match &self.status {
+            CompactStatus::Rejected(reason) => size_of::<RejectionReason>() + reason.cause().len(),
+            _ => 0,
+        };
+        let holds = self.holds.as_ref()
+            .map(|holds| size_of::<CompactHolds>() + holds.holds.len() * size_of::<CompactHold>())
+            .unwrap_or_default();
+        TRANSACTION_SIZE + rejection + holds
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, RepositoryError> {
+        bincode::serialize(self).map_err(storage_error)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, RepositoryError> {
+        bincode::deserialize(bytes).map_err(storage_error)
+    }
+}
+
+/// Idempotency keys are kept as a hash of 128 bits, collisions are not a practical concern.
+fn request_hash(key: &str) -> u128 {
+    let digest = Sha256::digest(key.as_bytes());
+    let mut hash = [0u8; 16];
+    hash.copy_from_slice(&digest[..16]);
+    u128::from_be_bytes(hash)
+}
+
+/// The file the transactions and requests beyond the memory budget are spilled to.
+struct Spill {
+    database: Database,
+    path: PathBuf,
+}
+
+impl Spill {
+    fn create(dir: PathBuf) -> Result<Self, RepositoryError> {
+        let path = dir.join(format!("rails-spill-{}-{}.redb", std::process::id(), SPILL_FILES.fetch_add(1, Ordering::Relaxed)));
+        let database = Database::create(&path).map_err(storage_error)?;
+        let spill = Spill { database, path };
+        spill.write(&BTreeMap::new(), &HashSet::new(), None).map_err(storage_error)?;
+        Ok(spill)
+    }
+
+    /// Writes the transactions and the requests, and drops the spilled transactions that are
+    /// older than the cutoff and not under dispute.
+    fn write(&self, transactions: &BTreeMap<TransactionId, CompactTransaction>, requests: &HashSet<u128>, cutoff: Option<Timestamp>) -> Result<(), Box<dyn Error>> {
+        let mut write = self.database.begin_write()?;
+        // The spill file does not outlive the store, there is nothing to recover after a crash.
+        write.set_durability(Durability::Eventual);
+        {
+            let mut spilled = write.open_table(TRANSACTIONS)?;
+            let mut by_time = write.open_table(TRANSACTIONS_BY_TIME)?;
+            let mut spilled_requests = write.open_table(REQUESTS)?;
+            for (transaction_id, transaction) in transactions {
+                spilled.insert(transaction_id, transaction.encode()?.as_slice())?;
+                if let Some(timestamp) = transaction.timestamp {
+                    by_time.insert((timestamp, *transaction_id), ())?;
+                }
+            }
+            for request in requests {
+                spilled_requests.insert(request, ())?;
+            }
+            if let Some(cutoff) = cutoff {
+                let expired = by_time.range(..(cutoff, 0))?
+                    .map(|entry| entry.map(|(key, _)| key.value()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (timestamp, transaction_id) in expired {
+                    let under_dispute = match spilled.get(transaction_id)? {
+                        Some(transaction) => CompactTransaction::decode(transaction.value())?.under_dispute(),
+                        None => false,
+                    };
+                    if !under_dispute {
+                        spilled.remove(transaction_id)?;
+                        by_time.remove((timestamp, transaction_id))?;
+                    }
+                }
+            }
+        }
+        write.commit()?;
+        Ok(())
+    }
+
+    fn find_transaction(&self, transaction_id: TransactionId) -> Result<Option<CompactTransaction>, RepositoryError> {
+        let read = self.database.begin_read().map_err(storage_error)?;
+        let spilled = read.open_table(TRANSACTIONS).map_err(storage_error)?;
+        match spilled.get(transaction_id).map_err(storage_error)? {
+            None => Ok(None),
+            Some(transaction) => CompactTransaction::decode(transaction.value()).map(Some),
+        }
+    }
+
+    fn contains_request(&self, request: u128) -> Result<bool, RepositoryError> {
+        let read = self.database.begin_read().map_err(storage_error)?;
+        let spilled = read.open_table(REQUESTS).map_err(storage_error)?;
+        Ok(spilled.get(request).map_err(storage_error)?.is_some())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A write of the open unit of work, undone on rollback.
+enum Undo {
+    /// The transaction in memory before the write, if any.
+    Transaction(TransactionId, Option<CompactTransaction>),
+    Request(u128),
+    DisputeCase(CaseId, Option<DisputeCase>),
+    DisputeHistory(TransactionId),
+}
+
+/// The open unit of work. Its writes are applied in memory, where they shadow the spill file, and
+/// nothing is spilled until it is committed.
+struct CompactUnit {
+    undo: Vec<Undo>,
+    outbox: Vec<OutboxEntry>,
+    next_event_sequence: u64,
+    latest_timestamp: Timestamp,
+}
+
+/// Transaction repository for inputs too large to keep every transaction in memory. Transactions
+/// are kept in a compact form holding only what disputes need, the ones beyond the memory budget
+/// are spilled to an embedded key-value store on disk along with the idempotency keys, and the ones
+/// past the dispute window can be dropped. Dispute cases and dispute history grow with the disputes
+/// only, they stay in memory. So does the outbox, which holds the events the subscribers did not
+/// handle yet and stays empty without subscribers. The accounts are not bounded by the store, nor
+/// is their history, see `ServiceConfig::account_history`.
+pub struct CompactTransactionRepository {
+    config: CompactStoreConfig,
+    /// Transactions not spilled yet, or read back from the spill file to be updated.
+    transactions: BTreeMap<TransactionId, CompactTransaction>,
+    /// Hashes of the idempotency keys of the requests not spilled yet.
+    requests: HashSet<u128>,
+    /// Approximate bytes taken by the transactions and the requests in memory.
+    memory: usize,
+    spill: Option<Spill>,
+    /// Timestamp of the latest transaction posted, where the dispute window ends.
+    latest_timestamp: Timestamp,
+    dispute_history_by_id: HashMap<TransactionId, Vec<DisputeHistoryEntry>>,
+    dispute_cases_by_id: HashMap<CaseId, DisputeCase>,
+    outbox: VecDeque<OutboxEntry>,
+    next_event_sequence: u64,
+    unit: Option<CompactUnit>,
+}
+
+impl Default for CompactTransactionRepository {
+    fn default() -> Self {
+        CompactTransactionRepository::new(CompactStoreConfig::default())
+    }
+}
+
+impl CompactTransactionRepository {
+    pub fn new(config: CompactStoreConfig) -> Self {
+        CompactTransactionRepository {
+            config,
+            transactions: BTreeMap::new(),
+            requests: HashSet::new(),
+            memory: 0,
+            spill: None,
+            latest_timestamp: 0,
+            dispute_history_by_id: HashMap::new(),
+            dispute_cases_by_id: HashMap::new(),
+            outbox: VecDeque::new(),
+            next_event_sequence: 0,
+            unit: None,
+        }
+    }
+
+    /// Transactions older than the cutoff cannot be disputed anymore.
+    fn cutoff(&self) -> Option<Timestamp> {
+        self.config.dispute_window.map(|window| self.latest_timestamp.saturating_sub(window))
+    }
+
+    fn expired(&self, transaction: &CompactTransaction) -> bool {
+        let cutoff = self.cutoff();
+        !transaction.under_dispute()
+            && transaction.timestamp.is_some_and(|timestamp| cutoff.is_some_and(|cutoff| timestamp < cutoff))
+    }
+
+    /// The stored transaction, even if past the dispute window.
+    fn stored_transaction(&self, transaction_id: &TransactionId) -> Result<Option<CompactTransaction>, RepositoryError> {
+        match (self.transactions.get(transaction_id), &self.spill) {
+            (Some(transaction), _) => Ok(Some(transaction.clone())),
+            (None, Some(spill)) => spill.find_transaction(*transaction_id),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The stored transaction, unless past the dispute window.
+    fn transaction(&self, transaction_id: &TransactionId) -> Result<Option<CompactTransaction>, RepositoryError> {
+        Ok(self.stored_transaction(transaction_id)?.filter(|transaction| !self.expired(transaction)))
+    }
+
+    /// Keeps the transaction in memory, recording the write in the open unit of work if any.
+    fn put_transaction(&mut self, transaction_id: TransactionId, transaction: CompactTransaction) {
+        self.memory += transaction.size();
+        let previous = self.transactions.insert(transaction_id, transaction);
+        if let Some(previous) = &previous {
+            self.memory = self.memory.saturating_sub(previous.size());
+        }
+        if let Some(unit) = self.unit.as_mut() {
+            unit.undo.push(Undo::Transaction(transaction_id, previous));
+        }
+    }
+
+    fn update_transaction<F>(&mut self, transaction_id: &TransactionId, f: F) -> Result<(), RepositoryError> where F: FnOnce(&mut CompactTransaction) -> Result<(), RepositoryError> {
+        // A transaction settling its last dispute past the window is dropped once the unit of work
+        // is committed, not while the other writes of the settlement are applied.
+        match self.stored_transaction(transaction_id)? {
+            None => Err(RepositoryError::EntityNotFound(transaction_id.to_string())),
+            Some(mut transaction) => {
+                f(&mut transaction)?;
+                self.put_transaction(*transaction_id, transaction);
+                self.spill_beyond_budget()
+            }
+        }
+    }
+
+    /// Spills the transactions and the requests in memory once they exceed the memory budget,
+    /// unless a unit of work is open.
+    fn spill_beyond_budget(&mut self) -> Result<(), RepositoryError> {
+        if self.unit.is_some() || self.memory <= self.config.memory_budget {
+            return Ok(());
+        }
+        let spill = match self.spill.take() {
+            Some(spill) => spill,
+            None => Spill::create(self.config.spill_dir.clone().unwrap_or_else(std::env::temp_dir))?,
+        };
+        // The transactions past the dispute window are written too, so that they replace their
+        // spilled version, and dropped along with it.
+        let transactions = take(&mut self.transactions);
+        let written = spill.write(&transactions, &self.requests, self.cutoff());
+        self.spill = Some(spill);
+        match written {
+            Ok(()) => {
+                self.requests.clear();
+                self.memory = 0;
+                Ok(())
+            }
+            Err(error) => {
+                // Keep what could not be spilled in memory.
+                self.transactions = transactions;
+                self.memory = self.transactions.values().map(CompactTransaction::size).sum::<usize>() + self.requests.len() * REQUEST_SIZE;
+                Err(storage_error(error))
+            }
+        }
+    }
+
+    /// Visits the transactions with ids in the range that are not past the dispute window, ordered
+    /// by id.
+    fn visit_transactions<F>(&self, from: TransactionId, to: TransactionId, mut f: F) -> Result<(), RepositoryError> where F: FnMut(TransactionId, &CompactTransaction) {
+        let mut visit = |transaction_id: TransactionId, transaction: &CompactTransaction| {
+            if !self.expired(transaction) {
+                f(transaction_id, transaction)
+            }
+        };
+        let mut in_memory = self.transactions.range(from..=to).peekable();
+        if let Some(spill) = &self.spill {
+            let read = spill.database.begin_read().map_err(storage_error)?;
+            let spilled = read.open_table(TRANSACTIONS).map_err(storage_error)?;
+            for entry in spilled.range(from..=to).map_err(storage_error)? {
+                let (transaction_id, transaction) = entry.map_err(storage_error)?;
+                let transaction_id = transaction_id.value();
+                while let Some((id, transaction)) = in_memory.next_if(|(id, _)| **id <= transaction_id) {
+                    visit(*id, transaction);
+                }
+                // The transactions in memory shadow their spilled version.
+                if !self.transactions.contains_key(&transaction_id) {
+                    visit(transaction_id, &CompactTransaction::decode(transaction.value())?);
+                }
+            }
+        }
+        in_memory.for_each(|(id, transaction)| visit(*id, transaction));
+        Ok(())
+    }
+}
+
+impl UnitOfWork for CompactTransactionRepository {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        if self.unit.is_some() {
+            return Err(unit_already_open());
+        }
+        self.unit = Some(CompactUnit {
+            undo: Vec::new(),
+            outbox: Vec::new(),
+            next_event_sequence: self.next_event_sequence,
+            latest_timestamp: self.latest_timestamp,
+        });
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        if let Some(unit) = self.unit.take() {
+            self.outbox.extend(unit.outbox);
+        }
+        self.spill_beyond_budget()
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        if let Some(unit) = self.unit.take() {
+            for undo in unit.undo.into_iter().rev() {
+                match undo {
+                    Undo::Transaction(transaction_id, previous) => {
+                        if let Some(written) = self.transactions.remove(&transaction_id) {
+                            self.memory = self.memory.saturating_sub(written.size());
+                        }
+                        if let Some(previous) = previous {
+                            self.memory += previous.size();
+                            self.transactions.insert(transaction_id, previous);
+                        }
+                    }
+                    Undo::Request(request) => {
+                        self.requests.remove(&request);
+                        self.memory = self.memory.saturating_sub(REQUEST_SIZE);
+                    }
+                    Undo::DisputeCase(case_id, previous) => match previous {
+                        None => { self.dispute_cases_by_id.remove(&case_id); }
+                        Some(previous) => { self.dispute_cases_by_id.insert(case_id, previous); }
+                    },
+                    Undo::DisputeHistory(transaction_id) => {
+                        if let Some(history) = self.dispute_history_by_id.get_mut(&transaction_id) {
+                            history.pop();
+                            if history.is_empty() {
+                                self.dispute_history_by_id.remove(&transaction_id);
+                            }
+                        }
+                    }
+                }
+            }
+            self.next_event_sequence = unit.next_event_sequence;
+            self.latest_timestamp = unit.latest_timestamp;
+        }
+        Ok(())
+    }
+}
+
+impl TransactionRepository for CompactTransactionRepository {
+    fn post_transaction(&mut self, transaction: &Transaction) -> Result<(), RepositoryError> {
+        if self.stored_transaction(&transaction.transaction_id())?.is_some() {
+            return Err(RepositoryError::EntityAlreadyExists(transaction.transaction_id().to_string()));
+        }
+        let compact = CompactTransaction::compact(transaction)?;
+        if let Some(timestamp) = compact.timestamp {
+            self.latest_timestamp = self.latest_timestamp.max(timestamp);
+        }
+        self.put_transaction(transaction.transaction_id(), compact);
+        self.spill_beyond_budget()
+    }
+
+    fn update_transaction_status(&mut self, transaction_id: &TransactionId, status: &TransactionStatus) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| {
+            tx.status = status.into();
+            Ok(())
+        })
+    }
+
+    fn update_transaction_dispute(&mut self, transaction_id: &TransactionId, dispute: &TransactionDispute) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| {
+            tx.dispute = dispute.to_owned();
+            Ok(())
+        })
+    }
+
+    fn update_transaction_holds(&mut self, transaction_id: &TransactionId, holds: &[DisputeHold], charged_back: &Amount) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| tx.set_holds(holds, charged_back))
+    }
+
+    fn find_transaction_by_id(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, RepositoryError> {
+        Ok(self.transaction(transaction_id)?.map(|transaction| transaction.transaction(*transaction_id)))
+    }
+
+    fn find_transactions(&mut self, query: &TransactionQuery) -> Result<TransactionPage, RepositoryError> {
+        let limit = query.limit.unwrap_or(usize::MAX);
+        let mut transactions = Vec::new();
+        let mut total = 0;
+        let from = query.from_transaction_id.unwrap_or(TransactionId::MIN);
+        let to = query.to_transaction_id.unwrap_or(TransactionId::MAX);
+        self.visit_transactions(from, to, |transaction_id, transaction| {
+            let transaction = transaction.transaction(transaction_id);
+            if query.matches(&transaction) {
+                if total >= query.offset && transactions.len() < limit {
+                    transactions.push(transaction);
+                }
+                total += 1;
+            }
+        })?;
+        Ok(TransactionPage {
+            transactions,
+            total,
+        })
+    }
+
+    fn register_request(&mut self, key: &str) -> Result<(), RepositoryError> {
+        let request = request_hash(key);
+        let registered = match &self.spill {
+            Some(spill) => self.requests.contains(&request) || spill.contains_request(request)?,
+            None => self.requests.contains(&request),
+        };
+        if registered {
+            return Err(RepositoryError::EntityAlreadyExists(key.to_owned()));
+        }
+        self.requests.insert(request);
+        self.memory += REQUEST_SIZE;
+        if let Some(unit) = self.unit.as_mut() {
+            unit.undo.push(Undo::Request(request));
+        }
+        self.spill_beyond_budget()
+    }
+
+    fn post_dispute_case(&mut self, case: &DisputeCase) -> Result<(), RepositoryError> {
+        if self.dispute_cases_by_id.contains_key(&case.case_id()) {
+            return Err(RepositoryError::EntityAlreadyExists(format!("case {}", case.case_id())));
+        }
+        self.dispute_cases_by_id.insert(case.case_id(), case.to_owned());
+        if let Some(unit) = self.unit.as_mut() {
+            unit.undo.push(Undo::DisputeCase(case.case_id(), None));
+        }
+        Ok(())
+    }
+
+    fn update_dispute_case_state(&mut self, case_id: &CaseId, state: &DisputeCaseState) -> Result<(), RepositoryError> {
+        match self.dispute_cases_by_id.get_mut(case_id) {
+            None => Err(RepositoryError::EntityNotFound(format!("case {}", case_id))),
+            Some(case) => {
+                if let Some(unit) = self.unit.as_mut() {
+                    unit.undo.push(Undo::DisputeCase(*case_id, Some(case.clone())));
+                }
+                case.set_state(state.to_owned());
+                Ok(())
+            }
+        }
+    }
+
+    fn find_dispute_case(&mut self, case_id: &CaseId) -> Result<Option<DisputeCase>, RepositoryError> {
+        Ok(self.dispute_cases_by_id.get(case_id).cloned())
+    }
+
+    fn append_events(&mut self, events: &[DomainEvent]) -> Result<(), RepositoryError> {
+        for event in events {
+            self.next_event_sequence += 1;
+            let entry = OutboxEntry::new(self.next_event_sequence, event.clone());
+            match self.unit.as_mut() {
+                None => self.outbox.push_back(entry),
+                Some(unit) => unit.outbox.push(entry),
+            }
+        }
+        Ok(())
+    }
+
+    /// The committed events only, the events of an open unit of work are not relayed until then.
+    fn unpublished_events(&mut self, after: u64) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        let start = self.outbox.partition_point(|entry| entry.sequence() <= after);
+        Ok(self.outbox.range(start..).cloned().collect())
+    }
+
+    fn mark_events_published(&mut self, sequence: u64) -> Result<(), RepositoryError> {
+        while self.outbox.front().is_some_and(|entry| entry.sequence() <= sequence) {
+            self.outbox.pop_front();
+        }
+        Ok(())
+    }
+
+    fn append_dispute_history(&mut self, entry: &DisputeHistoryEntry) -> Result<(), RepositoryError> {
+        if self.stored_transaction(&entry.transaction_id())?.is_none() {
+            return Err(RepositoryError::EntityNotFound(entry.transaction_id().to_string()));
+        }
+        self.dispute_history_by_id.entry(entry.transaction_id()).or_default().push(entry.clone());
+        if let Some(unit) = self.unit.as_mut() {
+            unit.undo.push(Undo::DisputeHistory(entry.transaction_id()));
+        }
+        Ok(())
+    }
+
+    fn find_dispute_history(&mut self, transaction_id: &TransactionId) -> Result<Vec<DisputeHistoryEntry>, RepositoryError> {
+        Ok(self.dispute_history_by_id.get(transaction_id).cloned().unwrap_or_default())
+    }
+
+    fn dispute_history_visitor(&mut self, f: &mut dyn FnMut(&DisputeHistoryEntry)) -> Result<(), RepositoryError> {
+        self.dispute_history_by_id.values().flatten().for_each(|entry| {f(entry)});
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use bigdecimal::{BigDecimal, Zero};
+    use crate::domain::{AccountRepository, Operation, ServiceConfig, TransactionDispute, TransactionQuery, TransactionRepository, TransactionRequest, TransactionService, TransactionStatus, UnitOfWork};
+    use crate::events::DomainEvent;
+    use crate::repository::{conformance, CompactStoreConfig, CompactTransactionRepository, InMemAccountRepository};
+
+    fn deposit(transaction_id: u64, amount: &str, timestamp: u64) -> TransactionRequest {
+        TransactionRequest::builder(Operation::Deposit, 1, transaction_id)
+            .amount(BigDecimal::from_str(amount).unwrap())
+            .timestamp(timestamp)
+            .row(transaction_id + 1)
+            .request_id(format!("input:{}", transaction_id + 1))
+            .build()
+    }
+
+    #[test]
+    fn conforms() {
+        conformance::transaction_repository(CompactTransactionRepository::default).unwrap();
+        // Spilling after every write.
+        conformance::transaction_repository(|| CompactTransactionRepository::new(CompactStoreConfig { memory_budget: 0, ..CompactStoreConfig::default() })).unwrap();
+    }
+
+    #[test]
+    fn spills_beyond_the_memory_budget() -> Result<(), Box<dyn std::error::Error>> {
+        // Every write exceeds the budget, the transactions live in the spill file.
+        let repository = CompactTransactionRepository::new(CompactStoreConfig { memory_budget: 0, ..CompactStoreConfig::default() });
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), repository);
+        for transaction_id in 1..=100 {
+            transaction_service.process_transaction(deposit(transaction_id, "1.5", transaction_id))?;
+        }
+
+        // Spilled transactions can be disputed, and their ids cannot be posted again.
+        transaction_service.process_transaction(TransactionRequest::builder(Operation::Dispute, 1, 42).build())?;
+        assert!(transaction_service.process_transaction(deposit(7, "1.5", 7)).is_err());
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from(150), account.total());
+        assert_eq!(BigDecimal::from_str("1.5")?, account.held());
+
+        // Only the fields disputes need are kept.
+        let transaction = transaction_service.find_transaction(&42)?.unwrap();
+        assert_eq!(Some(BigDecimal::from_str("1.5")?), transaction.amount());
+        assert_eq!(Some(42), transaction.timestamp());
+        assert!(matches!(transaction.status(), TransactionStatus::Applied));
+        assert!(matches!(transaction.dispute(), TransactionDispute::Disputed));
+        assert_eq!(1, transaction.disputes().len());
+        assert_eq!(None, transaction.row());
+
+        let page = transaction_service.find_transactions(&TransactionQuery {
+            dispute: Some(TransactionDispute::No),
+            from_transaction_id: Some(40),
+            offset: 1,
+            limit: Some(2),
+            ..TransactionQuery::default()
+        })?;
+        assert_eq!(60, page.total);
+        assert_eq!(vec![41, 43], page.transactions.iter().map(|transaction| transaction.transaction_id()).collect::<Vec<_>>());
+
+        let (_, mut repository) = transaction_service.into_repositories();
+        assert!(repository.transactions.is_empty());
+        assert!(repository.requests.is_empty());
+        assert!(repository.register_request("deposit,1,1,1.5,@input:2").is_err());
+        assert!(repository.register_request("deposit,1,101,1.5,@input:102").is_ok());
+        assert!(repository.register_request("deposit,1,101,1.5,@input:102").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bounded_runs_keep_no_history_nor_events() -> Result<(), Box<dyn std::error::Error>> {
+        let repository = CompactTransactionRepository::new(CompactStoreConfig { memory_budget: 0, ..CompactStoreConfig::default() });
+        let config = ServiceConfig { account_history: false, ..ServiceConfig::default() };
+        let mut transaction_service = TransactionService::with_config(InMemAccountRepository::default(), repository, config);
+        for transaction_id in 1..=100 {
+            transaction_service.process_transaction(deposit(transaction_id, "1", transaction_id))?;
+        }
+        assert_eq!(BigDecimal::from(100), transaction_service.get_account_status(&1)?.available());
+
+        let (mut accounts, repository) = transaction_service.into_repositories();
+        assert!(accounts.find_account_versions(&1)?.is_empty());
+        assert!(repository.outbox.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn evicts_transactions_past_the_dispute_window() -> Result<(), Box<dyn std::error::Error>> {
+        for memory_budget in [0, usize::MAX] {
+            let config = CompactStoreConfig { memory_budget, dispute_window: Some(100), ..CompactStoreConfig::default() };
+            let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), CompactTransactionRepository::new(config));
+            transaction_service.process_transaction(deposit(1, "1", 0))?;
+            transaction_service.process_transaction(deposit(2, "2", 50))?;
+            transaction_service.process_transaction(TransactionRequest::builder(Operation::Dispute, 1, 2).build())?;
+            transaction_service.process_transaction(deposit(3, "3", 200))?;
+
+            // The first deposit can no longer be disputed, the second one is under dispute.
+            assert!(transaction_service.find_transaction(&1)?.is_none());
+            assert!(transaction_service.find_transaction(&2)?.is_some());
+            let outcome = transaction_service.process_transaction(TransactionRequest::builder(Operation::Dispute, 1, 1).build());
+            assert!(outcome.is_err());
+            assert_eq!(2, transaction_service.find_transactions(&TransactionQuery::default())?.total);
+
+            // Once resolved it goes too.
+            transaction_service.process_transaction(TransactionRequest::builder(Operation::Resolve, 1, 2).build())?;
+            transaction_service.process_transaction(deposit(4, "4", 300))?;
+            assert!(transaction_service.find_transaction(&2)?.is_none());
+            assert_eq!(BigDecimal::from(10), transaction_service.get_account_status(&1)?.available());
+
+            // Spilling dropped them for good.
+            let (_, repository) = transaction_service.into_repositories();
+            if memory_budget == 0 {
+                assert!(repository.stored_transaction(&1)?.is_none());
+                assert!(repository.stored_transaction(&2)?.is_none());
+                assert!(repository.stored_transaction(&4)?.is_some());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_undoes_the_unit() -> Result<(), Box<dyn std::error::Error>> {
+        let mut repository = CompactTransactionRepository::default();
+        repository.post_transaction(&deposit(1, "1", 0).valid_transaction().unwrap())?;
+        repository.register_request("deposit,1,1,1,")?;
+
+        repository.begin()?;
+        repository.post_transaction(&deposit(2, "2", 10).valid_transaction().unwrap())?;
+        repository.update_transaction_status(&1, &TransactionStatus::Applied)?;
+        repository.register_request("deposit,1,2,2,")?;
+        repository.append_events(&[DomainEvent::AccountLocked { client_id: 1, transaction_id: 1 }])?;
+        assert!(repository.unpublished_events(0)?.is_empty());
+        repository.rollback()?;
+
+        assert!(repository.find_transaction_by_id(&2)?.is_none());
+        assert!(matches!(repository.find_transaction_by_id(&1)?.unwrap().status(), TransactionStatus::Pending));
+        assert!(repository.register_request("deposit,1,1,1,").is_err());
+        assert!(repository.register_request("deposit,1,2,2,").is_ok());
+        assert_eq!(0, repository.latest_timestamp);
+        assert_eq!(repository.transactions.values().map(|transaction| transaction.size()).sum::<usize>() + 2 * super::REQUEST_SIZE, repository.memory);
+
+        repository.begin()?;
+        repository.append_events(&[DomainEvent::AccountLocked { client_id: 1, transaction_id: 1 }])?;
+        repository.commit()?;
+        assert_eq!(1, repository.unpublished_events(0)?[0].sequence());
+        assert!(BigDecimal::zero() < repository.find_transaction_by_id(&1)?.unwrap().amount().unwrap());
+        Ok(())
+    }
+}
@@ -0,0 +1,448 @@
+//! Behaviour every repository implementation must share, so that the service works the same on
+//! top of any of them. Each check panics on the first difference, the repository errors it does
+//! not expect are returned.
+//!
+//! ```ignore
+//! conformance::account_repository(MyAccountRepository::default)?;
+//! conformance::transaction_repository(MyTransactionRepository::default)?;
+//! ```
+
+use std::collections::HashSet;
+use std::thread;
+use crate::domain::{Account, AccountRepository, AccountVersion, Amount, ClientId, DisputeAction, DisputeCase, DisputeCaseState, DisputeHistoryEntry, DisputeHold, Operation, RejectionReason, RepositoryError, StatusFilter, Transaction, TransactionDispute, TransactionId, TransactionQuery, TransactionRepository, TransactionRequest, TransactionStatus};
+use crate::events::DomainEvent;
+
+/// Updates of the account per writer in the concurrency checks.
+const UPDATES: u64 = 100;
+
+fn deposit(transaction_id: TransactionId, client_id: ClientId, amount: u32) -> Transaction {
+    TransactionRequest::builder(Operation::Deposit, client_id, transaction_id)
+        .amount(Amount::from(amount))
+        .timestamp(transaction_id)
+        .build()
+        .try_into()
+        .expect("an invalid deposit")
+}
+
+fn deposited(transaction_id: TransactionId) -> DomainEvent {
+    DomainEvent::Deposited { client_id: 1, transaction_id, amount: Amount::from(1) }
+}
+
+fn opened(transaction_id: TransactionId, cycle: u32) -> DisputeHistoryEntry {
+    DisputeHistoryEntry::new(transaction_id, 1, None, cycle, DisputeAction::Opened, Amount::from(1), None)
+}
+
+/// The account with its funds increased by the amount, one version later.
+fn credited(account: &Account, amount: u32) -> Account {
+    Account::from_parts(account.client_id(), account.available() + Amount::from(amount), account.held(), account.is_locked(), account.last_tx_applied(), account.version() + 1)
+}
+
+/// Asserts the fields every repository keeps of a transaction are the stored ones.
+fn assert_stored(expected: &Transaction, found: Option<Transaction>) {
+    let found = found.unwrap_or_else(|| panic!("transaction {} not found", expected.transaction_id()));
+    assert_eq!(expected.transaction_id(), found.transaction_id());
+    assert_eq!(expected.client_id(), found.client_id());
+    assert_eq!(expected.operation(), found.operation());
+    assert_eq!(expected.amount(), found.amount());
+    assert_eq!(expected.timestamp(), found.timestamp());
+    assert_eq!(format!("{:?}", expected.status()), format!("{:?}", found.status()));
+    assert_eq!(format!("{:?}", expected.dispute()), format!("{:?}", found.dispute()));
+    assert_eq!(expected.charged_back(), found.charged_back());
+    let held = |transaction: &Transaction| transaction.disputes().iter().map(|hold| (hold.held(), hold.cycle(), hold.case_id())).collect::<Vec<_>>();
+    assert_eq!(held(expected), held(&found));
+}
+
+/// Runs every account repository check, each on a new repository.
+pub fn account_repository<R, F>(new: F) -> Result<(), RepositoryError> where R: AccountRepository, F: Fn() -> R {
+    opens_accounts_once(&mut new())?;
+    compares_and_sets_accounts(&mut new())?;
+    visits_every_account(&mut new())?;
+    keeps_account_versions(&mut new())?;
+    units_of_work_of_accounts(&mut new())?;
+    Ok(())
+}
+
+/// Runs every transaction repository check, each on a new repository.
+pub fn transaction_repository<R, F>(new: F) -> Result<(), RepositoryError> where R: TransactionRepository, F: Fn() -> R {
+    posts_transactions_once(&mut new())?;
+    updates_stored_transactions(&mut new())?;
+    selects_transactions(&mut new())?;
+    registers_requests_once(&mut new())?;
+    keeps_dispute_cases(&mut new())?;
+    numbers_events(&mut new())?;
+    keeps_dispute_history(&mut new())?;
+    units_of_work_of_transactions(&mut new())?;
+    Ok(())
+}
+
+fn opens_accounts_once<R: AccountRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    assert!(repository.find_account(&1)?.is_none(), "an account exists before being opened");
+    assert_eq!(Account::new(1), repository.open_account(&1)?);
+    assert!(matches!(repository.open_account(&1), Err(RepositoryError::EntityAlreadyExists(_))), "an account opened twice");
+    assert_eq!(Some(Account::new(1)), repository.find_account(&1)?);
+    assert!(repository.find_account(&2)?.is_none(), "opening an account opened another one");
+    Ok(())
+}
+
+fn compares_and_sets_accounts<R: AccountRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    let account = repository.open_account(&1)?;
+    let update = credited(&account, 5);
+    repository.update_account(&account, &update)?;
+    assert_eq!(Some(update.clone()), repository.find_account(&1)?);
+
+    // The account changed since it was read.
+    assert!(matches!(repository.update_account(&account, &credited(&account, 7)), Err(RepositoryError::InconsistencyDetected(_))), "a stale update was applied");
+    assert_eq!(Some(update.clone()), repository.find_account(&1)?);
+    repository.update_account(&update, &credited(&update, 1))?;
+    assert_eq!(Some(credited(&update, 1)), repository.find_account(&1)?);
+
+    let missing = Account::new(2);
+    assert!(matches!(repository.update_account(&missing, &credited(&missing, 1)), Err(RepositoryError::EntityNotFound(_))), "a missing account was updated");
+    assert!(repository.find_account(&2)?.is_none(), "updating a missing account opened it");
+    Ok(())
+}
+
+fn visits_every_account<R: AccountRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    for client_id in 1..=20 {
+        let account = repository.open_account(&client_id)?;
+        repository.update_account(&account, &credited(&account, client_id as u32))?;
+    }
+    let mut visited = Vec::new();
+    repository.account_visitor(|account| visited.push(account.clone()))?;
+    visited.sort_by_key(|account| account.client_id());
+    assert_eq!((1..=20).collect::<Vec<_>>(), visited.iter().map(|account| account.client_id()).collect::<Vec<_>>());
+    assert!(visited.iter().all(|account| account.available() == Amount::from(account.client_id())), "a visited account is not the last version");
+    Ok(())
+}
+
+fn keeps_account_versions<R: AccountRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    assert!(repository.find_account_versions(&1)?.is_empty());
+    let mut account = repository.open_account(&1)?;
+    for transaction_id in 1..=3 {
+        let update = credited(&account, 1);
+        repository.update_account(&account, &update)?;
+        repository.append_account_version(&AccountVersion::new(&deposit(transaction_id, 1, 1), &update))?;
+        repository.append_account_version(&AccountVersion::new(&deposit(transaction_id + 10, 2, 1), &Account::new(2)))?;
+        account = update;
+    }
+    let versions = repository.find_account_versions(&1)?;
+    assert_eq!(vec![1, 2, 3], versions.iter().map(|version| version.transaction_id()).collect::<Vec<_>>(), "versions are not kept in order");
+    assert_eq!(vec![Amount::from(1), Amount::from(2), Amount::from(3)], versions.iter().map(|version| version.account().available()).collect::<Vec<_>>());
+    assert_eq!(3, repository.find_account_versions(&2)?.len());
+    Ok(())
+}
+
+fn units_of_work_of_accounts<R: AccountRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    let account = repository.open_account(&1)?;
+
+    repository.begin()?;
+    assert!(repository.begin().is_err(), "a unit of work began twice");
+    repository.open_account(&2)?;
+    repository.update_account(&account, &credited(&account, 1))?;
+    repository.append_account_version(&AccountVersion::new(&deposit(1, 1, 1), &credited(&account, 1)))?;
+    assert!(repository.find_account(&2)?.is_some(), "a unit of work does not read its own writes");
+    assert_eq!(Some(credited(&account, 1)), repository.find_account(&1)?);
+    repository.rollback()?;
+    assert!(repository.find_account(&2)?.is_none(), "a rolled back account is kept");
+    assert_eq!(Some(account.clone()), repository.find_account(&1)?);
+    assert!(repository.find_account_versions(&1)?.is_empty(), "a rolled back version is kept");
+
+    repository.begin()?;
+    repository.open_account(&2)?;
+    repository.update_account(&account, &credited(&account, 1))?;
+    repository.commit()?;
+    assert!(repository.find_account(&2)?.is_some(), "a committed account is lost");
+    assert_eq!(Some(credited(&account, 1)), repository.find_account(&1)?);
+
+    // Closing a unit of work that is not open does nothing.
+    repository.commit()?;
+    repository.rollback()?;
+    Ok(())
+}
+
+fn posts_transactions_once<R: TransactionRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    assert!(repository.find_transaction_by_id(&1)?.is_none());
+    let transaction = deposit(1, 1, 10);
+    repository.post_transaction(&transaction)?;
+    assert!(matches!(repository.post_transaction(&deposit(1, 2, 20)), Err(RepositoryError::EntityAlreadyExists(_))), "a transaction id posted twice");
+    assert_stored(&transaction, repository.find_transaction_by_id(&1)?);
+    assert!(repository.find_transaction_by_id(&2)?.is_none());
+    Ok(())
+}
+
+fn updates_stored_transactions<R: TransactionRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    assert!(matches!(repository.update_transaction_status(&1, &TransactionStatus::Applied), Err(RepositoryError::EntityNotFound(_))));
+    assert!(matches!(repository.update_transaction_dispute(&1, &TransactionDispute::Disputed), Err(RepositoryError::EntityNotFound(_))));
+    assert!(matches!(repository.update_transaction_holds(&1, &[], &Amount::from(0)), Err(RepositoryError::EntityNotFound(_))));
+    assert!(repository.find_transaction_by_id(&1)?.is_none(), "updating a missing transaction posted it");
+
+    let mut transaction = deposit(1, 1, 10);
+    repository.post_transaction(&transaction)?;
+    let holds = vec![DisputeHold::new(Amount::from(4), 1, Some(7), Some(3), 100), DisputeHold::new(Amount::from(2), 2, None, None, 200)];
+    repository.update_transaction_status(&1, &TransactionStatus::Rejected(RejectionReason::RuleViolation("max_deposit".to_string())))?;
+    repository.update_transaction_dispute(&1, &TransactionDispute::Disputed)?;
+    repository.update_transaction_holds(&1, &holds, &Amount::from(3))?;
+    transaction.set_status(TransactionStatus::Rejected(RejectionReason::RuleViolation("max_deposit".to_string())));
+    transaction.set_dispute(TransactionDispute::Disputed);
+    transaction.set_holds(holds, Amount::from(3));
+    assert_stored(&transaction, repository.find_transaction_by_id(&1)?);
+
+    repository.update_transaction_holds(&1, &[], &Amount::from(3))?;
+    transaction.set_holds(Vec::new(), Amount::from(3));
+    assert_stored(&transaction, repository.find_transaction_by_id(&1)?);
+    Ok(())
+}
+
+fn selects_transactions<R: TransactionRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    // Posted out of order, the odd ones applied.
+    for transaction_id in (1..=20).rev() {
+        repository.post_transaction(&deposit(transaction_id, transaction_id % 3, 1))?;
+        if transaction_id % 2 == 1 {
+            repository.update_transaction_status(&transaction_id, &TransactionStatus::Applied)?;
+        }
+    }
+    let ids = |page: crate::domain::TransactionPage| page.transactions.iter().map(|transaction| transaction.transaction_id()).collect::<Vec<_>>();
+
+    let all = repository.find_transactions(&TransactionQuery::default())?;
+    assert_eq!(20, all.total);
+    assert_eq!((1..=20).collect::<Vec<_>>(), ids(all), "transactions are not ordered by id");
+
+    let page = repository.find_transactions(&TransactionQuery {
+        client_id: Some(1),
+        status: Some(StatusFilter::Applied),
+        from_transaction_id: Some(2),
+        to_transaction_id: Some(19),
+        offset: 1,
+        limit: Some(2),
+        ..TransactionQuery::default()
+    })?;
+    // Clients 1 applied within the range: 7, 13, 19.
+    assert_eq!(3, page.total);
+    assert_eq!(vec![13, 19], ids(page));
+
+    let none = repository.find_transactions(&TransactionQuery { dispute: Some(TransactionDispute::Disputed), ..TransactionQuery::default() })?;
+    assert_eq!(0, none.total);
+    assert!(none.transactions.is_empty());
+    let past_end = repository.find_transactions(&TransactionQuery { offset: 30, ..TransactionQuery::default() })?;
+    assert_eq!(20, past_end.total);
+    assert!(past_end.transactions.is_empty());
+    Ok(())
+}
+
+fn registers_requests_once<R: TransactionRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    repository.register_request("deposit,1,1,10,")?;
+    repository.register_request("deposit,1,2,10,")?;
+    assert!(matches!(repository.register_request("deposit,1,1,10,"), Err(RepositoryError::EntityAlreadyExists(_))), "a request registered twice");
+    Ok(())
+}
+
+fn keeps_dispute_cases<R: TransactionRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    assert!(repository.find_dispute_case(&7)?.is_none());
+    assert!(matches!(repository.update_dispute_case_state(&7, &DisputeCaseState::Resolved), Err(RepositoryError::EntityNotFound(_))));
+    repository.post_transaction(&deposit(1, 1, 10))?;
+    repository.post_dispute_case(&DisputeCase::new(7, 1, 1))?;
+    assert!(matches!(repository.post_dispute_case(&DisputeCase::new(7, 1, 1)), Err(RepositoryError::EntityAlreadyExists(_))), "a case posted twice");
+    repository.update_dispute_case_state(&7, &DisputeCaseState::ChargedBack)?;
+    let case = repository.find_dispute_case(&7)?.expect("the case is not found");
+    assert_eq!((7, 1, 1), (case.case_id(), case.transaction_id(), case.client_id()));
+    assert!(matches!(case.state(), DisputeCaseState::ChargedBack));
+    Ok(())
+}
+
+fn numbers_events<R: TransactionRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    assert!(repository.unpublished_events(0)?.is_empty());
+    repository.append_events(&[deposited(1), deposited(2)])?;
+    repository.append_events(&[])?;
+    repository.append_events(&[deposited(3)])?;
+    let events = repository.unpublished_events(0)?;
+    assert_eq!(vec![1, 2, 3], events.iter().map(|entry| entry.sequence()).collect::<Vec<_>>(), "events are not numbered consecutively");
+    assert_eq!(&deposited(2), events[1].event());
+
+    repository.mark_events_published(2)?;
+    assert_eq!(vec![3], repository.unpublished_events(0)?.iter().map(|entry| entry.sequence()).collect::<Vec<_>>());
+    // Sequence numbers are not reused once published.
+    repository.mark_events_published(3)?;
+    repository.append_events(&[deposited(4)])?;
+    assert_eq!(vec![4], repository.unpublished_events(0)?.iter().map(|entry| entry.sequence()).collect::<Vec<_>>());
+    Ok(())
+}
+
+fn keeps_dispute_history<R: TransactionRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    assert!(repository.find_dispute_history(&1)?.is_empty());
+    assert!(matches!(repository.append_dispute_history(&opened(1, 1)), Err(RepositoryError::EntityNotFound(_))), "history kept for a missing transaction");
+    repository.post_transaction(&deposit(1, 1, 10))?;
+    repository.post_transaction(&deposit(2, 1, 10))?;
+    for cycle in 1..=3 {
+        repository.append_dispute_history(&opened(1, cycle))?;
+        repository.append_dispute_history(&opened(2, cycle))?;
+    }
+    let history = repository.find_dispute_history(&1)?;
+    assert_eq!(vec![1, 2, 3], history.iter().map(|entry| entry.cycle()).collect::<Vec<_>>(), "history is not kept in order");
+    assert!(history.iter().all(|entry| entry.transaction_id() == 1));
+
+    let mut visited = Vec::new();
+    repository.dispute_history_visitor(&mut |entry| visited.push((entry.transaction_id(), entry.cycle())))?;
+    visited.sort();
+    assert_eq!(vec![(1, 1), (1, 2), (1, 3), (2, 1), (2, 2), (2, 3)], visited);
+    Ok(())
+}
+
+fn units_of_work_of_transactions<R: TransactionRepository>(repository: &mut R) -> Result<(), RepositoryError> {
+    repository.post_transaction(&deposit(1, 1, 10))?;
+    repository.append_events(&[deposited(1)])?;
+
+    repository.begin()?;
+    assert!(repository.begin().is_err(), "a unit of work began twice");
+    repository.post_transaction(&deposit(2, 1, 10))?;
+    repository.update_transaction_status(&1, &TransactionStatus::Applied)?;
+    repository.register_request("deposit,1,2,10,")?;
+    repository.post_dispute_case(&DisputeCase::new(7, 1, 1))?;
+    repository.append_dispute_history(&opened(1, 1))?;
+    repository.append_events(&[deposited(2)])?;
+    assert!(repository.find_transaction_by_id(&2)?.is_some(), "a unit of work does not read its own writes");
+    assert!(repository.find_dispute_case(&7)?.is_some(), "a unit of work does not read its own writes");
+    assert_eq!(1, repository.find_dispute_history(&1)?.len(), "a unit of work does not read its own writes");
+    assert_eq!(2, repository.find_transactions(&TransactionQuery::default())?.total, "a unit of work does not read its own writes");
+    assert_eq!(1, repository.unpublished_events(0)?.len(), "the events of an open unit of work are relayed");
+    repository.rollback()?;
+
+    assert!(repository.find_transaction_by_id(&2)?.is_none(), "a rolled back transaction is kept");
+    assert!(matches!(repository.find_transaction_by_id(&1)?.map(|transaction| transaction.status().clone()), Some(TransactionStatus::Pending)), "a rolled back update is kept");
+    assert!(repository.find_dispute_case(&7)?.is_none(), "a rolled back case is kept");
+    assert!(repository.find_dispute_history(&1)?.is_empty(), "a rolled back history entry is kept");
+    repository.register_request("deposit,1,2,10,")?;
+
+    repository.begin()?;
+    repository.post_transaction(&deposit(2, 1, 10))?;
+    repository.append_events(&[deposited(2)])?;
+    repository.commit()?;
+    assert!(repository.find_transaction_by_id(&2)?.is_some(), "a committed transaction is lost");
+    // The sequence numbers of the rolled back events are reused.
+    assert_eq!(vec![1, 2], repository.unpublished_events(0)?.iter().map(|entry| entry.sequence()).collect::<Vec<_>>());
+
+    repository.commit()?;
+    repository.rollback()?;
+    Ok(())
+}
+
+/// Runs writers on several handles over the same accounts, e.g. clones of a shared repository, on
+/// a thread each. Every writer credits the account of client 1 [`UPDATES`] times, every other time
+/// within a unit of work, retrying when the account changed under it. No update may be lost.
+pub fn concurrent_account_writers<R>(mut handles: Vec<R>) -> Result<(), RepositoryError> where R: AccountRepository + Send + 'static {
+    let writers = handles.len() as u64;
+    handles.first_mut().expect("no handle").open_account(&1)?;
+    let threads: Vec<_> = handles.into_iter().map(|mut repository| thread::spawn(move || -> Result<R, RepositoryError> {
+        for update in 0..UPDATES {
+            let in_unit = update % 2 == 1;
+            loop {
+                if in_unit {
+                    repository.begin()?;
+                }
+                let account = repository.find_account(&1)?.expect("the account is not found");
+                match repository.update_account(&account, &credited(&account, 1)) {
+                    Ok(()) if in_unit => {
+                        repository.commit()?;
+                        break;
+                    }
+                    Ok(()) => break,
+                    Err(RepositoryError::InconsistencyDetected(_)) if in_unit => repository.rollback()?,
+                    Err(RepositoryError::InconsistencyDetected(_)) => continue,
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+        Ok(repository)
+    })).collect();
+    let mut handles = threads.into_iter()
+        .map(|thread| thread.join().expect("a writer panicked"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let account = handles[0].find_account(&1)?.expect("the account is not found");
+    assert_eq!(Amount::from(writers * UPDATES), account.available(), "an update was lost");
+    assert_eq!(writers * UPDATES, account.version());
+    Ok(())
+}
+
+/// Runs writers on several handles over the same transactions, on a thread each. Every writer posts
+/// the same transactions and registers the same requests, every other one within a unit of work,
+/// and appends an event for each. Each transaction and request is stored once, and the events are
+/// numbered consecutively.
+pub fn concurrent_transaction_writers<R>(handles: Vec<R>) -> Result<(), RepositoryError> where R: TransactionRepository + Send + 'static {
+    let writers = handles.len() as u64;
+    let threads: Vec<_> = handles.into_iter().map(|mut repository| thread::spawn(move || -> Result<(R, u64), RepositoryError> {
+        let mut posted = 0;
+        for transaction_id in 1..=UPDATES {
+            let in_unit = transaction_id % 2 == 1;
+            if in_unit {
+                repository.begin()?;
+            }
+            let stored = repository.register_request(&format!("deposit,1,{},1,", transaction_id))
+                .and_then(|_| repository.post_transaction(&deposit(transaction_id, 1, 1)));
+            let mut stored = match stored {
+                Ok(()) => true,
+                Err(RepositoryError::EntityAlreadyExists(_)) => false,
+                Err(error) => return Err(error),
+            };
+            repository.append_events(&[deposited(transaction_id)])?;
+            match (in_unit, stored) {
+                // The commit can refuse the entities another writer created since they were read.
+                (true, true) => match repository.commit() {
+                    Ok(()) => (),
+                    Err(RepositoryError::EntityAlreadyExists(_)) => stored = false,
+                    Err(error) => return Err(error),
+                },
+                (true, false) => repository.rollback()?,
+                (false, _) => {}
+            }
+            if stored {
+                posted += 1;
+            }
+        }
+        Ok((repository, posted))
+    })).collect();
+    let results = threads.into_iter()
+        .map(|thread| thread.join().expect("a writer panicked"))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(UPDATES, results.iter().map(|(_, posted)| posted).sum::<u64>(), "a transaction was posted more than once");
+    let (mut repository, _) = results.into_iter().next().expect("no handle");
+    assert_eq!(UPDATES as usize, repository.find_transactions(&TransactionQuery::default())?.total);
+
+    // The events of the rolled back units are discarded, the others are kept.
+    let sequences: Vec<u64> = repository.unpublished_events(0)?.iter().map(|entry| entry.sequence()).collect();
+    let expected_events = UPDATES / 2 + writers * UPDATES / 2;
+    assert_eq!((1..=expected_events).collect::<Vec<_>>(), sequences, "events are not numbered consecutively");
+    let unique: HashSet<u64> = sequences.iter().cloned().collect();
+    assert_eq!(sequences.len(), unique.len());
+    Ok(())
+}
+
+/// Runs a writer outside units of work on one handle while another handle rolls back unit after
+/// unit, on a thread each. The writes made outside the units must survive the rollbacks, and the
+/// rolled back ones must not leak.
+pub fn writers_outside_rolled_back_units<R>(mut handles: Vec<R>) -> Result<(), RepositoryError> where R: TransactionRepository + Send + 'static {
+    assert_eq!(2, handles.len(), "expected a handle for each writer");
+    let mut unit_writer = handles.pop().expect("no handle");
+    let mut writer = handles.pop().expect("no handle");
+    let rolling_back = thread::spawn(move || -> Result<R, RepositoryError> {
+        for transaction_id in UPDATES + 1..=2 * UPDATES {
+            unit_writer.begin()?;
+            unit_writer.post_transaction(&deposit(transaction_id, 2, 1))?;
+            unit_writer.append_events(&[deposited(transaction_id)])?;
+            unit_writer.rollback()?;
+        }
+        Ok(unit_writer)
+    });
+    let writing = thread::spawn(move || -> Result<R, RepositoryError> {
+        for transaction_id in 1..=UPDATES {
+            writer.post_transaction(&deposit(transaction_id, 1, 1))?;
+        }
+        Ok(writer)
+    });
+    rolling_back.join().expect("a writer panicked")?;
+    let mut repository = writing.join().expect("a writer panicked")?;
+    for transaction_id in 1..=UPDATES {
+        assert!(repository.find_transaction_by_id(&transaction_id)?.is_some(), "a write was rolled back with another unit");
+    }
+    assert_eq!(UPDATES as usize, repository.find_transactions(&TransactionQuery::default())?.total, "a rolled back write leaked");
+    assert!(repository.unpublished_events(0)?.is_empty(), "a rolled back event leaked");
+    Ok(())
+}
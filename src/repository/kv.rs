@@ -0,0 +1,699 @@
+use std::borrow::Borrow;
+use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use redb::{AccessGuard, Database, Key, Range, ReadOnlyTable, ReadTransaction, ReadableTable, Table, TableDefinition, Value, WriteTransaction};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::domain::{Account, AccountRepository, AccountVersion, Amount, CaseId, ClientId, DisputeCase, DisputeCaseState, DisputeHistoryEntry, DisputeHold, RepositoryError, Transaction, TransactionDispute, TransactionId, TransactionPage, TransactionQuery, TransactionRepository, TransactionStatus, UnitOfWork};
+use crate::events::{DomainEvent, OutboxEntry};
+use super::{unit_already_open, InMemSnapshot};
+
+/// Values are stored as json, the format of the in-memory snapshot.
+const ACCOUNTS: TableDefinition<ClientId, &[u8]> = TableDefinition::new("accounts");
+/// Versions of every account, keyed by client and position in the history of the account.
+const ACCOUNT_VERSIONS: TableDefinition<(ClientId, u64), &[u8]> = TableDefinition::new("account_versions");
+const TRANSACTIONS: TableDefinition<TransactionId, &[u8]> = TableDefinition::new("transactions");
+const DISPUTE_CASES: TableDefinition<CaseId, &[u8]> = TableDefinition::new("dispute_cases");
+/// Dispute history of every transaction, keyed by transaction and position in the history.
+const DISPUTE_HISTORY: TableDefinition<(TransactionId, u64), &[u8]> = TableDefinition::new("dispute_history");
+const REQUESTS: TableDefinition<&str, ()> = TableDefinition::new("requests");
+/// Unpublished events by sequence number.
+const OUTBOX: TableDefinition<u64, &[u8]> = TableDefinition::new("outbox");
+const COUNTERS: TableDefinition<&str, u64> = TableDefinition::new("counters");
+
+const NEXT_EVENT_SEQUENCE: &str = "next_event_sequence";
+
+fn storage_error<E: Display>(error: E) -> RepositoryError {
+    RepositoryError::StorageError(error.to_string())
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, RepositoryError> {
+    serde_json::to_vec(value).map_err(storage_error)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, RepositoryError> {
+    serde_json::from_slice(bytes).map_err(storage_error)
+}
+
+/// A table read within the open unit of work, or from the last committed state.
+enum ReadTable<'a, K: Key + 'static, V: Value + 'static> {
+    Unit(Table<'a, K, V>),
+    Committed(ReadOnlyTable<K, V>),
+}
+
+impl<K: Key + 'static, V: Value + 'static> ReadTable<'_, K, V> {
+    fn get<'k>(&self, key: impl Borrow<K::SelfType<'k>>) -> Result<Option<AccessGuard<'_, V>>, RepositoryError> {
+        match self {
+            ReadTable::Unit(table) => table.get(key),
+            ReadTable::Committed(table) => table.get(key),
+        }.map_err(storage_error)
+    }
+
+    fn range<'k, KR>(&self, range: impl RangeBounds<KR> + 'k) -> Result<Range<'_, K, V>, RepositoryError> where KR: Borrow<K::SelfType<'k>> + 'k {
+        match self {
+            ReadTable::Unit(table) => table.range(range),
+            ReadTable::Committed(table) => table.range(range),
+        }.map_err(storage_error)
+    }
+
+    /// Decodes the values of the range, in key order.
+    fn values<'k, KR, T>(&self, range: impl RangeBounds<KR> + 'k) -> Result<Vec<T>, RepositoryError>
+        where KR: Borrow<K::SelfType<'k>> + 'k, T: DeserializeOwned, V: for<'v> Value<SelfType<'v> = &'v [u8]> {
+        self.range(range)?
+            .map(|entry| entry.map_err(storage_error).and_then(|(_, value)| decode(value.value())))
+            .collect()
+    }
+}
+
+enum Reader<'a> {
+    Unit(&'a WriteTransaction),
+    Committed(ReadTransaction),
+}
+
+impl Reader<'_> {
+    fn table<K: Key + 'static, V: Value + 'static>(&self, definition: TableDefinition<K, V>) -> Result<ReadTable<'_, K, V>, RepositoryError> {
+        match self {
+            Reader::Unit(write) => write.open_table(definition).map(ReadTable::Unit),
+            Reader::Committed(read) => read.open_table(definition).map(ReadTable::Committed),
+        }.map_err(storage_error)
+    }
+}
+
+/// The next position in a history keyed by owner and position.
+fn next_position(table: &Table<(u64, u64), &[u8]>, owner: u64) -> Result<u64, RepositoryError> {
+    let last = table.range((owner, 0)..=(owner, u64::MAX)).map_err(storage_error)?.next_back();
+    match last {
+        None => Ok(0),
+        Some(entry) => Ok(entry.map_err(storage_error)?.0.value().1 + 1),
+    }
+}
+
+/// The unit of work shared by the repositories of a pair.
+#[derive(Default)]
+struct KvUnit {
+    write: Option<WriteTransaction>,
+    /// Repositories of the pair that began the unit and did not close it yet.
+    open: usize,
+}
+
+/// Embedded key-value store holding the accounts and the transactions in a single file, created if
+/// missing. Every commit is durable once it returns.
+#[derive(Clone)]
+pub struct KvStore {
+    database: Arc<Database>,
+}
+
+impl KvStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, RepositoryError> {
+        let database = Database::create(path).map_err(storage_error)?;
+        let write = database.begin_write().map_err(storage_error)?;
+        // Create the tables up front so that reads never miss one.
+        write.open_table(ACCOUNTS).map_err(storage_error)?;
+        write.open_table(ACCOUNT_VERSIONS).map_err(storage_error)?;
+        write.open_table(TRANSACTIONS).map_err(storage_error)?;
+        write.open_table(DISPUTE_CASES).map_err(storage_error)?;
+        write.open_table(DISPUTE_HISTORY).map_err(storage_error)?;
+        write.open_table(REQUESTS).map_err(storage_error)?;
+        write.open_table(OUTBOX).map_err(storage_error)?;
+        write.open_table(COUNTERS).map_err(storage_error)?;
+        write.commit().map_err(storage_error)?;
+        Ok(KvStore {
+            database: Arc::new(database),
+        })
+    }
+
+    /// A pair of repositories over the store. The units of work of the pair are shared: the writes
+    /// of both are committed together when the last one commits, and discarded if either rolls
+    /// back. Writers of other pairs wait for the unit to close.
+    pub fn repositories(&self) -> (KvAccountRepository, KvTransactionRepository) {
+        let unit = Arc::new(Mutex::new(KvUnit::default()));
+        (KvAccountRepository { handle: KvHandle { database: self.database.clone(), unit: unit.clone(), in_unit: false } },
+         KvTransactionRepository { handle: KvHandle { database: self.database.clone(), unit, in_unit: false } })
+    }
+
+    /// Replaces the content of the store with the snapshot.
+    pub fn import(&self, snapshot: &InMemSnapshot) -> Result<(), RepositoryError> {
+        let write = self.database.begin_write().map_err(storage_error)?;
+        {
+            let mut accounts = write.open_table(ACCOUNTS).map_err(storage_error)?;
+            let mut account_versions = write.open_table(ACCOUNT_VERSIONS).map_err(storage_error)?;
+            let mut transactions = write.open_table(TRANSACTIONS).map_err(storage_error)?;
+            let mut dispute_cases = write.open_table(DISPUTE_CASES).map_err(storage_error)?;
+            let mut dispute_history = write.open_table(DISPUTE_HISTORY).map_err(storage_error)?;
+            let mut requests = write.open_table(REQUESTS).map_err(storage_error)?;
+            let mut outbox = write.open_table(OUTBOX).map_err(storage_error)?;
+            let mut counters = write.open_table(COUNTERS).map_err(storage_error)?;
+            accounts.retain(|_, _| false).map_err(storage_error)?;
+            account_versions.retain(|_, _| false).map_err(storage_error)?;
+            transactions.retain(|_, _| false).map_err(storage_error)?;
+            dispute_cases.retain(|_, _| false).map_err(storage_error)?;
+            dispute_history.retain(|_, _| false).map_err(storage_error)?;
+            requests.retain(|_, _| false).map_err(storage_error)?;
+            outbox.retain(|_, _| false).map_err(storage_error)?;
+
+            for account in &snapshot.accounts {
+                accounts.insert(account.client_id(), encode(account)?.as_slice()).map_err(storage_error)?;
+            }
+            for version in &snapshot.account_versions {
+                let position = next_position(&account_versions, version.account().client_id())?;
+                account_versions.insert((version.account().client_id(), position), encode(version)?.as_slice()).map_err(storage_error)?;
+            }
+            for transaction in &snapshot.transactions {
+                transactions.insert(transaction.transaction_id(), encode(transaction)?.as_slice()).map_err(storage_error)?;
+            }
+            for case in &snapshot.dispute_cases {
+                dispute_cases.insert(case.case_id(), encode(case)?.as_slice()).map_err(storage_error)?;
+            }
+            for entry in &snapshot.dispute_history {
+                let position = next_position(&dispute_history, entry.transaction_id())?;
+                dispute_history.insert((entry.transaction_id(), position), encode(entry)?.as_slice()).map_err(storage_error)?;
+            }
+            for request in &snapshot.processed_requests {
+                requests.insert(request.as_str(), ()).map_err(storage_error)?;
+            }
+            for entry in &snapshot.outbox {
+                outbox.insert(entry.sequence(), encode(entry)?.as_slice()).map_err(storage_error)?;
+            }
+            counters.insert(NEXT_EVENT_SEQUENCE, snapshot.next_event_sequence).map_err(storage_error)?;
+        }
+        write.commit().map_err(storage_error)
+    }
+
+    /// The committed content of the store, as a snapshot of the in-memory repositories.
+    pub fn export(&self) -> Result<InMemSnapshot, RepositoryError> {
+        let reader = Reader::Committed(self.database.begin_read().map_err(storage_error)?);
+        let processed_requests = reader.table(REQUESTS)?.range::<&str>(..)?
+            .map(|entry| entry.map(|(request, _)| request.value().to_owned()).map_err(storage_error))
+            .collect::<Result<_, _>>()?;
+        let next_event_sequence = reader.table(COUNTERS)?.get(NEXT_EVENT_SEQUENCE)?
+            .map(|sequence| sequence.value())
+            .unwrap_or_default();
+        let accounts = reader.table(ACCOUNTS)?.values::<ClientId, _>(..)?;
+        let account_versions = reader.table(ACCOUNT_VERSIONS)?.values::<(ClientId, u64), _>(..)?;
+        let transactions = reader.table(TRANSACTIONS)?.values::<TransactionId, _>(..)?;
+        let dispute_cases = reader.table(DISPUTE_CASES)?.values::<CaseId, _>(..)?;
+        let dispute_history = reader.table(DISPUTE_HISTORY)?.values::<(TransactionId, u64), _>(..)?;
+        let outbox = reader.table(OUTBOX)?.values::<u64, _>(..)?;
+        Ok(InMemSnapshot {
+            accounts,
+            account_versions,
+            transactions,
+            dispute_cases,
+            dispute_history,
+            processed_requests,
+            outbox,
+            next_event_sequence,
+        })
+    }
+}
+
+/// A repository of a pair over the store.
+struct KvHandle {
+    database: Arc<Database>,
+    unit: Arc<Mutex<KvUnit>>,
+    in_unit: bool,
+}
+
+impl KvHandle {
+    fn lock(&self) -> MutexGuard<'_, KvUnit> {
+        self.unit.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Runs the writes in the open unit of work of the pair, or in a transaction of their own
+    /// committed right away. Nothing is written if they fail.
+    fn write<T, F>(&self, f: F) -> Result<T, RepositoryError> where F: FnOnce(&WriteTransaction) -> Result<T, RepositoryError> {
+        let unit = self.lock();
+        match unit.write.as_ref() {
+            Some(write) => f(write),
+            None => {
+                let write = self.database.begin_write().map_err(storage_error)?;
+                let result = f(&write)?;
+                write.commit().map_err(storage_error)?;
+                Ok(result)
+            }
+        }
+    }
+
+    /// Runs the reads within the open unit of work of the pair, or over the last committed state.
+    fn read<T, F>(&self, f: F) -> Result<T, RepositoryError> where F: FnOnce(&Reader) -> Result<T, RepositoryError> {
+        let unit = self.lock();
+        match unit.write.as_ref() {
+            Some(write) => f(&Reader::Unit(write)),
+            None => f(&Reader::Committed(self.database.begin_read().map_err(storage_error)?)),
+        }
+    }
+
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        if self.in_unit {
+            return Err(unit_already_open());
+        }
+        let mut unit = self.lock();
+        if unit.write.is_none() {
+            unit.write = Some(self.database.begin_write().map_err(storage_error)?);
+        }
+        unit.open += 1;
+        drop(unit);
+        self.in_unit = true;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        if !self.in_unit {
+            return Ok(());
+        }
+        self.in_unit = false;
+        let mut unit = self.lock();
+        unit.open -= 1;
+        if unit.open > 0 {
+            return Ok(());
+        }
+        match unit.write.take() {
+            Some(write) => write.commit().map_err(storage_error),
+            None => Err(RepositoryError::InconsistencyDetected("the unit of work was rolled back".to_string())),
+        }
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        if !self.in_unit {
+            return Ok(());
+        }
+        self.in_unit = false;
+        let mut unit = self.lock();
+        unit.open -= 1;
+        match unit.write.take() {
+            Some(write) => write.abort().map_err(storage_error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for KvHandle {
+    fn drop(&mut self) {
+        let _ = self.rollback();
+    }
+}
+
+/// Account repository over a [`KvStore`], keyed by client id.
+pub struct KvAccountRepository {
+    handle: KvHandle,
+}
+
+impl UnitOfWork for KvAccountRepository {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        self.handle.begin()
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        self.handle.commit()
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        self.handle.rollback()
+    }
+}
+
+impl AccountRepository for KvAccountRepository {
+    fn find_account(&mut self, client_id: &ClientId) -> Result<Option<Account>, RepositoryError> {
+        self.handle.read(|reader| {
+            reader.table(ACCOUNTS)?.get(client_id)?
+                .map(|account| decode(account.value()))
+                .transpose()
+        })
+    }
+
+    fn open_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError> {
+        self.handle.write(|write| {
+            let mut accounts = write.open_table(ACCOUNTS).map_err(storage_error)?;
+            if accounts.get(client_id).map_err(storage_error)?.is_some() {
+                return Err(RepositoryError::EntityAlreadyExists(format!("account {}", client_id)));
+            }
+            let account = Account::new(*client_id);
+            accounts.insert(client_id, encode(&account)?.as_slice()).map_err(storage_error)?;
+            Ok(account)
+        })
+    }
+
+    fn update_account(&mut self, account: &Account, update: &Account) -> Result<(), RepositoryError> {
+        self.handle.write(|write| {
+            let mut accounts = write.open_table(ACCOUNTS).map_err(storage_error)?;
+            let current: Account = match accounts.get(account.client_id()).map_err(storage_error)? {
+                None => return Err(RepositoryError::EntityNotFound(format!("Account cannot be updated, it does not exist. {}", account.client_id()))),
+                Some(current) => decode(current.value())?,
+            };
+            // The write transaction excludes every other writer, comparing and setting within it
+            // is atomic.
+            if current.version() != account.version() {
+                return Err(RepositoryError::InconsistencyDetected(format!("{}", account.client_id())));
+            }
+            accounts.insert(account.client_id(), encode(update)?.as_slice()).map_err(storage_error)?;
+            Ok(())
+        })
+    }
+
+    fn account_visitor<F>(&mut self, mut f: F) -> Result<(), RepositoryError> where F: FnMut(&Account) {
+        let accounts: Vec<Account> = self.handle.read(|reader| reader.table(ACCOUNTS)?.values::<ClientId, _>(..))?;
+        accounts.iter().for_each(|account| {f(account)});
+        Ok(())
+    }
+
+    fn append_account_version(&mut self, version: &AccountVersion) -> Result<(), RepositoryError> {
+        self.handle.write(|write| {
+            let mut account_versions = write.open_table(ACCOUNT_VERSIONS).map_err(storage_error)?;
+            let client_id = version.account().client_id();
+            let position = next_position(&account_versions, client_id)?;
+            account_versions.insert((client_id, position), encode(version)?.as_slice()).map_err(storage_error)?;
+            Ok(())
+        })
+    }
+
+    fn find_account_versions(&mut self, client_id: &ClientId) -> Result<Vec<AccountVersion>, RepositoryError> {
+        self.handle.read(|reader| reader.table(ACCOUNT_VERSIONS)?.values((*client_id, 0)..=(*client_id, u64::MAX)))
+    }
+}
+
+/// Transaction repository over a [`KvStore`], keyed by transaction id.
+pub struct KvTransactionRepository {
+    handle: KvHandle,
+}
+
+impl KvTransactionRepository {
+    fn update_transaction<F>(&mut self, transaction_id: &TransactionId, f: F) -> Result<(), RepositoryError> where F: FnOnce(&mut Transaction) {
+        self.handle.write(|write| {
+            let mut transactions = write.open_table(TRANSACTIONS).map_err(storage_error)?;
+            let mut transaction: Transaction = match transactions.get(transaction_id).map_err(storage_error)? {
+                None => return Err(RepositoryError::EntityNotFound(transaction_id.to_string())),
+                Some(transaction) => decode(transaction.value())?,
+            };
+            f(&mut transaction);
+            transactions.insert(transaction_id, encode(&transaction)?.as_slice()).map_err(storage_error)?;
+            Ok(())
+        })
+    }
+}
+
+impl UnitOfWork for KvTransactionRepository {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        self.handle.begin()
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        self.handle.commit()
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        self.handle.rollback()
+    }
+}
+
+impl TransactionRepository for KvTransactionRepository {
+    fn post_transaction(&mut self, transaction: &Transaction) -> Result<(), RepositoryError> {
+        self.handle.write(|write| {
+            let mut transactions = write.open_table(TRANSACTIONS).map_err(storage_error)?;
+            if transactions.get(transaction.transaction_id()).map_err(storage_error)?.is_some() {
+                return Err(RepositoryError::EntityAlreadyExists(transaction.transaction_id().to_string()));
+            }
+            transactions.insert(transaction.transaction_id(), encode(transaction)?.as_slice()).map_err(storage_error)?;
+            Ok(())
+        })
+    }
+
+    fn update_transaction_status(&mut self, transaction_id: &TransactionId, status: &TransactionStatus) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| tx.set_status(status.to_owned()))
+    }
+
+    fn update_transaction_dispute(&mut self, transaction_id: &TransactionId, dispute: &TransactionDispute) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| tx.set_dispute(dispute.to_owned()))
+    }
+
+    fn update_transaction_holds(&mut self, transaction_id: &TransactionId, holds: &[DisputeHold], charged_back: &Amount) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| tx.set_holds(holds.to_vec(), charged_back.to_owned()))
+    }
+
+    fn find_transaction_by_id(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, RepositoryError> {
+        self.handle.read(|reader| {
+            reader.table(TRANSACTIONS)?.get(transaction_id)?
+                .map(|transaction| decode(transaction.value()))
+                .transpose()
+        })
+    }
+
+    fn find_transactions(&mut self, query: &TransactionQuery) -> Result<TransactionPage, RepositoryError> {
+        let from = query.from_transaction_id.unwrap_or(TransactionId::MIN);
+        let to = query.to_transaction_id.unwrap_or(TransactionId::MAX);
+        let limit = query.limit.unwrap_or(usize::MAX);
+        self.handle.read(|reader| {
+            let mut transactions = Vec::new();
+            let mut total = 0;
+            for entry in reader.table(TRANSACTIONS)?.range(from..=to)? {
+                let transaction: Transaction = decode(entry.map_err(storage_error)?.1.value())?;
+                if query.matches(&transaction) {
+                    if total >= query.offset && transactions.len() < limit {
+                        transactions.push(transaction);
+                    }
+                    total += 1;
+                }
+            }
+            Ok(TransactionPage {
+                transactions,
+                total,
+            })
+        })
+    }
+
+    fn register_request(&mut self, key: &str) -> Result<(), RepositoryError> {
+        self.handle.write(|write| {
+            let mut requests = write.open_table(REQUESTS).map_err(storage_error)?;
+            if requests.insert(key, ()).map_err(storage_error)?.is_some() {
+                return Err(RepositoryError::EntityAlreadyExists(key.to_owned()));
+            }
+            Ok(())
+        })
+    }
+
+    fn post_dispute_case(&mut self, case: &DisputeCase) -> Result<(), RepositoryError> {
+        self.handle.write(|write| {
+            let mut dispute_cases = write.open_table(DISPUTE_CASES).map_err(storage_error)?;
+            if dispute_cases.get(case.case_id()).map_err(storage_error)?.is_some() {
+                return Err(RepositoryError::EntityAlreadyExists(format!("case {}", case.case_id())));
+            }
+            dispute_cases.insert(case.case_id(), encode(case)?.as_slice()).map_err(storage_error)?;
+            Ok(())
+        })
+    }
+
+    fn update_dispute_case_state(&mut self, case_id: &CaseId, state: &DisputeCaseState) -> Result<(), RepositoryError> {
+        self.handle.write(|write| {
+            let mut dispute_cases = write.open_table(DISPUTE_CASES).map_err(storage_error)?;
+            let mut case: DisputeCase = match dispute_cases.get(case_id).map_err(storage_error)? {
+                None => return Err(RepositoryError::EntityNotFound(format!("case {}", case_id))),
+                Some(case) => decode(case.value())?,
+            };
+            case.set_state(state.to_owned());
+            dispute_cases.insert(case_id, encode(&case)?.as_slice()).map_err(storage_error)?;
+            Ok(())
+        })
+    }
+
+    fn find_dispute_case(&mut self, case_id: &CaseId) -> Result<Option<DisputeCase>, RepositoryError> {
+        self.handle.read(|reader| {
+            reader.table(DISPUTE_CASES)?.get(case_id)?
+                .map(|case| decode(case.value()))
+                .transpose()
+        })
+    }
+
+    fn append_events(&mut self, events: &[DomainEvent]) -> Result<(), RepositoryError> {
+        self.handle.write(|write| {
+            let mut outbox = write.open_table(OUTBOX).map_err(storage_error)?;
+            let mut counters = write.open_table(COUNTERS).map_err(storage_error)?;
+            let mut sequence = counters.get(NEXT_EVENT_SEQUENCE).map_err(storage_error)?
+                .map(|sequence| sequence.value())
+                .unwrap_or_default();
+            for event in events {
+                sequence += 1;
+                outbox.insert(sequence, encode(&OutboxEntry::new(sequence, event.clone()))?.as_slice()).map_err(storage_error)?;
+            }
+            counters.insert(NEXT_EVENT_SEQUENCE, sequence).map_err(storage_error)?;
+            Ok(())
+        })
+    }
+
+    /// The committed events only, the events of an open unit of work are not relayed until then.
+    fn unpublished_events(&mut self, after: u64) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        let reader = Reader::Committed(self.handle.database.begin_read().map_err(storage_error)?);
+        let events = reader.table(OUTBOX)?.values::<u64, _>((Bound::Excluded(after), Bound::Unbounded));
+        events
+    }
+
+    fn mark_events_published(&mut self, sequence: u64) -> Result<(), RepositoryError> {
+        self.handle.write(|write| {
+            let mut outbox = write.open_table(OUTBOX).map_err(storage_error)?;
+            outbox.retain_in(..=sequence, |_, _| false).map_err(storage_error)
+        })
+    }
+
+    fn append_dispute_history(&mut self, entry: &DisputeHistoryEntry) -> Result<(), RepositoryError> {
+        self.handle.write(|write| {
+            let transactions = write.open_table(TRANSACTIONS).map_err(storage_error)?;
+            if transactions.get(entry.transaction_id()).map_err(storage_error)?.is_none() {
+                return Err(RepositoryError::EntityNotFound(entry.transaction_id().to_string()));
+            }
+            let mut dispute_history = write.open_table(DISPUTE_HISTORY).map_err(storage_error)?;
+            let position = next_position(&dispute_history, entry.transaction_id())?;
+            dispute_history.insert((entry.transaction_id(), position), encode(entry)?.as_slice()).map_err(storage_error)?;
+            Ok(())
+        })
+    }
+
+    fn find_dispute_history(&mut self, transaction_id: &TransactionId) -> Result<Vec<DisputeHistoryEntry>, RepositoryError> {
+        self.handle.read(|reader| reader.table(DISPUTE_HISTORY)?.values((*transaction_id, 0)..=(*transaction_id, u64::MAX)))
+    }
+
+    fn dispute_history_visitor(&mut self, f: &mut dyn FnMut(&DisputeHistoryEntry)) -> Result<(), RepositoryError> {
+        let history: Vec<DisputeHistoryEntry> = self.handle.read(|reader| reader.table(DISPUTE_HISTORY)?.values::<(TransactionId, u64), _>(..))?;
+        history.iter().for_each(|entry| {f(entry)});
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use crate::domain::{Account, AccountPoint, AccountRepository, Operation, RepositoryError, TransactionDispute, TransactionRepository, TransactionRequest, TransactionService, UnitOfWork};
+    use crate::events::{EventSubscriber, OutboxEntry};
+    use crate::repository::{conformance, InMemAccountRepository, InMemSnapshot, InMemTransactionRepository, KvStore};
+
+    /// Subscriber that never gets to handle the events, they stay in the outbox.
+    struct Stalled;
+
+    impl EventSubscriber for Stalled {
+        fn on_event(&mut self, _entry: &OutboxEntry) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn handled(&self) -> Option<u64> {
+            Some(0)
+        }
+    }
+
+    /// A store file of its own for the test, removed when dropped.
+    struct StoreFile(PathBuf);
+
+    impl StoreFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("rails-kv-{}-{}.redb", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            StoreFile(path)
+        }
+    }
+
+    impl Drop for StoreFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn request(operation: Operation, transaction_id: u64, amount: &str) -> TransactionRequest {
+        TransactionRequest::builder(operation, 1, transaction_id)
+            .amount(BigDecimal::from_str(amount).unwrap())
+            .build()
+    }
+
+    #[test]
+    fn conforms() {
+        // The store file is removed once open, the store keeps it until dropped.
+        let store = |name: &str| KvStore::open(&StoreFile::new(name).0).unwrap();
+        conformance::account_repository(|| store("conforms-accounts").repositories().0).unwrap();
+        conformance::transaction_repository(|| store("conforms-transactions").repositories().1).unwrap();
+
+        let accounts = store("concurrent-accounts");
+        conformance::concurrent_account_writers((0..4).map(|_| accounts.repositories().0).collect()).unwrap();
+        let transactions = store("concurrent-transactions");
+        conformance::concurrent_transaction_writers((0..4).map(|_| transactions.repositories().1).collect()).unwrap();
+        let transactions = store("rolled-back-units");
+        conformance::writers_outside_rolled_back_units((0..2).map(|_| transactions.repositories().1).collect()).unwrap();
+    }
+
+    #[test]
+    fn compares_and_sets_accounts() -> Result<(), Box<dyn std::error::Error>> {
+        let file = StoreFile::new("cas");
+        let (mut accounts, _) = KvStore::open(&file.0)?.repositories();
+        let account = accounts.open_account(&1)?;
+        assert!(matches!(accounts.open_account(&1), Err(RepositoryError::EntityAlreadyExists(_))));
+
+        let update = Account::from_parts(1, BigDecimal::from(5), BigDecimal::from(0), false, None, account.version() + 1);
+        accounts.update_account(&account, &update)?;
+        // The account changed since it was read.
+        let stale = Account::from_parts(1, BigDecimal::from(7), BigDecimal::from(0), false, None, account.version() + 1);
+        assert!(matches!(accounts.update_account(&account, &stale), Err(RepositoryError::InconsistencyDetected(_))));
+        assert!(matches!(accounts.update_account(&Account::new(2), &stale), Err(RepositoryError::EntityNotFound(_))));
+        assert_eq!(Some(update), accounts.find_account(&1)?);
+        Ok(())
+    }
+
+    #[test]
+    fn units_of_work_span_both_repositories() -> Result<(), Box<dyn std::error::Error>> {
+        let file = StoreFile::new("unit");
+        let store = KvStore::open(&file.0)?;
+        let (accounts, transactions) = store.repositories();
+        let mut transaction_service = TransactionService::new(accounts, transactions);
+        let (mut accounts, mut transactions) = store.repositories();
+        transaction_service.process_transaction(request(Operation::Deposit, 1, "10"))?;
+
+        accounts.begin()?;
+        transactions.begin()?;
+        accounts.open_account(&2)?;
+        transactions.register_request("deposit,2,2,1,")?;
+        assert!(accounts.find_account(&2)?.is_some());
+        transactions.rollback()?;
+        accounts.rollback()?;
+        assert!(accounts.find_account(&2)?.is_none());
+        transactions.register_request("deposit,2,2,1,")?;
+
+        // The writes of the service are durable once processed.
+        transaction_service.process_transaction(request(Operation::Dispute, 1, "4"))?;
+        drop((transaction_service, accounts, transactions, store));
+        let (accounts, transactions) = KvStore::open(&file.0)?.repositories();
+        let mut transaction_service = TransactionService::new(accounts, transactions);
+        let account = transaction_service.get_account_status(&1)?;
+        assert_eq!(BigDecimal::from(4), account.held());
+        assert!(matches!(transaction_service.find_transaction(&1)?.map(|transaction| transaction.dispute().clone()), Some(TransactionDispute::Disputed)));
+        assert_eq!(1, transaction_service.dispute_history(&1)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_from_and_to_the_snapshot() -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction_service = TransactionService::new(InMemAccountRepository::default(), InMemTransactionRepository::default());
+        transaction_service.register_subscriber(Box::new(Stalled));
+        transaction_service.process_transaction(request(Operation::Deposit, 1, "10"))?;
+        transaction_service.process_transaction(request(Operation::Deposit, 2, "3"))?;
+        transaction_service.process_transaction(request(Operation::Dispute, 1, "10"))?;
+        let (accounts, transactions) = transaction_service.into_repositories();
+        let snapshot = InMemSnapshot::capture(&accounts, &transactions);
+
+        let file = StoreFile::new("migrate");
+        let store = KvStore::open(&file.0)?;
+        store.import(&snapshot)?;
+        let (accounts, transactions) = store.repositories();
+        let mut transaction_service = TransactionService::new(accounts, transactions);
+        transaction_service.register_subscriber(Box::new(Stalled));
+        transaction_service.process_transaction(request(Operation::Resolve, 1, "10"))?;
+        transaction_service.process_transaction(request(Operation::Deposit, 3, "1"))?;
+        assert_eq!(BigDecimal::from(14), transaction_service.get_account_status(&1)?.available());
+        let account = transaction_service.get_account_at(&1, &AccountPoint::AfterTransaction(2))?;
+        assert_eq!(Some(BigDecimal::from(13)), account.map(|account| account.available()));
+
+        let (accounts, transactions) = store.export()?.restore();
+        let mut transaction_service = TransactionService::new(accounts, transactions);
+        assert_eq!(BigDecimal::from(14), transaction_service.get_account_status(&1)?.available());
+        assert_eq!(3, transaction_service.find_transactions(&Default::default())?.total);
+        assert_eq!(2, transaction_service.dispute_history(&1)?.len());
+        assert_eq!(5, transaction_service.into_repositories().1.unpublished_events(0)?.len());
+        Ok(())
+    }
+}
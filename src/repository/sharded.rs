@@ -0,0 +1,759 @@
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Condvar, LockResult, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::domain::{Account, AccountRepository, AccountVersion, Amount, CaseId, ClientId, DisputeCase, DisputeCaseState, DisputeHistoryEntry, DisputeHold, RepositoryError, Transaction, TransactionDispute, TransactionId, TransactionPage, TransactionQuery, TransactionRepository, TransactionStatus, UnitOfWork};
+use crate::events::{DomainEvent, OutboxEntry};
+use super::unit_already_open;
+
+/// Number of shards of every map, each behind its own lock.
+const SHARDS: usize = 16;
+
+fn recover<T>(result: LockResult<T>) -> T {
+    result.unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Map split in shards, each behind its own lock, so that the writers of keys in different shards
+/// do not wait for each other.
+struct ShardedMap<K, V> {
+    shards: Vec<RwLock<HashMap<K, V>>>,
+    hasher: RandomState,
+}
+
+impl<K, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        ShardedMap {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedMap<K, V> {
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    fn read(&self, key: &K) -> RwLockReadGuard<'_, HashMap<K, V>> {
+        recover(self.shard(key).read())
+    }
+
+    fn write(&self, key: &K) -> RwLockWriteGuard<'_, HashMap<K, V>> {
+        recover(self.shard(key).write())
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        self.read(key).get(key).cloned()
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.read(key).contains_key(key)
+    }
+
+    /// Visits every value, one shard at a time.
+    fn for_each<F>(&self, mut f: F) where F: FnMut(&K, &V) {
+        for shard in self.shards.iter() {
+            recover(shard.read()).iter().for_each(|(key, value)| f(key, value));
+        }
+    }
+}
+
+#[derive(Default)]
+struct AccountStore {
+    accounts: ShardedMap<ClientId, Account>,
+    versions: ShardedMap<ClientId, Vec<AccountVersion>>,
+    /// Clients whose account is in the open unit of work of a handle.
+    reserved: Mutex<HashSet<ClientId>>,
+    released: Condvar,
+}
+
+impl AccountStore {
+    /// Waits until no unit of work holds the account. No unit can reserve it while the guard is
+    /// held.
+    fn wait_released(&self, client_id: &ClientId) -> MutexGuard<'_, HashSet<ClientId>> {
+        let mut reserved = recover(self.reserved.lock());
+        while reserved.contains(client_id) {
+            reserved = recover(self.released.wait(reserved));
+        }
+        reserved
+    }
+}
+
+/// Writes of the open unit of work of a handle.
+#[derive(Default)]
+struct AccountUnit {
+    accounts: HashMap<ClientId, Account>,
+    versions: Vec<AccountVersion>,
+    reserved: HashSet<ClientId>,
+}
+
+/// Account repository that many services can share, e.g. one per thread. Handles are cheap to
+/// clone, `Send` and `Sync`, and see the same accounts. The accounts are spread over shards
+/// locked independently.
+///
+/// Every handle stages its own unit of work. A unit reserves the accounts it reads until it is
+/// committed or rolled back, the units of other handles wait for them, so that two units never
+/// update the same account from the same version. Writes outside a unit wait for them too. Units
+/// should reserve a single account, the one of the request, as units reserving several in
+/// different orders can wait for each other forever.
+#[derive(Default)]
+pub struct ShardedAccountRepository {
+    store: Arc<AccountStore>,
+    unit: Option<AccountUnit>,
+}
+
+impl ShardedAccountRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserves the account for the open unit of work, waiting for the unit holding it if any.
+    fn reserve(&mut self, client_id: &ClientId) {
+        let unit = match self.unit.as_mut() {
+            Some(unit) if !unit.reserved.contains(client_id) => unit,
+            _ => return,
+        };
+        self.store.wait_released(client_id).insert(*client_id);
+        unit.reserved.insert(*client_id);
+    }
+
+    /// Closes the unit of work, releasing its accounts.
+    fn close_unit(&mut self) -> Option<AccountUnit> {
+        let unit = self.unit.take()?;
+        if !unit.reserved.is_empty() {
+            let mut reserved = recover(self.store.reserved.lock());
+            unit.reserved.iter().for_each(|client_id| { reserved.remove(client_id); });
+            self.store.released.notify_all();
+        }
+        Some(unit)
+    }
+}
+
+impl Clone for ShardedAccountRepository {
+    /// A new handle on the same accounts, without a unit of work open.
+    fn clone(&self) -> Self {
+        ShardedAccountRepository {
+            store: self.store.clone(),
+            unit: None,
+        }
+    }
+}
+
+impl Drop for ShardedAccountRepository {
+    fn drop(&mut self) {
+        self.close_unit();
+    }
+}
+
+impl UnitOfWork for ShardedAccountRepository {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        if self.unit.is_some() {
+            return Err(unit_already_open());
+        }
+        self.unit = Some(AccountUnit::default());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        // The accounts are still reserved while applied.
+        if let Some(unit) = self.unit.as_mut() {
+            for (client_id, account) in unit.accounts.drain() {
+                self.store.accounts.write(&client_id).insert(client_id, account);
+            }
+            for version in unit.versions.drain(..) {
+                let client_id = version.account().client_id();
+                self.store.versions.write(&client_id).entry(client_id).or_default().push(version);
+            }
+        }
+        self.close_unit();
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        self.close_unit();
+        Ok(())
+    }
+}
+
+impl AccountRepository for ShardedAccountRepository {
+    fn find_account(&mut self, client_id: &ClientId) -> Result<Option<Account>, RepositoryError> {
+        self.reserve(client_id);
+        match self.unit.as_ref().and_then(|unit| unit.accounts.get(client_id)) {
+            Some(account) => Ok(Some(account.clone())),
+            None => Ok(self.store.accounts.get(client_id)),
+        }
+    }
+
+    fn open_account(&mut self, client_id: &ClientId) -> Result<Account, RepositoryError> {
+        self.reserve(client_id);
+        let exists = || RepositoryError::EntityAlreadyExists(format!("account {}", client_id));
+        let account = Account::new(*client_id);
+        match self.unit.as_mut() {
+            None => {
+                let _released = self.store.wait_released(client_id);
+                match self.store.accounts.write(client_id).entry(*client_id) {
+                    Entry::Occupied(_) => return Err(exists()),
+                    Entry::Vacant(vacant) => { vacant.insert(account.clone()); }
+                }
+            }
+            Some(unit) => {
+                if unit.accounts.contains_key(client_id) || self.store.accounts.contains_key(client_id) {
+                    return Err(exists());
+                }
+                unit.accounts.insert(*client_id, account.clone());
+            }
+        }
+        Ok(account)
+    }
+
+    fn update_account(&mut self, account: &Account, update: &Account) -> Result<(), RepositoryError> {
+        let client_id = account.client_id();
+        self.reserve(&client_id);
+        let conflict = || RepositoryError::InconsistencyDetected(format!("{}", client_id));
+        let not_found = || RepositoryError::EntityNotFound(format!("Account cannot be updated, it does not exist. {}", client_id));
+        match self.unit.as_mut() {
+            None => {
+                let _released = self.store.wait_released(&client_id);
+                let mut accounts = self.store.accounts.write(&client_id);
+                match accounts.get_mut(&client_id) {
+                    None => Err(not_found()),
+                    Some(current) if current.version() != account.version() => Err(conflict()),
+                    Some(current) => {
+                        *current = update.clone();
+                        Ok(())
+                    }
+                }
+            }
+            Some(unit) => {
+                let current = match unit.accounts.get(&client_id) {
+                    Some(current) => Some(current.clone()),
+                    None => self.store.accounts.get(&client_id),
+                };
+                match current {
+                    None => Err(not_found()),
+                    Some(current) if current.version() != account.version() => Err(conflict()),
+                    Some(_) => {
+                        unit.accounts.insert(client_id, update.clone());
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    fn account_visitor<F>(&mut self, mut f: F) -> Result<(), RepositoryError> where F: FnMut(&Account) {
+        let staged = self.unit.as_ref().map(|unit| &unit.accounts);
+        self.store.accounts.for_each(|client_id, account| {
+            match staged.and_then(|staged| staged.get(client_id)) {
+                Some(staged) => f(staged),
+                None => f(account),
+            }
+        });
+        if let Some(staged) = staged {
+            staged.iter()
+                .filter(|(client_id, _)| !self.store.accounts.contains_key(client_id))
+                .for_each(|(_, account)| f(account));
+        }
+        Ok(())
+    }
+
+    fn append_account_version(&mut self, version: &AccountVersion) -> Result<(), RepositoryError> {
+        match self.unit.as_mut() {
+            None => {
+                let client_id = version.account().client_id();
+                self.store.versions.write(&client_id).entry(client_id).or_default().push(version.clone());
+            }
+            Some(unit) => unit.versions.push(version.clone()),
+        }
+        Ok(())
+    }
+
+    fn find_account_versions(&mut self, client_id: &ClientId) -> Result<Vec<AccountVersion>, RepositoryError> {
+        let mut versions = self.store.versions.get(client_id).unwrap_or_default();
+        if let Some(unit) = self.unit.as_ref() {
+            versions.extend(unit.versions.iter().filter(|version| version.account().client_id() == *client_id).cloned());
+        }
+        Ok(versions)
+    }
+}
+
+#[derive(Default)]
+struct Outbox {
+    entries: VecDeque<OutboxEntry>,
+    next_sequence: u64,
+}
+
+impl Outbox {
+    fn append(&mut self, events: impl IntoIterator<Item=DomainEvent>) {
+        for event in events {
+            self.next_sequence += 1;
+            self.entries.push_back(OutboxEntry::new(self.next_sequence, event));
+        }
+    }
+}
+
+#[derive(Default)]
+struct TransactionStore {
+    transactions: ShardedMap<TransactionId, Transaction>,
+    dispute_history: ShardedMap<TransactionId, Vec<DisputeHistoryEntry>>,
+    dispute_cases: ShardedMap<CaseId, DisputeCase>,
+    processed_requests: ShardedMap<String, ()>,
+    outbox: Mutex<Outbox>,
+    /// Serializes the commits, which check that the entities a unit created were not created by
+    /// another writer in the meantime, with the writes creating entities outside a unit.
+    commit: Mutex<()>,
+}
+
+/// Writes of the open unit of work of a handle.
+#[derive(Default)]
+struct TransactionUnit {
+    transactions: HashMap<TransactionId, Transaction>,
+    /// Transactions posted by the unit, the others were updated.
+    posted: HashSet<TransactionId>,
+    dispute_history: Vec<DisputeHistoryEntry>,
+    dispute_cases: HashMap<CaseId, DisputeCase>,
+    /// Dispute cases opened by the unit, the others were updated.
+    posted_cases: HashSet<CaseId>,
+    processed_requests: HashSet<String>,
+    /// Events are numbered when committed.
+    events: Vec<DomainEvent>,
+}
+
+/// Transaction repository that many services can share, e.g. one per thread. Handles are cheap to
+/// clone, `Send` and `Sync`, and see the same transactions. The maps are spread over shards locked
+/// independently.
+///
+/// Every handle stages its own unit of work. A commit fails, applying nothing, if another writer
+/// created a transaction, dispute case or request key the unit created too. Updates of existing
+/// transactions are kept from racing by the account reservations of [`ShardedAccountRepository`]:
+/// the service refuses the disputes, resolves and chargebacks of a transaction that do not come
+/// from its client, so every update of a transaction is made under the reservation of one account.
+#[derive(Default)]
+pub struct ShardedTransactionRepository {
+    store: Arc<TransactionStore>,
+    unit: Option<TransactionUnit>,
+}
+
+impl ShardedTransactionRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The transaction as seen by the open unit of work, if any.
+    fn transaction(&self, transaction_id: &TransactionId) -> Option<Transaction> {
+        self.unit.as_ref()
+            .and_then(|unit| unit.transactions.get(transaction_id).cloned())
+            .or_else(|| self.store.transactions.get(transaction_id))
+    }
+
+    /// Changes a transaction, in the open unit of work if any.
+    fn update_transaction<F>(&mut self, transaction_id: &TransactionId, f: F) -> Result<(), RepositoryError> where F: FnOnce(&mut Transaction) {
+        let not_found = || RepositoryError::EntityNotFound(transaction_id.to_string());
+        match self.unit.as_mut() {
+            None => {
+                let mut transactions = self.store.transactions.write(transaction_id);
+                let transaction = transactions.get_mut(transaction_id).ok_or_else(not_found)?;
+                f(transaction);
+            }
+            Some(unit) => {
+                let transaction = match unit.transactions.entry(*transaction_id) {
+                    Entry::Occupied(occupied) => occupied.into_mut(),
+                    Entry::Vacant(vacant) => vacant.insert(self.store.transactions.get(transaction_id).ok_or_else(not_found)?),
+                };
+                f(transaction);
+            }
+        }
+        Ok(())
+    }
+
+    /// The dispute case as seen by the open unit of work, if any.
+    fn dispute_case(&self, case_id: &CaseId) -> Option<DisputeCase> {
+        self.unit.as_ref()
+            .and_then(|unit| unit.dispute_cases.get(case_id).cloned())
+            .or_else(|| self.store.dispute_cases.get(case_id))
+    }
+}
+
+impl Clone for ShardedTransactionRepository {
+    /// A new handle on the same transactions, without a unit of work open.
+    fn clone(&self) -> Self {
+        ShardedTransactionRepository {
+            store: self.store.clone(),
+            unit: None,
+        }
+    }
+}
+
+impl UnitOfWork for ShardedTransactionRepository {
+    fn begin(&mut self) -> Result<(), RepositoryError> {
+        if self.unit.is_some() {
+            return Err(unit_already_open());
+        }
+        self.unit = Some(TransactionUnit::default());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), RepositoryError> {
+        let unit = match self.unit.take() {
+            None => return Ok(()),
+            Some(unit) => unit,
+        };
+        let store = &self.store;
+        let _commit = recover(store.commit.lock());
+        if let Some(transaction_id) = unit.posted.iter().find(|transaction_id| store.transactions.contains_key(transaction_id)) {
+            return Err(RepositoryError::EntityAlreadyExists(transaction_id.to_string()));
+        }
+        if let Some(case_id) = unit.posted_cases.iter().find(|case_id| store.dispute_cases.contains_key(case_id)) {
+            return Err(RepositoryError::EntityAlreadyExists(format!("case {}", case_id)));
+        }
+        if let Some(key) = unit.processed_requests.iter().find(|key| store.processed_requests.contains_key(key)) {
+            return Err(RepositoryError::EntityAlreadyExists(key.to_owned()));
+        }
+
+        for (transaction_id, transaction) in unit.transactions {
+            store.transactions.write(&transaction_id).insert(transaction_id, transaction);
+        }
+        for entry in unit.dispute_history {
+            let transaction_id = entry.transaction_id();
+            store.dispute_history.write(&transaction_id).entry(transaction_id).or_default().push(entry);
+        }
+        for (case_id, case) in unit.dispute_cases {
+            store.dispute_cases.write(&case_id).insert(case_id, case);
+        }
+        for key in unit.processed_requests {
+            store.processed_requests.write(&key).insert(key, ());
+        }
+        recover(store.outbox.lock()).append(unit.events);
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), RepositoryError> {
+        self.unit = None;
+        Ok(())
+    }
+}
+
+impl TransactionRepository for ShardedTransactionRepository {
+    fn post_transaction(&mut self, transaction: &Transaction) -> Result<(), RepositoryError> {
+        let transaction_id = transaction.transaction_id();
+        let exists = || RepositoryError::EntityAlreadyExists(transaction_id.to_string());
+        match self.unit.as_mut() {
+            None => {
+                let _commit = recover(self.store.commit.lock());
+                let mut transactions = self.store.transactions.write(&transaction_id);
+                if transactions.contains_key(&transaction_id) {
+                    return Err(exists());
+                }
+                transactions.insert(transaction_id, transaction.to_owned());
+            }
+            Some(unit) => {
+                if unit.transactions.contains_key(&transaction_id) || self.store.transactions.contains_key(&transaction_id) {
+                    return Err(exists());
+                }
+                unit.transactions.insert(transaction_id, transaction.to_owned());
+                unit.posted.insert(transaction_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_transaction_status(&mut self, transaction_id: &TransactionId, status: &TransactionStatus) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| tx.set_status(status.to_owned()))
+    }
+
+    fn update_transaction_dispute(&mut self, transaction_id: &TransactionId, dispute: &TransactionDispute) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| tx.set_dispute(dispute.to_owned()))
+    }
+
+    fn update_transaction_holds(&mut self, transaction_id: &TransactionId, holds: &[DisputeHold], charged_back: &Amount) -> Result<(), RepositoryError> {
+        self.update_transaction(transaction_id, |tx| tx.set_holds(holds.to_vec(), charged_back.to_owned()))
+    }
+
+    fn find_transaction_by_id(&mut self, transaction_id: &TransactionId) -> Result<Option<Transaction>, RepositoryError> {
+        Ok(self.transaction(transaction_id))
+    }
+
+    fn find_transactions(&mut self, query: &TransactionQuery) -> Result<TransactionPage, RepositoryError> {
+        let staged = self.unit.as_ref().map(|unit| &unit.transactions);
+        let mut selected: Vec<Transaction> = Vec::new();
+        self.store.transactions.for_each(|transaction_id, transaction| {
+            if staged.is_none_or(|staged| !staged.contains_key(transaction_id)) && query.matches(transaction) {
+                selected.push(transaction.clone());
+            }
+        });
+        selected.extend(staged.into_iter().flat_map(|staged| staged.values())
+            .filter(|transaction| query.matches(transaction))
+            .cloned());
+        selected.sort_by_key(|transaction| transaction.transaction_id());
+        let total = selected.len();
+        let transactions = selected.into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+        Ok(TransactionPage {
+            transactions,
+            total,
+        })
+    }
+
+    fn register_request(&mut self, key: &str) -> Result<(), RepositoryError> {
+        let key = key.to_owned();
+        let inserted = match self.unit.as_mut() {
+            None => {
+                let _commit = recover(self.store.commit.lock());
+                self.store.processed_requests.write(&key).insert(key.clone(), ()).is_none()
+            }
+            Some(unit) => !self.store.processed_requests.contains_key(&key) && unit.processed_requests.insert(key.clone()),
+        };
+        match inserted {
+            true => Ok(()),
+            false => Err(RepositoryError::EntityAlreadyExists(key)),
+        }
+    }
+
+    fn post_dispute_case(&mut self, case: &DisputeCase) -> Result<(), RepositoryError> {
+        let case_id = case.case_id();
+        let exists = || RepositoryError::EntityAlreadyExists(format!("case {}", case_id));
+        match self.unit.as_mut() {
+            None => {
+                let _commit = recover(self.store.commit.lock());
+                let mut dispute_cases = self.store.dispute_cases.write(&case_id);
+                if dispute_cases.contains_key(&case_id) {
+                    return Err(exists());
+                }
+                dispute_cases.insert(case_id, case.to_owned());
+            }
+            Some(unit) => {
+                if unit.dispute_cases.contains_key(&case_id) || self.store.dispute_cases.contains_key(&case_id) {
+                    return Err(exists());
+                }
+                unit.dispute_cases.insert(case_id, case.to_owned());
+                unit.posted_cases.insert(case_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_dispute_case_state(&mut self, case_id: &CaseId, state: &DisputeCaseState) -> Result<(), RepositoryError> {
+        let not_found = || RepositoryError::EntityNotFound(format!("case {}", case_id));
+        match self.unit.as_mut() {
+            None => {
+                let mut dispute_cases = self.store.dispute_cases.write(case_id);
+                dispute_cases.get_mut(case_id).ok_or_else(not_found)?.set_state(state.to_owned());
+            }
+            Some(unit) => {
+                let case = match unit.dispute_cases.entry(*case_id) {
+                    Entry::Occupied(occupied) => occupied.into_mut(),
+                    Entry::Vacant(vacant) => vacant.insert(self.store.dispute_cases.get(case_id).ok_or_else(not_found)?),
+                };
+                case.set_state(state.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn find_dispute_case(&mut self, case_id: &CaseId) -> Result<Option<DisputeCase>, RepositoryError> {
+        Ok(self.dispute_case(case_id))
+    }
+
+    fn append_events(&mut self, events: &[DomainEvent]) -> Result<(), RepositoryError> {
+        match self.unit.as_mut() {
+            None => recover(self.store.outbox.lock()).append(events.iter().cloned()),
+            Some(unit) => unit.events.extend(events.iter().cloned()),
+        }
+        Ok(())
+    }
+
+    /// The committed events only, the events of an open unit of work are not relayed until then.
+    fn unpublished_events(&mut self, after: u64) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        let outbox = recover(self.store.outbox.lock());
+        let start = outbox.entries.partition_point(|entry| entry.sequence() <= after);
+        Ok(outbox.entries.range(start..).cloned().collect())
+    }
+
+    fn mark_events_published(&mut self, sequence: u64) -> Result<(), RepositoryError> {
+        let mut outbox = recover(self.store.outbox.lock());
+        while outbox.entries.front().is_some_and(|entry| entry.sequence() <= sequence) {
+            outbox.entries.pop_front();
+        }
+        Ok(())
+    }
+
+    fn append_dispute_history(&mut self, entry: &DisputeHistoryEntry) -> Result<(), RepositoryError> {
+        let transaction_id = entry.transaction_id();
+        if self.transaction(&transaction_id).is_none() {
+            return Err(RepositoryError::EntityNotFound(transaction_id.to_string()));
+        }
+        match self.unit.as_mut() {
+            None => self.store.dispute_history.write(&transaction_id).entry(transaction_id).or_default().push(entry.clone()),
+            Some(unit) => unit.dispute_history.push(entry.clone()),
+        }
+        Ok(())
+    }
+
+    fn find_dispute_history(&mut self, transaction_id: &TransactionId) -> Result<Vec<DisputeHistoryEntry>, RepositoryError> {
+        let mut history = self.store.dispute_history.get(transaction_id).unwrap_or_default();
+        if let Some(unit) = self.unit.as_ref() {
+            history.extend(unit.dispute_history.iter().filter(|entry| entry.transaction_id() == *transaction_id).cloned());
+        }
+        Ok(history)
+    }
+
+    fn dispute_history_visitor(&mut self, f: &mut dyn FnMut(&DisputeHistoryEntry)) -> Result<(), RepositoryError> {
+        self.store.dispute_history.for_each(|_, entries| entries.iter().for_each(&mut *f));
+        if let Some(unit) = self.unit.as_ref() {
+            unit.dispute_history.iter().for_each(f);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use bigdecimal::BigDecimal;
+    use crate::domain::{AccountRepository, Amount, ClientId, Operation, TransactionRepository, TransactionRequest, TransactionService, TransactionStatus};
+    use crate::events::{EventSubscriber, OutboxEntry};
+    use crate::repository::{conformance, ShardedAccountRepository, ShardedTransactionRepository};
+
+    /// Subscriber that never gets to handle the events, they stay in the outbox.
+    struct Stalled;
+
+    impl EventSubscriber for Stalled {
+        fn on_event(&mut self, _entry: &OutboxEntry) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn handled(&self) -> Option<u64> {
+            Some(0)
+        }
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn handles_are_send_and_sync() {
+        assert_send_sync::<ShardedAccountRepository>();
+        assert_send_sync::<ShardedTransactionRepository>();
+    }
+
+    #[test]
+    fn conforms() {
+        conformance::account_repository(ShardedAccountRepository::new).unwrap();
+        conformance::transaction_repository(ShardedTransactionRepository::new).unwrap();
+        let accounts = ShardedAccountRepository::new();
+        conformance::concurrent_account_writers(vec![accounts.clone(), accounts.clone(), accounts.clone(), accounts]).unwrap();
+        let transactions = ShardedTransactionRepository::new();
+        conformance::concurrent_transaction_writers(vec![transactions.clone(), transactions.clone(), transactions.clone(), transactions]).unwrap();
+        let transactions = ShardedTransactionRepository::new();
+        conformance::writers_outside_rolled_back_units(vec![transactions.clone(), transactions]).unwrap();
+    }
+
+    #[test]
+    fn concurrent_services_lose_no_update() {
+        const THREADS: u64 = 8;
+        const REQUESTS: u64 = 500;
+        const CLIENTS: u64 = 4;
+        let accounts = ShardedAccountRepository::new();
+        let transactions = ShardedTransactionRepository::new();
+
+        // Every thread deposits 2 and withdraws 1 in turns, on accounts the other threads use too.
+        let writers: Vec<_> = (0..THREADS).map(|thread| {
+            let (accounts, transactions) = (accounts.clone(), transactions.clone());
+            thread::spawn(move || {
+                let mut service = TransactionService::new(accounts, transactions);
+                service.register_subscriber(Box::new(Stalled));
+                let mut applied = vec![(0u64, 0u64); CLIENTS as usize];
+                for request in 0..REQUESTS {
+                    let tx = thread * REQUESTS + request + 1;
+                    let client: ClientId = request / 2 % CLIENTS;
+                    let (operation, amount) = if request % 2 == 0 { (Operation::Deposit, 2) } else { (Operation::Withdrawal, 1) };
+                    let request = TransactionRequest::builder(operation, client, tx).amount(BigDecimal::from(amount)).build();
+                    if matches!(service.process_transaction(request).unwrap().status, TransactionStatus::Applied) {
+                        match operation {
+                            Operation::Deposit => applied[client as usize].0 += 1,
+                            _ => applied[client as usize].1 += 1,
+                        }
+                    }
+                }
+                applied
+            })
+        }).collect();
+        let mut applied = vec![(0u64, 0u64); CLIENTS as usize];
+        for writer in writers {
+            for (client, (deposits, withdrawals)) in writer.join().unwrap().into_iter().enumerate() {
+                applied[client].0 += deposits;
+                applied[client].1 += withdrawals;
+            }
+        }
+
+        let mut accounts = accounts;
+        let mut transactions = transactions;
+        for (client, (deposits, withdrawals)) in applied.into_iter().enumerate() {
+            let account = accounts.find_account(&(client as ClientId)).unwrap().unwrap();
+            assert_eq!(BigDecimal::from(2 * deposits - withdrawals), account.available());
+            assert_eq!(deposits + withdrawals, account.version());
+            assert_eq!((deposits + withdrawals) as usize, accounts.find_account_versions(&(client as ClientId)).unwrap().len());
+        }
+        assert_eq!((THREADS * REQUESTS) as usize, transactions.find_transactions(&Default::default()).unwrap().total);
+        assert_eq!((THREADS * REQUESTS) as usize, transactions.unpublished_events(0).unwrap().len());
+    }
+
+    #[test]
+    fn concurrent_posts_of_a_transaction_commit_once() {
+        let accounts = ShardedAccountRepository::new();
+        let transactions = ShardedTransactionRepository::new();
+        let writers: Vec<_> = (0..4u64).map(|client| {
+            let (accounts, transactions) = (accounts.clone(), transactions.clone());
+            thread::spawn(move || {
+                let mut service = TransactionService::new(accounts, transactions);
+                let deposit = TransactionRequest::builder(Operation::Deposit, client, 1).amount(BigDecimal::from(1)).build();
+                service.process_transaction(deposit).is_ok()
+            })
+        }).collect();
+        let committed = writers.into_iter().map(|writer| writer.join().unwrap()).filter(|committed| *committed).count();
+
+        let mut accounts = accounts;
+        let mut total = BigDecimal::from(0);
+        accounts.account_visitor(|account| total += account.available()).unwrap();
+        assert_eq!(1, committed);
+        assert_eq!(BigDecimal::from(1), total);
+    }
+
+    #[test]
+    fn concurrent_disputes_of_a_transaction_lose_no_hold() {
+        const TRANSACTIONS: u64 = 200;
+        let accounts = ShardedAccountRepository::new();
+        let transactions = ShardedTransactionRepository::new();
+        let mut service = TransactionService::new(accounts.clone(), transactions.clone());
+        for transaction_id in 1..=TRANSACTIONS {
+            service.process_transaction(TransactionRequest::builder(Operation::Deposit, 1, transaction_id).amount(BigDecimal::from(2)).build()).unwrap();
+        }
+        service.process_transaction(TransactionRequest::builder(Operation::Deposit, 2, TRANSACTIONS + 1).amount(BigDecimal::from(TRANSACTIONS)).build()).unwrap();
+
+        // Both clients dispute every transaction of client 1 at the same time.
+        let writers: Vec<_> = (1..=2u64).map(|client| {
+            let (accounts, transactions) = (accounts.clone(), transactions.clone());
+            thread::spawn(move || {
+                let mut service = TransactionService::new(accounts, transactions);
+                (1..=TRANSACTIONS).filter(|transaction_id| {
+                    let dispute = TransactionRequest::builder(Operation::Dispute, client, *transaction_id).amount(BigDecimal::from(1)).build();
+                    service.process_transaction(dispute).is_ok()
+                }).count()
+            })
+        }).collect();
+        let applied: Vec<usize> = writers.into_iter().map(|writer| writer.join().unwrap()).collect();
+
+        let mut accounts = accounts;
+        let mut transactions = transactions;
+        let mut holds = Amount::from(0);
+        for transaction_id in 1..=TRANSACTIONS {
+            let transaction = transactions.find_transaction_by_id(&transaction_id).unwrap().unwrap();
+            holds += transaction.disputes().iter().map(|hold| hold.held()).sum::<Amount>();
+        }
+        assert_eq!(vec![TRANSACTIONS as usize, 0], applied);
+        assert_eq!(BigDecimal::from(TRANSACTIONS), accounts.find_account(&1).unwrap().unwrap().held());
+        assert_eq!(BigDecimal::from(0), accounts.find_account(&2).unwrap().unwrap().held());
+        assert_eq!(BigDecimal::from(TRANSACTIONS), holds);
+    }
+}
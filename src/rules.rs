@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::ops::Add;
+use bigdecimal::{BigDecimal, Zero};
+use serde::{Deserialize, Serialize};
+use crate::domain::{Amount, Operation, Timestamp, Transaction};
+
+const SECONDS_PER_DAY: Timestamp = 24 * 60 * 60;
+
+/// At most `withdrawals` withdrawals among the last `transactions` transactions of a client.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WithdrawalsPerTransactions {
+    pub withdrawals: usize,
+    pub transactions: usize,
+}
+
+/// At most `withdrawals` withdrawals within any window of `seconds` seconds.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WithdrawalsPerWindow {
+    pub withdrawals: usize,
+    pub seconds: Timestamp,
+}
+
+/// Per client risk limits. Every limit is optional, an empty configuration allows everything.
+/// The limits over time only count the transactions that carry a timestamp, so that an input
+/// gives the same results whenever it is processed.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RulesConfig {
+    /// Largest amount a single withdrawal can take.
+    pub max_withdrawal_amount: Option<Amount>,
+    pub max_withdrawals_per_transactions: Option<WithdrawalsPerTransactions>,
+    pub max_withdrawals_per_window: Option<WithdrawalsPerWindow>,
+    /// Largest amount deposited plus withdrawn by a client in a calendar day (UTC).
+    pub max_daily_volume: Option<Amount>,
+    /// Lock the account of a client that violates a rule.
+    pub lock_on_violation: bool,
+}
+
+/// What a client did recently, as far as the rules need to know. Kept with the account of the
+/// client, so that it is stored and rolled back along with the balance.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClientActivity {
+    /// Whether each of the last transactions was a withdrawal, newest last.
+    recent_withdrawals: VecDeque<bool>,
+    /// Times of the withdrawals within the largest window, oldest first.
+    withdrawal_times: VecDeque<Timestamp>,
+    day: Timestamp,
+    day_volume: Amount,
+}
+
+/// Evaluates the risk rules against the transactions of each client before they are applied.
+pub struct RulesEngine {
+    config: RulesConfig,
+}
+
+impl RulesEngine {
+    pub fn new(config: RulesConfig) -> Self {
+        RulesEngine {
+            config,
+        }
+    }
+
+    pub fn lock_on_violation(&self) -> bool {
+        self.config.lock_on_violation
+    }
+
+    /// Checks the transaction against the rules and the activity of its client, returns the name
+    /// of the first violated rule.
+    pub fn evaluate(&self, transaction: &Transaction, activity: &ClientActivity) -> Option<String> {
+        let is_withdrawal = matches!(transaction.operation(), Operation::Withdrawal);
+        if !is_withdrawal && !matches!(transaction.operation(), Operation::Deposit) {
+            return None;
+        }
+        let amount = transaction.amount().unwrap_or_else(BigDecimal::zero);
+        let time = transaction.timestamp();
+
+        if is_withdrawal {
+            if let Some(max_amount) = &self.config.max_withdrawal_amount {
+                if amount.gt(max_amount) {
+                    return Some("max_withdrawal_amount".to_string());
+                }
+            }
+            if let Some(limit) = &self.config.max_withdrawals_per_transactions {
+                let previous = limit.transactions.saturating_sub(1);
+                let withdrawals = activity.recent_withdrawals.iter().rev()
+                    .take(previous)
+                    .filter(|withdrawal| **withdrawal)
+                    .count();
+                if withdrawals + 1 > limit.withdrawals {
+                    return Some("max_withdrawals_per_transactions".to_string());
+                }
+            }
+            if let (Some(limit), Some(time)) = (&self.config.max_withdrawals_per_window, time) {
+                let withdrawals = activity.withdrawal_times.iter()
+                    .filter(|withdrawal_time| time.saturating_sub(**withdrawal_time) < limit.seconds)
+                    .count();
+                if withdrawals + 1 > limit.withdrawals {
+                    return Some("max_withdrawals_per_window".to_string());
+                }
+            }
+        }
+        if let (Some(max_volume), Some(time)) = (&self.config.max_daily_volume, time) {
+            let day_volume = if activity.day == time / SECONDS_PER_DAY { activity.day_volume.clone() } else { BigDecimal::zero() };
+            if day_volume.add(&amount).gt(max_volume) {
+                return Some("max_daily_volume".to_string());
+            }
+        }
+        None
+    }
+
+    /// Records a transaction applied to the account of the client in its activity.
+    pub fn record(&self, activity: &mut ClientActivity, transaction: &Transaction) {
+        let is_withdrawal = matches!(transaction.operation(), Operation::Withdrawal);
+        if !is_withdrawal && !matches!(transaction.operation(), Operation::Deposit) {
+            return;
+        }
+        let time = transaction.timestamp();
+
+        if let Some(limit) = &self.config.max_withdrawals_per_transactions {
+            activity.recent_withdrawals.push_back(is_withdrawal);
+            while activity.recent_withdrawals.len() > limit.transactions {
+                activity.recent_withdrawals.pop_front();
+            }
+        }
+        if let (Some(limit), Some(time)) = (&self.config.max_withdrawals_per_window, time) {
+            if is_withdrawal {
+                activity.withdrawal_times.push_back(time);
+            }
+            while activity.withdrawal_times.front().is_some_and(|oldest| time.saturating_sub(*oldest) >= limit.seconds) {
+                activity.withdrawal_times.pop_front();
+            }
+        }
+        if let (Some(_), Some(time)) = (&self.config.max_daily_volume, time) {
+            let day = time / SECONDS_PER_DAY;
+            if activity.day != day {
+                activity.day = day;
+                activity.day_volume = BigDecimal::zero();
+            }
+            let amount = transaction.amount().unwrap_or_else(BigDecimal::zero);
+            activity.day_volume = activity.day_volume.clone().add(&amount);
+        }
+    }
+}
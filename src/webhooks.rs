@@ -0,0 +1,583 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::mem::take;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, LockResult, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{debug, error, warn};
+use crate::events::{EventSubscriber, OutboxEntry};
+
+/// Header carrying the HMAC-SHA256 signature of the payload, `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "X-Rails-Signature";
+
+fn default_events() -> Vec<String> {
+    vec!["account_locked".to_string(), "charged_back".to_string()]
+}
+
+/// An HTTP endpoint notified of the events it subscribes to.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookEndpoint {
+    /// Plain http url, e.g. `http://localhost:8080/notifications`.
+    pub url: String,
+    /// Key the payloads are signed with.
+    pub secret: String,
+    /// Event types delivered to the endpoint, account locks and chargebacks by default.
+    #[serde(default = "default_events")]
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub endpoints: Vec<WebhookEndpoint>,
+    /// Deliveries attempted per event and endpoint before giving up.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every following retry up to `max_backoff_ms`.
+    pub initial_backoff_ms: u64,
+    /// Also the delay between retries of the deliveries that could not be dead lettered.
+    pub max_backoff_ms: u64,
+    /// Connect, read and write timeout of every attempt.
+    pub timeout_ms: u64,
+    /// File the deliveries that failed permanently are appended to, one json object per line.
+    /// Without one they are only logged.
+    pub dead_letter: Option<String>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            endpoints: Vec::new(),
+            max_attempts: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 10_000,
+            timeout_ms: 5_000,
+            dead_letter: None,
+        }
+    }
+}
+
+/// Signs a payload with the secret of an endpoint, as sent in the signature header.
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// A delivery that failed permanently.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub url: String,
+    pub attempts: u32,
+    pub error: String,
+    pub entry: OutboxEntry,
+}
+
+enum DeliveryError {
+    /// Worth retrying: transport errors, server errors, timeouts and throttling.
+    Transient(String),
+    /// The endpoint refused the payload, retrying would not help.
+    Permanent(String),
+}
+
+/// Where an endpoint url points to.
+struct Target {
+    url: String,
+    host: String,
+    path: String,
+    secret: String,
+    events: Vec<String>,
+}
+
+impl Target {
+    fn parse(endpoint: &WebhookEndpoint) -> Result<Self, io::Error> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported webhook url {}", endpoint.url));
+        let location = endpoint.url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (host, path) = match location.find('/') {
+            Some(index) => (&location[..index], &location[index..]),
+            None => (location, "/"),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let host = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+        Ok(Target {
+            url: endpoint.url.clone(),
+            host,
+            path: path.to_string(),
+            secret: endpoint.secret.clone(),
+            events: endpoint.events.clone(),
+        })
+    }
+
+    fn subscribes(&self, entry: &OutboxEntry) -> bool {
+        self.events.iter().any(|kind| kind == entry.event().kind())
+    }
+
+    /// POSTs the payload once, returns the response status.
+    fn post(&self, entry: &OutboxEntry, payload: &[u8], timeout: Duration) -> Result<u16, io::Error> {
+        let address = self.host.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Cannot resolve {}", self.host)))?;
+        let stream = TcpStream::connect_timeout(&address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut writer = BufWriter::new(&stream);
+        write!(writer, "POST {} HTTP/1.1\r\n", self.path)?;
+        write!(writer, "Host: {}\r\n", self.host)?;
+        write!(writer, "Content-Type: application/json\r\n")?;
+        write!(writer, "Content-Length: {}\r\n", payload.len())?;
+        write!(writer, "X-Rails-Event: {}\r\n", entry.event().kind())?;
+        write!(writer, "X-Rails-Sequence: {}\r\n", entry.sequence())?;
+        write!(writer, "{}: {}\r\n", SIGNATURE_HEADER, sign(&self.secret, payload))?;
+        write!(writer, "Connection: close\r\n\r\n")?;
+        writer.write_all(payload)?;
+        writer.flush()?;
+        drop(writer);
+
+        let mut status_line = String::new();
+        BufReader::new(&stream).read_line(&mut status_line)?;
+        status_line.split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Malformed response {:?}", status_line)))
+    }
+
+    fn deliver(&self, entry: &OutboxEntry, payload: &[u8], timeout: Duration) -> Result<(), DeliveryError> {
+        match self.post(entry, payload, timeout) {
+            Ok(status) if (200..300).contains(&status) => Ok(()),
+            Ok(status) if status == 408 || status == 429 || status >= 500 => {
+                Err(DeliveryError::Transient(format!("HTTP status {}", status)))
+            }
+            Ok(status) => Err(DeliveryError::Permanent(format!("HTTP status {}", status))),
+            Err(err) => Err(DeliveryError::Transient(err.to_string())),
+        }
+    }
+}
+
+/// A delivery that failed and could not be written to the dead letters either.
+struct Unhandled {
+    target: usize,
+    entry: OutboxEntry,
+    attempts: u32,
+}
+
+/// Delivers the events on a background worker so that processing never waits for the endpoints.
+struct DeliveryWorker {
+    targets: Vec<Target>,
+    config: WebhookConfig,
+    dead_letters: Option<BufWriter<File>>,
+    /// Deliveries neither delivered nor written to the dead letters, in sequence order. They are
+    /// retried before the next event, and while no event comes.
+    unhandled: Vec<Unhandled>,
+}
+
+impl DeliveryWorker {
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self.config.initial_backoff_ms.saturating_mul(1 << retry.min(20));
+        Duration::from_millis(delay.min(self.config.max_backoff_ms))
+    }
+
+    /// Delivers the event to the endpoints subscribing to it. The deliveries that fail and cannot
+    /// be written to the dead letters are kept to be retried.
+    fn process(&mut self, entry: &OutboxEntry) {
+        for target in 0..self.targets.len() {
+            if self.targets[target].subscribes(entry) {
+                self.deliver(target, entry, self.config.max_attempts, 0);
+            }
+        }
+    }
+
+    /// Retries the deliveries kept, once each.
+    fn retry_unhandled(&mut self) {
+        for unhandled in take(&mut self.unhandled) {
+            self.deliver(unhandled.target, &unhandled.entry, 1, unhandled.attempts);
+        }
+    }
+
+    /// Sequence of the first event not handled for good, if any.
+    fn first_unhandled(&self) -> Option<u64> {
+        self.unhandled.first().map(|unhandled| unhandled.entry.sequence())
+    }
+
+    /// Delivers the event to the target, with up to `max_attempts` attempts, then writes it to the
+    /// dead letters if it failed. `attempts` counts the earlier attempts.
+    fn deliver(&mut self, target: usize, entry: &OutboxEntry, max_attempts: u32, mut attempts: u32) {
+        let payload = match serde_json::to_vec(entry) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!(sequence = entry.sequence(), error = %err, "Event could not be serialized");
+                self.unhandled.push(Unhandled { target, entry: entry.clone(), attempts });
+                return;
+            }
+        };
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let endpoint = &self.targets[target];
+        let mut tries = 0;
+        let failure = loop {
+            tries += 1;
+            attempts += 1;
+            match endpoint.deliver(entry, &payload, timeout) {
+                Ok(()) => break None,
+                Err(DeliveryError::Permanent(error)) => break Some(error),
+                Err(DeliveryError::Transient(error)) if tries >= max_attempts => break Some(error),
+                Err(DeliveryError::Transient(error)) => {
+                    debug!(sequence = entry.sequence(), url = %endpoint.url, attempts, %error, "Event delivery failed, will retry");
+                    thread::sleep(self.backoff(tries - 1))
+                }
+            }
+        };
+        if let Some(error) = failure {
+            let dead_letter = DeadLetter {
+                url: endpoint.url.clone(),
+                attempts,
+                error,
+                entry: entry.clone(),
+            };
+            if !self.dead_letter(&dead_letter) {
+                self.unhandled.push(Unhandled { target, entry: entry.clone(), attempts });
+            }
+        }
+    }
+
+    /// Returns whether the dead letter was written, or there is no dead letter file to write to.
+    fn dead_letter(&mut self, dead_letter: &DeadLetter) -> bool {
+        warn!(sequence = dead_letter.entry.sequence(), url = %dead_letter.url, attempts = dead_letter.attempts, error = %dead_letter.error, "Event could not be delivered");
+        if let Some(writer) = &mut self.dead_letters {
+            let written = serde_json::to_writer(&mut *writer, dead_letter)
+                .map_err(io::Error::from)
+                .and_then(|_| writeln!(writer))
+                .and_then(|_| writer.flush());
+            if let Err(err) = written {
+                error!(sequence = dead_letter.entry.sequence(), error = %err, "Dead letter could not be written");
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn recover<T>(result: LockResult<T>) -> T {
+    result.unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// How far the worker got through the events, which it processes in sequence order.
+#[derive(Default)]
+struct Progress {
+    /// Last event processed, delivered or not.
+    processed: u64,
+    /// Last event delivered or written to the dead letters, before the first one that was not.
+    handled: u64,
+}
+
+#[derive(Default)]
+struct SharedProgress {
+    progress: Mutex<Progress>,
+    changed: Condvar,
+}
+
+impl SharedProgress {
+    /// Records the last event processed and the first one still not handled for good, the events
+    /// from it on are not handled for good either.
+    fn record(&self, processed: u64, first_unhandled: Option<u64>) {
+        let mut progress = recover(self.progress.lock());
+        progress.processed = processed;
+        progress.handled = first_unhandled.map_or(processed, |sequence| sequence - 1);
+        self.changed.notify_all();
+    }
+}
+
+/// Subscriber posting the events to the configured webhook endpoints. Deliveries run on a
+/// background worker, an event counts as handled once delivered or written to the dead letters.
+/// Dropping the dispatcher waits for the pending deliveries. Events in flight when the process
+/// stops are posted again by the next run with the same sequence header, endpoints skip the
+/// sequences they already got.
+pub struct WebhookDispatcher {
+    sender: Option<Sender<OutboxEntry>>,
+    worker: Option<JoinHandle<()>>,
+    progress: Arc<SharedProgress>,
+    /// Last event handed over to the worker.
+    sent: u64,
+}
+
+impl WebhookDispatcher {
+    pub fn start(config: WebhookConfig) -> Result<Self, io::Error> {
+        let targets = config.endpoints.iter()
+            .map(Target::parse)
+            .collect::<Result<Vec<Target>, io::Error>>()?;
+        let dead_letters = match &config.dead_letter {
+            Some(filename) => Some(BufWriter::new(OpenOptions::new().create(true).append(true).open(filename)?)),
+            None => None,
+        };
+        let mut worker = DeliveryWorker {
+            targets,
+            config,
+            dead_letters,
+            unhandled: Vec::new(),
+        };
+        let progress = Arc::new(SharedProgress::default());
+        let worker_progress = progress.clone();
+        let (sender, receiver) = channel::<OutboxEntry>();
+        let handle = thread::Builder::new()
+            .name("webhooks".to_string())
+            .spawn(move || {
+                let mut processed = 0;
+                loop {
+                    let received = match worker.unhandled.is_empty() {
+                        true => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                        false => receiver.recv_timeout(Duration::from_millis(worker.config.max_backoff_ms)),
+                    };
+                    if let Err(RecvTimeoutError::Disconnected) = received {
+                        break;
+                    }
+                    // The deliveries kept are retried first, so that the events are handled in order.
+                    worker.retry_unhandled();
+                    if let Ok(entry) = received {
+                        worker.process(&entry);
+                        processed = entry.sequence();
+                    }
+                    worker_progress.record(processed, worker.first_unhandled());
+                }
+            })?;
+        Ok(WebhookDispatcher {
+            sender: Some(sender),
+            worker: Some(handle),
+            progress,
+            sent: 0,
+        })
+    }
+}
+
+impl EventSubscriber for WebhookDispatcher {
+    fn on_event(&mut self, entry: &OutboxEntry) -> Result<(), io::Error> {
+        match &self.sender {
+            Some(sender) if sender.send(entry.clone()).is_ok() => {
+                self.sent = entry.sequence();
+                Ok(())
+            }
+            _ => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Webhook worker stopped")),
+        }
+    }
+
+    fn handled(&self) -> Option<u64> {
+        Some(recover(self.progress.progress.lock()).handled)
+    }
+
+    fn flush(&mut self) {
+        let mut progress = recover(self.progress.progress.lock());
+        while progress.processed < self.sent && !self.worker.as_ref().is_none_or(|worker| worker.is_finished()) {
+            progress = recover(self.progress.changed.wait_timeout(progress, Duration::from_millis(100))).0;
+        }
+    }
+}
+
+impl Drop for WebhookDispatcher {
+    fn drop(&mut self) {
+        // Closing the channel lets the worker finish the pending deliveries and stop.
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("Webhook worker panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use bigdecimal::BigDecimal;
+    use crate::events::{DomainEvent, EventSubscriber, OutboxEntry};
+    use crate::webhooks::{sign, DeadLetter, WebhookConfig, WebhookDispatcher, WebhookEndpoint, SIGNATURE_HEADER};
+
+    struct ReceivedRequest {
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    /// Stand-in endpoint answering the scripted statuses in order, one per connection, and
+    /// reporting every request it received.
+    fn stand_in_server(statuses: Vec<u16>) -> (String, Receiver<ReceivedRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+                    }
+                }
+                let length: usize = headers.get("content-length").unwrap().parse().unwrap();
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(stream, "HTTP/1.1 {} Scripted\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                sender.send(ReceivedRequest { headers, body }).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn config(endpoints: Vec<WebhookEndpoint>) -> WebhookConfig {
+        WebhookConfig {
+            endpoints,
+            max_attempts: 3,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 5,
+            ..WebhookConfig::default()
+        }
+    }
+
+    fn endpoint(url: &str) -> WebhookEndpoint {
+        serde_json::from_str(&format!(r#"{{ "url": "{}", "secret": "s3cret" }}"#, url)).unwrap()
+    }
+
+    fn chargeback(sequence: u64) -> OutboxEntry {
+        OutboxEntry::new(sequence, DomainEvent::ChargedBack {
+            client_id: 1,
+            transaction_id: 1,
+            case_id: None,
+            amount: BigDecimal::from(10),
+        })
+    }
+
+    #[test]
+    fn delivers_signed_locks_and_chargebacks() {
+        let (url, requests) = stand_in_server(vec![200, 200]);
+        let mut dispatcher = WebhookDispatcher::start(config(vec![endpoint(&url)])).unwrap();
+        dispatcher.on_event(&OutboxEntry::new(1, DomainEvent::Deposited { client_id: 1, transaction_id: 1, amount: BigDecimal::from(10) })).unwrap();
+        dispatcher.on_event(&chargeback(2)).unwrap();
+        dispatcher.on_event(&OutboxEntry::new(3, DomainEvent::AccountLocked { client_id: 1, transaction_id: 2 })).unwrap();
+        drop(dispatcher);
+
+        let received: Vec<ReceivedRequest> = requests.iter().collect();
+        assert_eq!(2, received.len());
+        for (request, sequence) in received.iter().zip([2, 3]) {
+            assert_eq!(&sign("s3cret", &request.body), request.headers.get(&SIGNATURE_HEADER.to_lowercase()).unwrap());
+            let entry: OutboxEntry = serde_json::from_slice(&request.body).unwrap();
+            assert_eq!(sequence, entry.sequence());
+        }
+        assert_eq!("charged_back", received[0].headers.get("x-rails-event").unwrap());
+    }
+
+    #[test]
+    fn retries_with_backoff_then_dead_letters() {
+        let dead_letter_file = std::env::temp_dir().join(format!("rails-dead-letters-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&dead_letter_file);
+
+        // The first event goes through on the third attempt, the second one never does.
+        let (url, requests) = stand_in_server(vec![503, 500, 200, 500, 500, 500]);
+        let (refusing_url, _refused) = stand_in_server(vec![400, 422]);
+        let mut config = config(vec![endpoint(&url), endpoint(&refusing_url)]);
+        config.dead_letter = Some(dead_letter_file.to_string_lossy().to_string());
+        let mut dispatcher = WebhookDispatcher::start(config).unwrap();
+        dispatcher.on_event(&chargeback(1)).unwrap();
+        dispatcher.on_event(&chargeback(2)).unwrap();
+        drop(dispatcher);
+        assert_eq!(6, requests.iter().count());
+
+        let dead_letters: Vec<DeadLetter> = std::fs::read_to_string(&dead_letter_file).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&dead_letter_file).unwrap();
+        let summary: Vec<(u64, &str, u32)> = dead_letters.iter()
+            .map(|dead_letter| (dead_letter.entry.sequence(), dead_letter.url.as_str(), dead_letter.attempts))
+            .collect();
+        // Refused payloads are not retried.
+        assert_eq!(vec![(1, refusing_url.as_str(), 1), (2, url.as_str(), 3), (2, refusing_url.as_str(), 1)], summary);
+    }
+
+    #[test]
+    fn acknowledges_events_once_delivered_or_dead_lettered() {
+        let dead_letter_file = std::env::temp_dir().join(format!("rails-acknowledged-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&dead_letter_file);
+
+        let (url, _requests) = stand_in_server(vec![200, 400]);
+        let mut config = config(vec![endpoint(&url)]);
+        config.dead_letter = Some(dead_letter_file.to_string_lossy().to_string());
+        let mut dispatcher = WebhookDispatcher::start(config).unwrap();
+        assert_eq!(Some(0), dispatcher.handled());
+        dispatcher.on_event(&chargeback(1)).unwrap();
+        dispatcher.on_event(&OutboxEntry::new(2, DomainEvent::Deposited { client_id: 1, transaction_id: 2, amount: BigDecimal::from(10) })).unwrap();
+        dispatcher.on_event(&chargeback(3)).unwrap();
+        dispatcher.flush();
+        assert_eq!(Some(3), dispatcher.handled());
+        drop(dispatcher);
+        assert_eq!(1, std::fs::read_to_string(&dead_letter_file).unwrap().lines().count());
+        std::fs::remove_file(&dead_letter_file).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn keeps_events_whose_dead_letter_was_not_written() {
+        let (url, _requests) = stand_in_server(vec![400]);
+        let mut config = config(vec![endpoint(&url)]);
+        config.dead_letter = Some("/dev/full".to_string());
+        let mut dispatcher = WebhookDispatcher::start(config).unwrap();
+        dispatcher.on_event(&chargeback(1)).unwrap();
+        dispatcher.on_event(&OutboxEntry::new(2, DomainEvent::Deposited { client_id: 1, transaction_id: 2, amount: BigDecimal::from(10) })).unwrap();
+        dispatcher.flush();
+        // The events after the lost one are not acknowledged either, to be relayed in order.
+        assert_eq!(Some(0), dispatcher.handled());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn acknowledges_events_once_the_endpoint_recovers() {
+        // The first event is refused and cannot be dead lettered, the endpoint then accepts it.
+        let (url, requests) = stand_in_server(vec![400, 200, 200]);
+        let mut config = config(vec![endpoint(&url)]);
+        config.dead_letter = Some("/dev/full".to_string());
+        // Retried along with the next event only, not while idle.
+        config.max_backoff_ms = 60_000;
+        let mut dispatcher = WebhookDispatcher::start(config).unwrap();
+        dispatcher.on_event(&chargeback(1)).unwrap();
+        dispatcher.flush();
+        assert_eq!(Some(0), dispatcher.handled());
+        dispatcher.on_event(&chargeback(2)).unwrap();
+        dispatcher.flush();
+        assert_eq!(Some(2), dispatcher.handled());
+        drop(dispatcher);
+        let sequences: Vec<u64> = requests.iter()
+            .map(|request| serde_json::from_slice::<OutboxEntry>(&request.body).unwrap().sequence())
+            .collect();
+        assert_eq!(vec![1, 1, 2], sequences);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn retries_unhandled_events_while_idle() {
+        let (url, _requests) = stand_in_server(vec![400, 200]);
+        let mut config = config(vec![endpoint(&url)]);
+        config.dead_letter = Some("/dev/full".to_string());
+        let mut dispatcher = WebhookDispatcher::start(config).unwrap();
+        dispatcher.on_event(&chargeback(1)).unwrap();
+        let mut waited = 0;
+        while dispatcher.handled() != Some(1) && waited < 500 {
+            thread::sleep(std::time::Duration::from_millis(10));
+            waited += 1;
+        }
+        assert_eq!(Some(1), dispatcher.handled());
+    }
+}